@@ -0,0 +1,204 @@
+use crate::SceneRoot;
+use std::io;
+#[cfg(all(windows, feature = "live"))]
+use winreg::{RegKey, RegValue, enums::*};
+
+/// Registry value type codes as stored in [`RawValue::vtype`].
+pub mod vtype {
+    pub const REG_NONE: u32 = 0;
+    pub const REG_SZ: u32 = 1;
+    pub const REG_EXPAND_SZ: u32 = 2;
+    pub const REG_BINARY: u32 = 3;
+    pub const REG_DWORD: u32 = 4;
+    pub const REG_DWORD_BIG_ENDIAN: u32 = 5;
+    pub const REG_LINK: u32 = 6;
+    pub const REG_MULTI_SZ: u32 = 7;
+    pub const REG_RESOURCE_LIST: u32 = 8;
+    pub const REG_FULL_RESOURCE_DESCRIPTOR: u32 = 9;
+    pub const REG_RESOURCE_REQUIREMENTS_LIST: u32 = 10;
+    pub const REG_QWORD: u32 = 11;
+}
+
+/// A registry value exactly as a backend stores it: a type code and its payload.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RawValue {
+    pub vtype: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// Storage behind `RegItem`, `Blocks` and the menu style switch.
+///
+/// Paths are relative to `root`, use `\` as separator and are matched
+/// case-insensitively, like the Windows registry. `create_key` creates any
+/// missing parents, `delete_key` only removes keys without subkeys.
+pub trait RegistryBackend {
+    fn enum_keys(&self, root: SceneRoot, path: &str) -> io::Result<Vec<String>>;
+    fn enum_values(&self, root: SceneRoot, path: &str) -> io::Result<Vec<(String, RawValue)>>;
+    fn create_key(&self, root: SceneRoot, path: &str) -> io::Result<()>;
+    fn set_value(
+        &self,
+        root: SceneRoot,
+        path: &str,
+        name: &str,
+        value: &RawValue,
+    ) -> io::Result<()>;
+    fn delete_value(&self, root: SceneRoot, path: &str, name: &str) -> io::Result<()>;
+    fn delete_key(&self, root: SceneRoot, path: &str) -> io::Result<()>;
+    /// Last write time of the key as a Windows `FILETIME`.
+    fn last_write_time(&self, root: SceneRoot, path: &str) -> io::Result<u64>;
+
+    fn key_exists(&self, root: SceneRoot, path: &str) -> bool {
+        self.enum_keys(root, path).is_ok()
+    }
+
+    fn get_value(&self, root: SceneRoot, path: &str, name: &str) -> io::Result<RawValue> {
+        self.enum_values(root, path)?
+            .into_iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{path}\\{name}")))
+    }
+
+    fn delete_tree(&self, root: SceneRoot, path: &str) -> io::Result<()> {
+        for name in self.enum_keys(root, path)? {
+            self.delete_tree(root, &join_path(path, &name))?;
+        }
+        self.delete_key(root, path)
+    }
+}
+
+pub(crate) fn join_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{parent}\\{name}")
+    }
+}
+
+/// The rest of `path` below `prefix`, if `path` is `prefix` or one of its
+/// subkeys.
+pub(crate) fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    if prefix.is_empty() {
+        return Some(path);
+    }
+    let head = path.get(..prefix.len())?;
+    if !head.eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    match &path[prefix.len()..] {
+        "" => Some(""),
+        rest => rest.strip_prefix('\\'),
+    }
+}
+
+/// Current time as a Windows `FILETIME` (100ns ticks since 1601-01-01).
+pub(crate) fn filetime_now() -> u64 {
+    const UNIX_EPOCH_AS_FILETIME: u64 = 116_444_736_000_000_000;
+    let since_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    UNIX_EPOCH_AS_FILETIME + (since_epoch.as_nanos() / 100) as u64
+}
+
+/// The live registry of the current machine.
+#[cfg(all(windows, feature = "live"))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WinRegBackend;
+
+#[cfg(all(windows, feature = "live"))]
+impl WinRegBackend {
+    fn open(root: SceneRoot, path: &str, flags: u32) -> io::Result<RegKey> {
+        let key = RegKey::predef(root.get_reg());
+        if path.is_empty() {
+            Ok(key)
+        } else {
+            key.open_subkey_with_flags(path, flags)
+        }
+    }
+}
+
+#[cfg(all(windows, feature = "live"))]
+fn to_reg_type(code: u32) -> io::Result<RegType> {
+    let ty = match code {
+        vtype::REG_NONE => REG_NONE,
+        vtype::REG_SZ => REG_SZ,
+        vtype::REG_EXPAND_SZ => REG_EXPAND_SZ,
+        vtype::REG_BINARY => REG_BINARY,
+        vtype::REG_DWORD => REG_DWORD,
+        vtype::REG_DWORD_BIG_ENDIAN => REG_DWORD_BIG_ENDIAN,
+        vtype::REG_LINK => REG_LINK,
+        vtype::REG_MULTI_SZ => REG_MULTI_SZ,
+        vtype::REG_RESOURCE_LIST => REG_RESOURCE_LIST,
+        vtype::REG_FULL_RESOURCE_DESCRIPTOR => REG_FULL_RESOURCE_DESCRIPTOR,
+        vtype::REG_RESOURCE_REQUIREMENTS_LIST => REG_RESOURCE_REQUIREMENTS_LIST,
+        vtype::REG_QWORD => REG_QWORD,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported registry value type {code}"),
+            ));
+        }
+    };
+    Ok(ty)
+}
+
+#[cfg(all(windows, feature = "live"))]
+impl RegistryBackend for WinRegBackend {
+    fn enum_keys(&self, root: SceneRoot, path: &str) -> io::Result<Vec<String>> {
+        let key = Self::open(root, path, KEY_READ)?;
+        Ok(key.enum_keys().flatten().collect())
+    }
+
+    fn enum_values(&self, root: SceneRoot, path: &str) -> io::Result<Vec<(String, RawValue)>> {
+        let key = Self::open(root, path, KEY_READ)?;
+        Ok(key
+            .enum_values()
+            .flatten()
+            .map(|(name, value)| {
+                let raw = RawValue {
+                    vtype: value.vtype.clone() as u32,
+                    bytes: value.bytes,
+                };
+                (name, raw)
+            })
+            .collect())
+    }
+
+    fn create_key(&self, root: SceneRoot, path: &str) -> io::Result<()> {
+        RegKey::predef(root.get_reg()).create_subkey(path)?;
+        Ok(())
+    }
+
+    fn set_value(
+        &self,
+        root: SceneRoot,
+        path: &str,
+        name: &str,
+        value: &RawValue,
+    ) -> io::Result<()> {
+        let key = Self::open(root, path, KEY_SET_VALUE)?;
+        let data = RegValue {
+            vtype: to_reg_type(value.vtype)?,
+            bytes: value.bytes.clone(),
+        };
+        key.set_raw_value(name, &data)
+    }
+
+    fn delete_value(&self, root: SceneRoot, path: &str, name: &str) -> io::Result<()> {
+        Self::open(root, path, KEY_SET_VALUE)?.delete_value(name)
+    }
+
+    fn delete_key(&self, root: SceneRoot, path: &str) -> io::Result<()> {
+        Self::open(root, path, KEY_WRITE)?.delete_subkey_with_flags("", KEY_WRITE)
+    }
+
+    fn last_write_time(&self, root: SceneRoot, path: &str) -> io::Result<u64> {
+        let info = Self::open(root, path, KEY_READ)?.query_info()?;
+        let ft = &info.last_write_time;
+        Ok(((ft.dwHighDateTime as u64) << 32) | ft.dwLowDateTime as u64)
+    }
+
+    fn key_exists(&self, root: SceneRoot, path: &str) -> bool {
+        Self::open(root, path, KEY_READ).is_ok()
+    }
+}
@@ -0,0 +1,774 @@
+use crate::APP_NAME;
+use crate::BACKUP_NAME;
+use crate::IconStore;
+use crate::MenuItem;
+use crate::RegItem;
+use crate::SceneRoot;
+use crate::snapshot::unix_now;
+use anyhow::{Context, bail};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The version of the backup document this wcm writes.
+pub const BACKUP_VERSION: u32 = 3;
+
+/// An earlier copy of a backed up item.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BackupRevision {
+    /// When this copy was first seen, in seconds since the Unix epoch.
+    pub seen: u64,
+    pub item: MenuItem,
+}
+
+/// A backed up item and when it was seen in the registry.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BackupEntry {
+    /// Root of the key the item was read from, `None` when it has no key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<SceneRoot>,
+    /// Seconds since the Unix epoch.
+    pub first_seen: u64,
+    /// Seconds since the Unix epoch.
+    pub last_seen: u64,
+    /// When the registry content of `item` was first seen, in seconds since
+    /// the Unix epoch.
+    #[serde(default)]
+    pub revised: u64,
+    pub item: MenuItem,
+    /// Earlier copies of `item`, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub revisions: Vec<BackupRevision>,
+    /// Created by wcm rather than installed by a program, so wcm may delete
+    /// it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub added: bool,
+    /// How the item was before wcm first edited it, until it's reverted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited: Option<RegItem>,
+}
+
+impl BackupEntry {
+    fn new(item: MenuItem, now: u64) -> Self {
+        BackupEntry {
+            root: item.root(),
+            first_seen: now,
+            last_seen: now,
+            revised: now,
+            item,
+            revisions: vec![],
+            added: false,
+            edited: None,
+        }
+    }
+
+    /// Revision `n` of the item, numbered from 1 for the oldest copy up to
+    /// the current one.
+    pub fn revision(&self, n: usize) -> Option<&MenuItem> {
+        match n.checked_sub(1)? {
+            i if i < self.revisions.len() => Some(&self.revisions[i].item),
+            i if i == self.revisions.len() => Some(&self.item),
+            _ => None,
+        }
+    }
+
+    /// Every revision of the item with when it was first seen, oldest first.
+    pub fn revisions(&self) -> impl Iterator<Item = (u64, &MenuItem)> {
+        self.revisions
+            .iter()
+            .map(|r| (r.seen, &r.item))
+            .chain([(self.revised, &self.item)])
+    }
+
+    /// Whether this entry backs up `item`. Ids are only unique per root.
+    pub fn matches(&self, item: &MenuItem) -> bool {
+        self.item.id == item.id && self.root == item.root()
+    }
+}
+
+/// The copies of every Win10 item wcm has seen, kept so disabled items can
+/// be written back.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Backup {
+    pub version: u32,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    /// Seconds since the Unix epoch.
+    pub updated: u64,
+    /// The machine and account the backup was taken on.
+    #[serde(default)]
+    pub host: String,
+    #[serde(default)]
+    pub user: String,
+    pub entries: Vec<BackupEntry>,
+}
+
+impl Backup {
+    pub fn new(now: u64) -> Self {
+        let env = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| std::env::var(name).ok())
+                .unwrap_or_default()
+        };
+        Backup {
+            version: BACKUP_VERSION,
+            created: now,
+            updated: now,
+            host: env(&["COMPUTERNAME", "HOSTNAME"]),
+            user: env(&["USERNAME", "USER"]),
+            entries: vec![],
+        }
+    }
+
+    /// Reads a backup document, migrating older versions. `now` stands in for
+    /// times older versions didn't record.
+    pub fn from_json(s: &str, now: u64) -> anyhow::Result<Backup> {
+        let value: serde_json::Value = serde_json::from_str(s)?;
+        match &value {
+            // version 0, a bare list of items
+            serde_json::Value::Array(_) => {
+                let items: Vec<MenuItem> = serde_json::from_value(value)?;
+                let mut backup = Backup::new(now);
+                backup.entries = items
+                    .into_iter()
+                    .map(|item| BackupEntry::new(item, now))
+                    .collect();
+                Ok(backup)
+            }
+            serde_json::Value::Object(map) => {
+                let Some(version) = map.get("version").and_then(|v| v.as_u64()) else {
+                    bail!("backup has no version");
+                };
+                if version > u64::from(BACKUP_VERSION) {
+                    bail!("backup version {version} is newer than this wcm supports");
+                }
+                let mut backup: Backup = serde_json::from_value(value)?;
+                if version < 2 {
+                    for entry in &mut backup.entries {
+                        entry.revised = entry.first_seen;
+                    }
+                }
+                backup.version = BACKUP_VERSION;
+                Ok(backup)
+            }
+            _ => bail!("not a wcm backup"),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    fn all_items_mut(&mut self) -> impl Iterator<Item = &mut MenuItem> {
+        self.entries.iter_mut().flat_map(|e| {
+            std::iter::once(&mut e.item).chain(e.revisions.iter_mut().map(|r| &mut r.item))
+        })
+    }
+
+    /// Moves the icons of every item and revision into `icons`.
+    pub fn store_icons(&mut self, icons: &IconStore) -> io::Result<()> {
+        for item in self.all_items_mut() {
+            if let Some(info) = &mut item.info {
+                icons.store_icon(info)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the icons [`Backup::store_icons`] moved out back in.
+    pub fn load_icons(&mut self, icons: &IconStore) {
+        let mut loaded = std::collections::HashMap::new();
+        for item in self.all_items_mut() {
+            let Some(info) = &mut item.info else {
+                continue;
+            };
+            let Some(hash) = info.icon_hash.clone() else {
+                continue;
+            };
+            let icon = loaded
+                .entry(hash)
+                .or_insert_with_key(|hash| icons.get(hash).ok());
+            if let Some(icon) = icon {
+                info.icon = Some(icon.clone());
+                info.icon_hash = None;
+            }
+        }
+    }
+
+    /// The backed up items.
+    pub fn items(&self) -> Vec<MenuItem> {
+        self.entries.iter().map(|e| e.item.clone()).collect()
+    }
+
+    /// The entry for `id`, in any root.
+    pub fn find(&self, id: &str) -> Option<&BackupEntry> {
+        self.entries.iter().find(|e| e.item.id == id)
+    }
+
+    /// The entry for `id` in `root`.
+    pub fn find_in(&self, root: SceneRoot, id: &str) -> Option<&BackupEntry> {
+        self.entries
+            .iter()
+            .find(|e| e.root == Some(root) && e.item.id == id)
+    }
+
+    /// Adds the items that aren't backed up yet and marks all of `items` as
+    /// seen at `now`. Items whose registry content changed since they were
+    /// backed up get a new revision, keeping the old copy.
+    pub fn merge(&mut self, items: &[MenuItem], now: u64) {
+        let reg = |item: &MenuItem| item.info.as_ref().and_then(|i| i.reg.clone());
+        for item in items {
+            match self.entries.iter_mut().find(|e| e.matches(item)) {
+                Some(entry) => {
+                    entry.last_seen = now;
+                    if reg(&entry.item) != reg(item) {
+                        let old = std::mem::replace(&mut entry.item, item.clone());
+                        entry.revisions.push(BackupRevision {
+                            seen: entry.revised,
+                            item: old,
+                        });
+                        entry.revised = now;
+                    }
+                }
+                None => self.entries.push(BackupEntry::new(item.clone(), now)),
+            }
+        }
+        self.updated = now;
+    }
+
+    /// Backs up `item` as one wcm created.
+    pub fn add(&mut self, item: &MenuItem, now: u64) {
+        self.merge(std::slice::from_ref(item), now);
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.matches(item)) {
+            entry.added = true;
+        }
+    }
+
+    /// Backs up `after`, an edit of `before`. `before` is kept to revert to,
+    /// unless an earlier edit that wasn't reverted already kept one.
+    pub fn edit(&mut self, before: &MenuItem, after: &MenuItem, now: u64) {
+        self.merge(&[before.clone(), after.clone()], now);
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.matches(after))
+            && entry.edited.is_none()
+        {
+            entry.edited = before.info.as_ref().and_then(|i| i.reg.clone());
+        }
+    }
+
+    /// Backs up `item`, which was put back the way it was before its edits.
+    pub fn revert(&mut self, item: &MenuItem, now: u64) {
+        self.merge(std::slice::from_ref(item), now);
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.matches(item)) {
+            entry.edited = None;
+        }
+    }
+
+    /// Drops the entry for `id` in `root` and returns it.
+    pub fn remove(&mut self, root: SceneRoot, id: &str) -> Option<BackupEntry> {
+        let i = self
+            .entries
+            .iter()
+            .position(|e| e.root == Some(root) && e.item.id == id)?;
+        Some(self.entries.remove(i))
+    }
+}
+
+impl Backup {
+    /// A copy holding only the entries for `ids`, or every entry when `ids`
+    /// is empty.
+    pub fn export(&self, ids: &[String]) -> Backup {
+        let mut backup = self.clone();
+        if !ids.is_empty() {
+            backup.entries.retain(|e| ids.contains(&e.item.id));
+        }
+        backup
+    }
+
+    /// Adds the entries of `other` this backup doesn't have, such as one
+    /// taken on another machine, and returns how many were added.
+    pub fn import(&mut self, other: &Backup) -> usize {
+        let mut added = 0;
+        for entry in &other.entries {
+            if !self.entries.iter().any(|e| e.matches(&entry.item)) {
+                self.entries.push(entry.clone());
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// Removes the entries last seen before `before` that aren't among the
+    /// `present` items, and returns them.
+    pub fn prune(&mut self, before: u64, present: &[MenuItem]) -> Vec<BackupEntry> {
+        let (pruned, kept) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|e| e.last_seen < before && !present.iter().any(|i| e.matches(i)));
+        self.entries = kept;
+        pruned
+    }
+
+    /// Checks every entry of a backup document on its own, so one damaged
+    /// entry doesn't hide the others.
+    pub fn verify_json(s: &str) -> anyhow::Result<Vec<BackupProblem>> {
+        let value: serde_json::Value = serde_json::from_str(s)?;
+        let (entries, v0) = match &value {
+            serde_json::Value::Array(items) => (items, true),
+            serde_json::Value::Object(map) => match map.get("entries") {
+                Some(serde_json::Value::Array(entries)) => (entries, false),
+                _ => bail!("backup has no entries"),
+            },
+            _ => bail!("not a wcm backup"),
+        };
+        let mut problems = vec![];
+        for (index, value) in entries.iter().enumerate() {
+            let parsed = if v0 {
+                serde_json::from_value(value.clone()).map(|item| BackupEntry::new(item, 0))
+            } else {
+                serde_json::from_value::<BackupEntry>(value.clone())
+            };
+            let entry = match parsed {
+                Ok(entry) => entry,
+                Err(e) => {
+                    let id = value.pointer(if v0 { "/id" } else { "/item/id" });
+                    problems.push(BackupProblem {
+                        index,
+                        id: id
+                            .and_then(|id| id.as_str())
+                            .unwrap_or_default()
+                            .to_string(),
+                        problem: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+            for (n, (_, item)) in entry.revisions().enumerate() {
+                let problem = match item.info.as_ref().and_then(|i| i.reg.as_ref()) {
+                    None => "has no registry key",
+                    Some(reg) if !reg.is_safe() => "is outside the context menu locations",
+                    Some(_) => continue,
+                };
+                problems.push(BackupProblem {
+                    index,
+                    id: entry.item.id.clone(),
+                    problem: format!("revision {} {problem}", n + 1),
+                });
+            }
+        }
+        Ok(problems)
+    }
+}
+
+/// Something wrong with one entry of a backup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupProblem {
+    /// Position of the entry in the file.
+    pub index: usize,
+    pub id: String,
+    pub problem: String,
+}
+
+impl Display for BackupProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{} {}: {}", self.index, self.id, self.problem)
+    }
+}
+
+/// How many earlier versions of the backup file are kept.
+pub const BACKUP_GENERATIONS: usize = 5;
+
+/// The backup file. Earlier versions are kept next to it as `backup.json.1`,
+/// the newest, up to `backup.json.5`, and icons once each in `icons`.
+#[derive(Debug, Clone)]
+pub struct BackupStore {
+    path: PathBuf,
+}
+
+impl BackupStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        BackupStore { path: path.into() }
+    }
+
+    /// `backup.json` in the wcm config directory.
+    pub fn open_default() -> Option<Self> {
+        let dir = dirs::config_local_dir()?.join(APP_NAME);
+        Some(BackupStore::new(dir.join(BACKUP_NAME)))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(suffix);
+        path.into()
+    }
+
+    /// Where the icons of the items are kept, shared by every generation.
+    pub fn icons(&self) -> IconStore {
+        let dir = self.path.parent().unwrap_or(Path::new(""));
+        IconStore::new(dir.join("icons"))
+    }
+
+    /// The `n`th earlier version of the file, starting at 1.
+    pub fn generation(&self, n: usize) -> PathBuf {
+        self.sibling(&format!(".{n}"))
+    }
+
+    /// Reads the backup. A missing file is an empty backup, a damaged one is
+    /// an error rather than being taken for empty.
+    pub fn load(&self) -> anyhow::Result<Backup> {
+        let now = unix_now();
+        let s = match std::fs::read_to_string(&self.path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Backup::new(now)),
+            Err(e) => return Err(e).with_context(|| self.path.display().to_string()),
+        };
+        let mut backup = Backup::from_json(&s, now).with_context(|| {
+            format!(
+                "{} is damaged, earlier copies are kept as {}",
+                self.path.display(),
+                self.generation(1).display()
+            )
+        })?;
+        backup.load_icons(&self.icons());
+        Ok(backup)
+    }
+
+    /// Writes `backup` over the file, for exports.
+    pub fn save(&self, backup: &Backup) -> anyhow::Result<()> {
+        let _lock = self.lock()?;
+        self.write(backup, false)
+            .with_context(|| self.path.display().to_string())
+    }
+
+    /// A copy of the backup in `<file>.pending`, for changes that only count
+    /// once something else succeeds, see [`BackupStore::unstage`].
+    pub fn stage(&self) -> anyhow::Result<BackupStore> {
+        let dir = self.sibling(".pending");
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).with_context(|| dir.display().to_string())?;
+        }
+        let staged = BackupStore::new(dir.join(BACKUP_NAME));
+        staged.save(&self.load()?)?;
+        Ok(staged)
+    }
+
+    /// Removes `staged` from [`BackupStore::stage`], taking over its backup
+    /// when `keep`.
+    pub fn unstage(&self, staged: BackupStore, keep: bool) -> anyhow::Result<()> {
+        if keep {
+            let backup = staged.load()?;
+            self.update(|b| *b = backup)?;
+        }
+        let dir = staged.path.parent().unwrap_or(Path::new(""));
+        std::fs::remove_dir_all(dir).with_context(|| dir.display().to_string())
+    }
+
+    /// Checks every entry of the file, see [`Backup::verify_json`].
+    pub fn verify(&self) -> anyhow::Result<Vec<BackupProblem>> {
+        let s =
+            std::fs::read_to_string(&self.path).with_context(|| self.path.display().to_string())?;
+        Backup::verify_json(&s)
+    }
+
+    /// Reads the backup, runs `f` on it and writes it back, holding a lock
+    /// so other wcm processes don't interleave their changes.
+    pub fn update<T>(&self, f: impl FnOnce(&mut Backup) -> T) -> anyhow::Result<T> {
+        self.update_then(f, |_, out| out)
+    }
+
+    /// Like [`BackupStore::update`], then runs `then` on the written backup
+    /// before the lock is released, for changes that rely on being backed
+    /// up first.
+    pub fn update_then<T, U>(
+        &self,
+        f: impl FnOnce(&mut Backup) -> T,
+        then: impl FnOnce(&Backup, T) -> U,
+    ) -> anyhow::Result<U> {
+        let _lock = self.lock()?;
+        let mut backup = self.load()?;
+        let old = backup.clone();
+        let out = f(&mut backup);
+        if backup != old {
+            // only changed items push out earlier generations, not timestamps
+            self.write(&backup, backup.items() != old.items())
+                .with_context(|| self.path.display().to_string())?;
+        }
+        Ok(then(&backup, out))
+    }
+
+    /// Waits for and takes the lock, which is released when the file closes.
+    fn lock(&self) -> io::Result<File> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = File::create(self.sibling(".lock"))?;
+        file.lock()?;
+        Ok(file)
+    }
+
+    /// Replaces the file in one rename, so a crash leaves the old or the new
+    /// version but never half of one.
+    fn write(&self, backup: &Backup, rotate: bool) -> io::Result<()> {
+        if rotate && self.path.exists() {
+            for n in (1..BACKUP_GENERATIONS).rev() {
+                let from = self.generation(n);
+                if from.exists() {
+                    std::fs::rename(from, self.generation(n + 1))?;
+                }
+            }
+            std::fs::copy(&self.path, self.generation(1))?;
+        }
+        let mut backup = backup.clone();
+        backup.store_icons(&self.icons())?;
+        let tmp = self.sibling(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(backup.to_json()?.as_bytes())?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BACKUP_VERSION, Backup, BackupStore};
+    use crate::test_support::{TempDir, menu_item};
+    use crate::{RegItemValue, SceneRoot};
+
+    #[test]
+    fn test_migrate() {
+        let items = vec![menu_item(SceneRoot::HKCR, r"*\shell\git")];
+        let v0 = serde_json::to_string(&items).unwrap();
+        let backup = Backup::from_json(&v0, 100).unwrap();
+        assert_eq!(backup.version, BACKUP_VERSION);
+        assert_eq!(backup.items(), items);
+        assert_eq!(backup.entries[0].root, Some(SceneRoot::HKCR));
+        assert_eq!(backup.entries[0].first_seen, 100);
+
+        let json = backup.to_json().unwrap();
+        assert_eq!(Backup::from_json(&json, 200).unwrap(), backup);
+        let newer = json.replacen(
+            &format!(r#""version": {BACKUP_VERSION}"#),
+            r#""version": 99"#,
+            1,
+        );
+        assert!(Backup::from_json(&newer, 200).is_err());
+        assert!(Backup::from_json("{}", 200).is_err());
+
+        // version 1 had no revisions
+        let mut v1: serde_json::Value = serde_json::from_str(&json).unwrap();
+        v1["version"] = 1.into();
+        v1["entries"][0].as_object_mut().unwrap().remove("revised");
+        let migrated = Backup::from_json(&v1.to_string(), 200).unwrap();
+        assert_eq!(migrated, backup);
+    }
+
+    #[test]
+    fn test_merge() {
+        let path = r"Software\Classes\*\shell\git";
+        let mut backup = Backup::new(100);
+        backup.merge(&[menu_item(SceneRoot::HKCU, path)], 100);
+        backup.merge(
+            &[
+                menu_item(SceneRoot::HKCU, path),
+                menu_item(SceneRoot::HKLM, path),
+            ],
+            200,
+        );
+        let seen: Vec<_> = backup
+            .entries
+            .iter()
+            .map(|e| (e.root, e.first_seen, e.last_seen))
+            .collect();
+        assert_eq!(
+            seen,
+            [
+                (Some(SceneRoot::HKCU), 100, 200),
+                (Some(SceneRoot::HKLM), 200, 200)
+            ]
+        );
+        assert_eq!(backup.updated, 200);
+
+        let wt = r"Directory\Background\Shell\wt";
+        backup.add(&menu_item(SceneRoot::HKCR, wt), 300);
+        let added: Vec<_> = backup.entries.iter().map(|e| e.added).collect();
+        assert_eq!(added, [false, false, true]);
+        assert!(backup.find_in(SceneRoot::HKCU, wt).is_none());
+        assert!(backup.remove(SceneRoot::HKCU, wt).is_none());
+        assert!(backup.remove(SceneRoot::HKCR, wt).is_some_and(|e| e.added));
+        assert!(backup.find(wt).is_none());
+    }
+
+    #[test]
+    fn test_revisions() {
+        let git = r"*\shell\git";
+        let mut backup = Backup::new(100);
+        backup.merge(&[menu_item(SceneRoot::HKCR, git)], 100);
+        let mut updated = menu_item(SceneRoot::HKCR, git);
+        let reg = updated.info.as_mut().unwrap().reg.as_mut().unwrap();
+        reg.set_value("Icon", RegItemValue::SZ("git.exe".to_string()));
+        backup.merge(std::slice::from_ref(&updated), 200);
+        backup.merge(std::slice::from_ref(&updated), 300);
+
+        let entry = backup.find(git).unwrap();
+        assert_eq!(entry.revisions.len(), 1);
+        assert_eq!(entry.revision(1), Some(&menu_item(SceneRoot::HKCR, git)));
+        assert_eq!(entry.revision(2), Some(&updated));
+        assert_eq!(entry.revision(0), None);
+        assert_eq!(entry.revision(3), None);
+        let seen: Vec<u64> = entry.revisions().map(|(seen, _)| seen).collect();
+        assert_eq!(seen, [100, 200]);
+        assert_eq!((entry.first_seen, entry.last_seen), (100, 300));
+        assert_eq!(backup.items(), [updated]);
+    }
+
+    #[test]
+    fn test_export_import_prune() {
+        let (git, code) = (r"*\shell\git", r"*\shell\code");
+        let mut backup = Backup::new(100);
+        backup.merge(&[menu_item(SceneRoot::HKCR, git)], 100);
+        backup.merge(&[menu_item(SceneRoot::HKCR, code)], 200);
+
+        let exported = backup.export(&[git.to_string()]);
+        assert_eq!(exported.items(), [menu_item(SceneRoot::HKCR, git)]);
+        let mut other = Backup::new(300);
+        assert_eq!(other.import(&backup), 2);
+        assert_eq!(other.import(&exported), 0);
+        assert_eq!(other.entries, backup.entries);
+
+        // code is still present, git is long gone
+        let pruned = other.prune(300, &[menu_item(SceneRoot::HKCR, code)]);
+        assert_eq!(pruned.len(), 1);
+        assert_eq!(pruned[0].item.id, git);
+        assert_eq!(other.items(), [menu_item(SceneRoot::HKCR, code)]);
+        assert!(other.prune(150, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut backup = Backup::new(100);
+        backup.merge(
+            &[
+                menu_item(SceneRoot::HKCR, r"*\shell\git"),
+                menu_item(SceneRoot::HKLM, r"SYSTEM\CurrentControlSet"),
+            ],
+            100,
+        );
+        let mut json: serde_json::Value = serde_json::to_value(&backup).unwrap();
+        json["entries"]
+            .as_array_mut()
+            .unwrap()
+            .push(serde_json::json!({
+                "item": { "id": "broken" }
+            }));
+        let problems = Backup::verify_json(&json.to_string()).unwrap();
+        let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems.len(), 2);
+        assert_eq!(
+            problems[0],
+            r"#1 SYSTEM\CurrentControlSet: revision 1 is outside the context menu locations"
+        );
+        assert!(problems[1].starts_with("#2 broken: missing field"));
+    }
+
+    #[test]
+    fn test_store() {
+        let dir = TempDir::new("backup");
+        let store = BackupStore::new(dir.join("backup.json"));
+        assert!(store.load().unwrap().entries.is_empty());
+
+        let threads: Vec<_> = (0..4)
+            .map(|n| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    for i in 0..5 {
+                        let item = menu_item(SceneRoot::HKCR, &format!(r"*\shell\{n}-{i}"));
+                        store.update(|b| b.merge(&[item], 100)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(store.load().unwrap().entries.len(), 20);
+        assert_eq!(
+            Backup::from_json(&std::fs::read_to_string(store.generation(1)).unwrap(), 0)
+                .unwrap()
+                .entries
+                .len(),
+            19
+        );
+        assert!(store.generation(5).exists());
+        assert!(!store.generation(6).exists());
+
+        // seeing the same items again doesn't push out a generation
+        let first = std::fs::read_to_string(store.generation(1)).unwrap();
+        store
+            .update(|b| b.merge(&[menu_item(SceneRoot::HKCR, r"*\shell\0-0")], 200))
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(store.generation(1)).unwrap(), first);
+
+        std::fs::write(store.path(), "{\"entries\": [").unwrap();
+        let e = store.update(|b| b.merge(&[], 300)).unwrap_err();
+        assert!(format!("{e:#}").contains("damaged"));
+        assert_eq!(
+            std::fs::read_to_string(store.path()).unwrap(),
+            "{\"entries\": ["
+        );
+    }
+
+    #[test]
+    fn test_store_icons() {
+        let dir = TempDir::new("backup-icons");
+        let store = BackupStore::new(dir.join("backup.json"));
+        let items: Vec<_> = (0..3)
+            .map(|n| {
+                let mut item = menu_item(SceneRoot::HKCR, &format!(r"*\shell\{n}"));
+                item.info.as_mut().unwrap().icon = Some(b"shell32".to_vec());
+                item
+            })
+            .collect();
+        store.update(|b| b.merge(&items, 100)).unwrap();
+
+        let json = std::fs::read_to_string(store.path()).unwrap();
+        assert!(!json.contains(r#""icon":"#));
+        assert_eq!(json.matches(r#""icon_hash":"#).count(), 3);
+        assert_eq!(std::fs::read_dir(store.icons().dir()).unwrap().count(), 1);
+        assert_eq!(store.load().unwrap().items(), items);
+
+        // a missing icon keeps its reference rather than being dropped
+        let _ = std::fs::remove_dir_all(store.icons().dir());
+        store.update(|b| b.merge(&[], 200)).unwrap();
+        let json = std::fs::read_to_string(store.path()).unwrap();
+        assert_eq!(json.matches(r#""icon_hash":"#).count(), 3);
+    }
+
+    #[test]
+    fn test_stage() {
+        let dir = TempDir::new("backup-stage");
+        let store = BackupStore::new(dir.join("backup.json"));
+        let first = vec![menu_item(SceneRoot::HKCR, r"*\shell\first")];
+        store.update(|b| b.merge(&first, 100)).unwrap();
+
+        // changes to a staged copy that isn't kept leave the store as is
+        let staged = store.stage().unwrap();
+        let second = vec![menu_item(SceneRoot::HKCR, r"*\shell\second")];
+        staged.update(|b| b.merge(&second, 200)).unwrap();
+        assert_eq!(store.load().unwrap().items(), first);
+        store.unstage(staged, false).unwrap();
+        assert_eq!(store.load().unwrap().items(), first);
+
+        let staged = store.stage().unwrap();
+        staged.update(|b| b.merge(&second, 200)).unwrap();
+        store.unstage(staged, true).unwrap();
+        assert_eq!(store.load().unwrap().items(), [first, second].concat());
+        assert!(!dir.join("backup.json.pending").exists());
+    }
+}
@@ -1,3 +1,17 @@
+use crate::BackupStore;
+use crate::NewVerb;
+use crate::RawValue;
+use crate::RegOp;
+use crate::RegistryBackend;
+use crate::Transaction;
+use crate::VerbEdit;
+use crate::VerbInfo;
+#[cfg(all(windows, feature = "live"))]
+use crate::WinRegBackend;
+use crate::backend::strip_path_prefix;
+use crate::snapshot::unix_now;
+use crate::vtype::*;
+use crate::{encode_reg_file, reg_txt};
 use serde::Serialize;
 use std::collections::HashMap;
 use std::fmt::Display;
@@ -5,12 +19,15 @@ use strum::IntoEnumIterator;
 use strum_macros::Display;
 use strum_macros::EnumIter;
 use strum_macros::EnumString;
+#[cfg(all(windows, feature = "live"))]
 use tempfile::NamedTempFile;
+#[cfg(all(windows, feature = "live"))]
 use windows::Win32::System::Threading::CREATE_NO_WINDOW;
-use winreg::HKEY;
-use winreg::RegKey;
-use winreg::RegValue;
-use winreg::enums::*;
+#[cfg(all(windows, feature = "live"))]
+use winreg::{
+    HKEY,
+    enums::{HKEY_CLASSES_ROOT, HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE},
+};
 
 pub const APP_NAME: &str = "windows-contextmenu-manager";
 pub const BACKUP_NAME: &str = "backup.json";
@@ -51,18 +68,28 @@ pub enum Scope {
 }
 
 impl Scope {
+    #[cfg(all(windows, feature = "live"))]
     pub fn to_hive(self) -> HKEY {
+        self.to_root().get_reg()
+    }
+
+    pub fn to_root(self) -> SceneRoot {
         match self {
-            Scope::User => HKEY_CURRENT_USER,
-            Scope::Machine => HKEY_LOCAL_MACHINE,
+            Scope::User => SceneRoot::HKCU,
+            Scope::Machine => SceneRoot::HKLM,
         }
     }
 }
 
 pub trait Manager {
-    fn list(&self, scope: Option<Scope>) -> Vec<MenuItem>;
+    /// The items on one of `scenes`, or all of them when it's empty.
+    fn list(&self, scope: Option<Scope>, scenes: &[Scene]) -> Vec<MenuItem>;
     fn disable(&self, id: &str, scope: Option<Scope>) -> Result<(), anyhow::Error>;
     fn enable(&self, id: &str, scope: Option<Scope>) -> Result<(), anyhow::Error>;
+    /// The registry changes `disable` would make, without making them.
+    fn plan_disable(&self, id: &str, scope: Option<Scope>) -> Result<Vec<RegOp>, anyhow::Error>;
+    /// The registry changes `enable` would make, without making them.
+    fn plan_enable(&self, id: &str, scope: Option<Scope>) -> Result<Vec<RegOp>, anyhow::Error>;
 }
 #[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
 pub struct MenuItem {
@@ -71,6 +98,56 @@ pub struct MenuItem {
     pub enabled: bool,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub info: Option<MenuItemInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<MenuItemKind>,
+    /// The menu the item shows up on, `None` for items that don't belong to
+    /// one, like Edge policies and file associations.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scene: Option<Scene>,
+    /// The root the item was read from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<SceneRoot>,
+}
+
+impl MenuItem {
+    /// Whether the item shows up on one of `scenes`, always true when it's
+    /// empty. Items backed up before scenes were recorded go by their id.
+    pub fn on_scene(&self, scenes: &[Scene]) -> bool {
+        scenes.is_empty()
+            || self
+                .scene
+                .or_else(|| Scene::from_item_path(&self.id))
+                .is_some_and(|scene| scenes.contains(&scene))
+    }
+
+    /// The root of the key a Win10 item is registered in. Ids are only
+    /// unique per root, Edge policies for example are in HKCU and HKLM.
+    pub fn root(&self) -> Option<SceneRoot> {
+        Some(self.info.as_ref()?.reg.as_ref()?.root)
+    }
+}
+
+/// What kind of registration a [`MenuItem`] is.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize, EnumIter, EnumString, Display,
+)]
+pub enum MenuItemKind {
+    /// A key below `Shell`.
+    Verb,
+    /// A key below `ShellEx\ContextMenuHandlers`.
+    ContextMenuHandler,
+    /// A key below `ShellEx\DragDropHandlers`.
+    DragDropHandler,
+    /// A key below `ShellEx\CopyHookHandlers`.
+    CopyHook,
+    /// A key below `ShellEx\PropertySheetHandlers`.
+    PropertySheet,
+    /// The program a file extension opens with.
+    Association,
+    /// An Edge policy key.
+    Policy,
+    /// A command of a packaged app, the Win11 menu items.
+    PackagedCommand,
 }
 
 #[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
@@ -85,6 +162,9 @@ pub struct MenuItemInfo {
     #[serde(with = "base64_option_vec")]
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub icon: Option<Vec<u8>>,
+    /// Where `icon` is kept in an [`crate::IconStore`] while it's written out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon_hash: Option<String>,
     pub publisher_display_name: String,
     pub description: String,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
@@ -96,6 +176,9 @@ pub struct MenuItemInfo {
     pub reg: Option<RegItem>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub reg_txt: Option<String>,
+    /// The values of a classic menu verb.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verb: Option<VerbInfo>,
 }
 
 use base64::{Engine as _, engine::general_purpose};
@@ -142,57 +225,114 @@ pub enum Type {
     Win11,
 }
 
+#[cfg(all(windows, feature = "live"))]
 impl Manager for Type {
-    fn list(&self, scope: Option<Scope>) -> Vec<MenuItem> {
-        match self {
+    fn list(&self, scope: Option<Scope>, scenes: &[Scene]) -> Vec<MenuItem> {
+        let items = match self {
             Type::Win10 => crate::win10::list(),
             Type::Win11 => crate::win11::list(scope.unwrap_or_default()),
-        }
+        };
+        items.into_iter().filter(|i| i.on_scene(scenes)).collect()
     }
 
     fn disable(&self, id: &str, scope: Option<Scope>) -> Result<(), anyhow::Error> {
-        match self {
+        let ops = self.plan_disable(id, scope)?;
+        let action = self.action_name("disable", id, scope);
+        crate::journal::record_live(&action, &ops, || match self {
             Type::Win10 => crate::win10::disable(id),
             Type::Win11 => crate::win11::disable(id, scope.unwrap_or_default()),
-        }
+        })
     }
 
     fn enable(&self, id: &str, scope: Option<Scope>) -> Result<(), anyhow::Error> {
-        match self {
+        let ops = self.plan_enable(id, scope)?;
+        let action = self.action_name("enable", id, scope);
+        crate::journal::record_live(&action, &ops, || match self {
             Type::Win10 => crate::win10::enable(id),
             Type::Win11 => crate::win11::enable(id, scope.unwrap_or_default()),
-        }
+        })
+    }
+
+    fn plan_disable(&self, id: &str, scope: Option<Scope>) -> Result<Vec<RegOp>, anyhow::Error> {
+        let mut tx = Transaction::new();
+        tx.disable(*self, id, scope);
+        Ok(tx
+            .plan_backed_up(&WinRegBackend, &crate::win10::get_backup()?)?
+            .concat())
+    }
+
+    fn plan_enable(&self, id: &str, scope: Option<Scope>) -> Result<Vec<RegOp>, anyhow::Error> {
+        let mut tx = Transaction::new();
+        tx.enable(*self, id, scope);
+        Ok(tx
+            .plan(&WinRegBackend, &crate::win10::get_backup()?.items())?
+            .concat())
     }
 }
 
 use std::io::{self};
 
-const CLSID_PATH: &str =
+/// Holds an empty default value while the classic context menu is switched on.
+pub(crate) const CLSID_PATH: &str =
     r"SOFTWARE\Classes\CLSID\{86ca1aa0-34aa-4e8b-a509-50c905bae2a2}\InprocServer32";
 
+#[cfg(all(windows, feature = "live"))]
 pub fn set_context_menu_style(is_win11_style: bool) -> io::Result<()> {
-    let hkcu = RegKey::predef(HKEY_CURRENT_USER);
-    if is_win11_style {
-        let hkcu = RegKey::predef(HKEY_CURRENT_USER);
-        let key = hkcu.open_subkey_with_flags(CLSID_PATH, KEY_SET_VALUE)?;
-        key.delete_value("")?;
+    let ops = plan_context_menu_style_in(&WinRegBackend, is_win11_style);
+    let action = if is_win11_style {
+        "switch win11"
     } else {
-        let clsid_path = r"Software\Classes\CLSID\{86ca1aa0-34aa-4e8b-a509-50c905bae2a2}";
-        let (clsid_key, _) = hkcu.create_subkey(clsid_path)?;
-        let (inproc_key, _) = clsid_key.create_subkey("InprocServer32")?;
-        inproc_key.set_value("", &"")?;
+        "switch win10"
+    };
+    crate::journal::record_live(action, &ops, || {
+        set_context_menu_style_in(&WinRegBackend, is_win11_style)
+    })
+}
+
+pub fn set_context_menu_style_in(
+    backend: &dyn RegistryBackend,
+    is_win11_style: bool,
+) -> io::Result<()> {
+    for op in plan_context_menu_style_in(backend, is_win11_style) {
+        op.apply(backend)?;
     }
     Ok(())
 }
 
+/// The registry changes `set_context_menu_style_in` would make.
+pub fn plan_context_menu_style_in(
+    backend: &dyn RegistryBackend,
+    is_win11_style: bool,
+) -> Vec<RegOp> {
+    let (root, path, name) = (SceneRoot::HKCU, CLSID_PATH.to_string(), String::new());
+    let current = backend.get_value(root, &path, &name).ok();
+    let empty = RegItemValue::SZ(String::new());
+    match (current, is_win11_style) {
+        (Some(_), true) => vec![RegOp::DeleteValue { root, path, name }],
+        (None, true) => vec![],
+        (Some(value), false) if value == empty.to_raw() => vec![],
+        (_, false) => vec![RegOp::SetValue {
+            root,
+            path,
+            name,
+            value: empty,
+        }],
+    }
+}
+
+#[cfg(all(windows, feature = "live"))]
 pub fn get_context_menu_style() -> bool {
-    let hkcu = RegKey::predef(HKEY_CURRENT_USER);
-    hkcu.open_subkey(CLSID_PATH).is_ok()
+    get_context_menu_style_in(&WinRegBackend)
 }
 
-use std::process::Command;
-use std::process::Stdio;
+pub fn get_context_menu_style_in(backend: &dyn RegistryBackend) -> bool {
+    backend.key_exists(SceneRoot::HKCU, CLSID_PATH)
+}
+
+#[cfg(all(windows, feature = "live"))]
+use std::process::{Command, Stdio};
 
+#[cfg(all(windows, feature = "live"))]
 pub fn restart_explorer() {
     let _ = Command::new("taskkill")
         .creation_flags(CREATE_NO_WINDOW.0)
@@ -207,14 +347,244 @@ pub fn restart_explorer() {
 }
 
 impl Type {
+    /// Lists the items registered in `backend` (for example offline hives)
+    /// without reading or updating the backup.
+    pub fn list_in(&self, backend: &dyn RegistryBackend, scope: Option<Scope>) -> Vec<MenuItem> {
+        match self {
+            Type::Win10 => crate::win10::load_all_in(backend).unwrap_or_default(),
+            Type::Win11 => crate::win11::list_in(backend, scope.unwrap_or_default()),
+        }
+    }
+
+    /// Lists the Win10 items in `backend` with the ones only `store` holds,
+    /// which are disabled.
+    pub fn list_backed_up_in(
+        backend: &dyn RegistryBackend,
+        store: &BackupStore,
+    ) -> Result<Vec<MenuItem>, anyhow::Error> {
+        Ok(crate::profile::list_win10_in(
+            backend,
+            &store.load()?.items(),
+        ))
+    }
+
+    /// Disables an item registered in `backend`, such as a set of offline
+    /// hives. Win10 items are backed up in `store` before they're deleted.
+    pub fn disable_in(
+        &self,
+        backend: &dyn RegistryBackend,
+        store: &BackupStore,
+        id: &str,
+        scope: Option<Scope>,
+    ) -> Result<(), anyhow::Error> {
+        match self {
+            Type::Win10 => crate::win10::disable_in(backend, store, id),
+            Type::Win11 => crate::win11::disable_in(backend, id, scope.unwrap_or_default()),
+        }
+    }
+
+    /// The registry changes `disable_in` would make.
+    pub fn plan_disable_in(
+        &self,
+        backend: &dyn RegistryBackend,
+        store: &BackupStore,
+        id: &str,
+        scope: Option<Scope>,
+    ) -> Result<Vec<RegOp>, anyhow::Error> {
+        let mut tx = Transaction::new();
+        tx.disable(*self, id, scope);
+        Ok(tx.plan_backed_up(backend, &store.load()?)?.concat())
+    }
+
+    /// Enables an item in `backend`, restoring Win10 items from `store`.
+    pub fn enable_in(
+        &self,
+        backend: &dyn RegistryBackend,
+        store: &BackupStore,
+        id: &str,
+        scope: Option<Scope>,
+    ) -> Result<(), anyhow::Error> {
+        match self {
+            Type::Win10 => crate::win10::enable_in(backend, &store.load()?.items(), id),
+            Type::Win11 => crate::win11::enable_in(backend, id, scope.unwrap_or_default()),
+        }
+    }
+
+    /// The registry changes `enable_in` would make.
+    pub fn plan_enable_in(
+        &self,
+        backend: &dyn RegistryBackend,
+        store: &BackupStore,
+        id: &str,
+        scope: Option<Scope>,
+    ) -> Result<Vec<RegOp>, anyhow::Error> {
+        let mut tx = Transaction::new();
+        tx.enable(*self, id, scope);
+        Ok(tx.plan(backend, &store.load()?.items())?.concat())
+    }
+
+    /// Writes back revision `revision` of a Win10 item from `store`.
+    pub fn enable_revision_in(
+        backend: &dyn RegistryBackend,
+        store: &BackupStore,
+        id: &str,
+        revision: usize,
+    ) -> Result<(), anyhow::Error> {
+        let backup = crate::win10::backup_revision(&store.load()?, id, revision)?;
+        crate::win10::enable_in(backend, &backup, id)
+    }
+
+    /// The registry changes `enable_revision_in` would make.
+    pub fn plan_enable_revision_in(
+        backend: &dyn RegistryBackend,
+        store: &BackupStore,
+        id: &str,
+        revision: usize,
+    ) -> Result<Vec<RegOp>, anyhow::Error> {
+        let mut tx = Transaction::new();
+        tx.enable(Type::Win10, id, None);
+        let backup = crate::win10::backup_revision(&store.load()?, id, revision)?;
+        Ok(tx.plan(backend, &backup)?.concat())
+    }
+
+    #[cfg(all(windows, feature = "live"))]
+    pub fn enable_revision(
+        store: &BackupStore,
+        id: &str,
+        revision: usize,
+    ) -> Result<(), anyhow::Error> {
+        let ops = Type::plan_enable_revision_in(&WinRegBackend, store, id, revision)?;
+        let action = format!("win10 enable {id} --revision {revision}");
+        crate::journal::record_live(&action, &ops, || {
+            Type::enable_revision_in(&WinRegBackend, store, id, revision)
+        })
+    }
+
+    /// Creates a classic menu verb in `backend` and backs it up in `store`
+    /// as created by wcm.
+    pub fn add_in(
+        backend: &dyn RegistryBackend,
+        store: &BackupStore,
+        verb: &NewVerb,
+    ) -> Result<MenuItem, anyhow::Error> {
+        let item = crate::win10::add_in(backend, verb)?;
+        store.update(|backup| backup.add(&item, unix_now()))?;
+        Ok(item)
+    }
+
+    /// Deletes a verb [`Type::add_in`] created and drops it from `store`.
+    pub fn remove_in(
+        backend: &dyn RegistryBackend,
+        store: &BackupStore,
+        id: &str,
+    ) -> Result<(), anyhow::Error> {
+        crate::win10::remove_in(backend, &store.load()?, id)?;
+        store.update(|backup| {
+            backup.remove(SceneRoot::HKCR, id);
+        })
+    }
+
+    /// The registry changes `remove_in` would make.
+    pub fn plan_remove_in(
+        backend: &dyn RegistryBackend,
+        store: &BackupStore,
+        id: &str,
+    ) -> Result<Vec<RegOp>, anyhow::Error> {
+        crate::win10::plan_remove_in(backend, &store.load()?, id)
+    }
+
+    /// Changes the values of a classic menu verb in `backend`, backing up
+    /// how it was in `store` so [`Type::revert_in`] can put it back.
+    pub fn edit_in(
+        backend: &dyn RegistryBackend,
+        store: &BackupStore,
+        id: &str,
+        edit: &VerbEdit,
+    ) -> Result<MenuItem, anyhow::Error> {
+        let (before, after) = crate::win10::edit_in(backend, id, edit)?;
+        store.update(|backup| backup.edit(&before, &after, unix_now()))?;
+        Ok(after)
+    }
+
+    /// The registry changes `edit_in` would make.
+    pub fn plan_edit_in(
+        backend: &dyn RegistryBackend,
+        id: &str,
+        edit: &VerbEdit,
+    ) -> Result<Vec<RegOp>, anyhow::Error> {
+        crate::win10::plan_edit_in(backend, id, edit)
+    }
+
+    /// Puts a verb back the way it was before [`Type::edit_in`] first
+    /// changed it, as `store` recorded it.
+    pub fn revert_in(
+        backend: &dyn RegistryBackend,
+        store: &BackupStore,
+        id: &str,
+    ) -> Result<(), anyhow::Error> {
+        let item = crate::win10::revert_in(backend, &store.load()?, id)?;
+        store.update(|backup| backup.revert(&item, unix_now()))
+    }
+
+    /// The registry changes `revert_in` would make.
+    pub fn plan_revert_in(
+        backend: &dyn RegistryBackend,
+        store: &BackupStore,
+        id: &str,
+    ) -> Result<Vec<RegOp>, anyhow::Error> {
+        crate::win10::plan_revert_in(backend, &store.load()?, id)
+    }
+
+    #[cfg(all(windows, feature = "live"))]
+    pub fn add(store: &BackupStore, verb: &NewVerb) -> Result<MenuItem, anyhow::Error> {
+        let ops = verb.plan_in(&WinRegBackend)?;
+        let action = format!("win10 add {}", verb.path()?);
+        crate::journal::record_live(&action, &ops, || Type::add_in(&WinRegBackend, store, verb))
+    }
+
+    #[cfg(all(windows, feature = "live"))]
+    pub fn remove(store: &BackupStore, id: &str) -> Result<(), anyhow::Error> {
+        let ops = Type::plan_remove_in(&WinRegBackend, store, id)?;
+        let action = format!("win10 remove {id}");
+        crate::journal::record_live(&action, &ops, || Type::remove_in(&WinRegBackend, store, id))
+    }
+
+    #[cfg(all(windows, feature = "live"))]
+    pub fn edit(store: &BackupStore, id: &str, edit: &VerbEdit) -> Result<MenuItem, anyhow::Error> {
+        let ops = Type::plan_edit_in(&WinRegBackend, id, edit)?;
+        let action = format!("win10 edit {id}");
+        crate::journal::record_live(&action, &ops, || {
+            Type::edit_in(&WinRegBackend, store, id, edit)
+        })
+    }
+
+    #[cfg(all(windows, feature = "live"))]
+    pub fn revert(store: &BackupStore, id: &str) -> Result<(), anyhow::Error> {
+        let ops = Type::plan_revert_in(&WinRegBackend, store, id)?;
+        let action = format!("win10 edit {id} --revert");
+        crate::journal::record_live(&action, &ops, || Type::revert_in(&WinRegBackend, store, id))
+    }
+
+    /// The command line of an enable or disable, as the journal shows it.
+    #[cfg(all(windows, feature = "live"))]
+    fn action_name(&self, verb: &str, id: &str, scope: Option<Scope>) -> String {
+        match self {
+            Type::Win10 => format!("win10 {verb} {id}"),
+            Type::Win11 => format!("win11 {verb} {} {id}", scope.unwrap_or_default()),
+        }
+    }
+
+    #[cfg(all(windows, feature = "live"))]
     pub fn enable_classic_menu() -> io::Result<()> {
         set_context_menu_style(false)
     }
 
+    #[cfg(all(windows, feature = "live"))]
     pub fn disable_classic_menu() -> io::Result<()> {
         set_context_menu_style(true)
     }
 
+    #[cfg(all(windows, feature = "live"))]
     pub fn menu_type() -> Type {
         if get_context_menu_style() {
             Type::Win11
@@ -233,77 +603,105 @@ pub enum RegItemValue {
     QWORD(u64),
     BINARY(Vec<u8>),
     None(Vec<u8>),
+    DwordBigEndian(u32),
+    /// Target of a symbolic link key, stored without a terminator.
+    Link(String),
+    ResourceList(Vec<u8>),
+    FullResourceDescriptor(Vec<u8>),
+    ResourceRequirementsList(Vec<u8>),
+    /// A value of an unknown type, or one whose payload doesn't fit its
+    /// type, such as a three byte `REG_DWORD`. Kept as is.
+    Raw {
+        vtype: u32,
+        bytes: Vec<u8>,
+    },
 }
 
-fn parse_dword(value: &RegValue) -> Option<u32> {
-    if value.vtype == RegType::REG_DWORD && value.bytes.len() == 4 {
-        let mut arr = [0u8; 4];
-        arr.copy_from_slice(&value.bytes);
-        Some(u32::from_le_bytes(arr))
-    } else {
-        None
+fn decode_utf16(bytes: &[u8]) -> String {
+    let words: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    let mut s = String::from_utf16_lossy(&words);
+    while s.ends_with('\0') {
+        s.pop();
     }
+    s
 }
-fn parse_qword(value: &RegValue) -> Option<u64> {
-    if value.vtype == RegType::REG_QWORD && value.bytes.len() == 8 {
-        let mut arr = [0u8; 8];
-        arr.copy_from_slice(&value.bytes);
-        Some(u64::from_le_bytes(arr))
-    } else {
-        None
-    }
+
+pub(crate) fn encode_utf16(s: &str) -> Vec<u8> {
+    s.encode_utf16()
+        .chain(std::iter::once(0))
+        .flat_map(u16::to_le_bytes)
+        .collect()
 }
-impl TryFrom<RegValue> for RegItemValue {
-    type Error = anyhow::Error;
-
-    fn try_from(value: RegValue) -> Result<Self, Self::Error> {
-        let v = match value.vtype {
-            REG_SZ => RegItemValue::SZ(value.to_string()),
-            REG_EXPAND_SZ => RegItemValue::ExpandSz(value.bytes.to_vec()),
-            REG_MULTI_SZ => RegItemValue::MultiSz(value.to_string()),
-            REG_DWORD => RegItemValue::DWORD(parse_dword(&value).expect("parse_dword error")),
-            REG_QWORD => RegItemValue::QWORD(parse_qword(&value).expect("parse_qword error")),
-            REG_BINARY => RegItemValue::BINARY(value.bytes),
-            REG_NONE => RegItemValue::None(value.bytes),
-            REG_DWORD_BIG_ENDIAN => todo!(),
-            REG_LINK => todo!(),
-            REG_RESOURCE_LIST => todo!(),
-            REG_FULL_RESOURCE_DESCRIPTOR => todo!(),
-            REG_RESOURCE_REQUIREMENTS_LIST => todo!(),
-        };
-        Ok(v)
+
+fn hex_string(bytes: &[u8]) -> String {
+    let hex: Vec<_> = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    format!("0x{}", hex.join(""))
+}
+
+impl From<RawValue> for RegItemValue {
+    fn from(value: RawValue) -> Self {
+        let RawValue { vtype, bytes } = value;
+        match (vtype, bytes.len()) {
+            (REG_SZ | REG_MULTI_SZ | REG_LINK, len) if len % 2 != 0 => {
+                RegItemValue::Raw { vtype, bytes }
+            }
+            (REG_SZ, _) => RegItemValue::SZ(decode_utf16(&bytes)),
+            (REG_EXPAND_SZ, _) => RegItemValue::ExpandSz(bytes),
+            (REG_MULTI_SZ, _) => RegItemValue::MultiSz(decode_utf16(&bytes).replace('\0', "\n")),
+            (REG_DWORD, 4) => {
+                RegItemValue::DWORD(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            }
+            (REG_DWORD_BIG_ENDIAN, 4) => RegItemValue::DwordBigEndian(u32::from_be_bytes([
+                bytes[0], bytes[1], bytes[2], bytes[3],
+            ])),
+            (REG_QWORD, 8) => {
+                let mut arr = [0u8; 8];
+                arr.copy_from_slice(&bytes);
+                RegItemValue::QWORD(u64::from_le_bytes(arr))
+            }
+            (REG_BINARY, _) => RegItemValue::BINARY(bytes),
+            (REG_NONE, _) => RegItemValue::None(bytes),
+            (REG_LINK, _) => RegItemValue::Link(decode_utf16(&bytes)),
+            (REG_RESOURCE_LIST, _) => RegItemValue::ResourceList(bytes),
+            (REG_FULL_RESOURCE_DESCRIPTOR, _) => RegItemValue::FullResourceDescriptor(bytes),
+            (REG_RESOURCE_REQUIREMENTS_LIST, _) => RegItemValue::ResourceRequirementsList(bytes),
+            _ => RegItemValue::Raw { vtype, bytes },
+        }
     }
 }
 
 impl RegItemValue {
-    fn write(&self, key: &RegKey, name: &str) {
-        let _ = match self {
-            RegItemValue::SZ(v) => key.set_value(name, v),
-            RegItemValue::DWORD(v) => key.set_value(name, v),
-            RegItemValue::ExpandSz(bytes) => {
-                let data = RegValue {
-                    vtype: REG_EXPAND_SZ,
-                    bytes: bytes.to_vec(),
-                };
-                key.set_raw_value(name, &data)
+    pub fn to_raw(&self) -> RawValue {
+        let (vtype, bytes) = match self {
+            RegItemValue::SZ(v) => (REG_SZ, encode_utf16(v)),
+            RegItemValue::DWORD(v) => (REG_DWORD, v.to_le_bytes().to_vec()),
+            RegItemValue::ExpandSz(bytes) => (REG_EXPAND_SZ, bytes.to_vec()),
+            RegItemValue::MultiSz(v) => {
+                let mut bytes: Vec<u8> = v.split('\n').flat_map(encode_utf16).collect();
+                bytes.extend([0, 0]);
+                (REG_MULTI_SZ, bytes)
             }
-            RegItemValue::MultiSz(v) => key.set_value(name, v),
-            RegItemValue::QWORD(v) => key.set_value(name, v),
-            RegItemValue::BINARY(bytes) => {
-                let data = RegValue {
-                    vtype: REG_BINARY,
-                    bytes: bytes.to_vec(),
-                };
-                key.set_raw_value(name, &data)
+            RegItemValue::QWORD(v) => (REG_QWORD, v.to_le_bytes().to_vec()),
+            RegItemValue::BINARY(bytes) => (REG_BINARY, bytes.to_vec()),
+            RegItemValue::None(bytes) => (REG_NONE, bytes.to_vec()),
+            RegItemValue::DwordBigEndian(v) => (REG_DWORD_BIG_ENDIAN, v.to_be_bytes().to_vec()),
+            RegItemValue::Link(v) => (
+                REG_LINK,
+                v.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            ),
+            RegItemValue::ResourceList(bytes) => (REG_RESOURCE_LIST, bytes.to_vec()),
+            RegItemValue::FullResourceDescriptor(bytes) => {
+                (REG_FULL_RESOURCE_DESCRIPTOR, bytes.to_vec())
             }
-            RegItemValue::None(bytes) => {
-                let data = RegValue {
-                    vtype: REG_NONE,
-                    bytes: bytes.to_vec(),
-                };
-                key.set_raw_value(name, &data)
+            RegItemValue::ResourceRequirementsList(bytes) => {
+                (REG_RESOURCE_REQUIREMENTS_LIST, bytes.to_vec())
             }
+            RegItemValue::Raw { vtype, bytes } => (*vtype, bytes.to_vec()),
         };
+        RawValue { vtype, bytes }
     }
 }
 
@@ -312,29 +710,25 @@ impl Display for RegItemValue {
         let s = match self {
             RegItemValue::SZ(v) => v.to_string(),
             RegItemValue::DWORD(v) => v.to_string(),
-            RegItemValue::ExpandSz(v) => String::from_utf16(
-                &v.chunks(2)
-                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
-                    .take_while(|&u| u != 0)
-                    .collect::<Vec<u16>>(),
-            )
-            .expect("ExpandSz format error"),
+            RegItemValue::ExpandSz(v) => {
+                let s = decode_utf16(v);
+                s.split('\0').next().unwrap_or_default().to_string()
+            }
             RegItemValue::MultiSz(v) => v.to_string(),
             RegItemValue::QWORD(v) => v.to_string(),
             RegItemValue::None(_) => "REG_NONE".to_string(),
-            RegItemValue::BINARY(bytes) => {
-                let hex: Vec<_> = bytes.iter().map(|b| format!("{b:02x}")).collect();
-                format!("0x{}", hex.join(""))
-            }
+            RegItemValue::BINARY(bytes) => hex_string(bytes),
+            RegItemValue::DwordBigEndian(v) => v.to_string(),
+            RegItemValue::Link(v) => v.to_string(),
+            RegItemValue::ResourceList(bytes)
+            | RegItemValue::FullResourceDescriptor(bytes)
+            | RegItemValue::ResourceRequirementsList(bytes) => hex_string(bytes),
+            RegItemValue::Raw { vtype, bytes } => format!("({vtype}) {}", hex_string(bytes)),
         };
         f.write_str(&s)
     }
 }
 
-fn escape_str(s: &str) -> String {
-    s.replace("\\", "\\\\").replace("\"", "\\\"")
-}
-
 #[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
 pub struct RegItem {
     pub path: String,
@@ -348,43 +742,34 @@ pub struct RegItem {
     pub root: SceneRoot,
 }
 
-fn split_bytes(data: &[u8]) -> Vec<&[u8]> {
-    let mut groups = Vec::new();
-
-    if data.len() <= 23 {
-        groups.push(data);
-        return groups;
+impl RegItem {
+    pub fn new(root: SceneRoot, path: &str) -> RegItem {
+        RegItem {
+            path: path.to_string(),
+            root,
+            ..Default::default()
+        }
     }
 
-    groups.push(&data[..23]);
-
-    let mut i = 23;
-    while i < data.len() {
-        let end = usize::min(i + 25, data.len());
-        groups.push(&data[i..end]);
-        i += 25;
+    pub fn set_value(&mut self, name: &str, value: RegItemValue) {
+        self.values.insert(name.to_string(), value);
     }
 
-    groups
-}
-
-fn to_hex_line(bytes: &[u8], hex_type: u32) -> String {
-    let mut s = if hex_type == 0 {
-        String::from("hex:")
-    } else {
-        format!("hex({hex_type}):")
-    };
+    /// Removes the value named `name` in any case and returns it with the
+    /// name it had.
+    pub fn remove_value(&mut self, name: &str) -> Option<(String, RegItemValue)> {
+        let key = self
+            .values
+            .keys()
+            .find(|k| k.eq_ignore_ascii_case(name))?
+            .clone();
+        self.values.remove_entry(&key)
+    }
 
-    let mut v = vec![];
-    for i in split_bytes(bytes) {
-        let hex: Vec<_> = i.iter().map(|b| format!("{b:02x}")).collect();
-        v.push(hex.join(","));
+    pub fn values(&self) -> impl Iterator<Item = (&String, &RegItemValue)> {
+        self.values.iter()
     }
-    s.push_str(&v.join(",\\\n  "));
-    s
-}
 
-impl RegItem {
     pub fn get_child(&self, name: &str) -> Option<&RegItem> {
         self.children.iter().find(|c| {
             c.path.to_lowercase().split('\\').next_back() == Some(name.to_lowercase().as_str())
@@ -443,28 +828,29 @@ impl RegItem {
 
         None
     }
+    #[cfg(all(windows, feature = "live"))]
     pub fn from_path(root: SceneRoot, path: &str) -> io::Result<RegItem> {
-        let mut reg_key = RegKey::predef(root.get_reg());
-
-        if !path.is_empty() {
-            reg_key = reg_key.open_subkey(path)?;
-        }
+        RegItem::from_path_in(&WinRegBackend, root, path)
+    }
 
+    pub fn from_path_in(
+        backend: &dyn RegistryBackend,
+        root: SceneRoot,
+        path: &str,
+    ) -> io::Result<RegItem> {
         let mut values = HashMap::new();
-        for (name, value) in reg_key.enum_values().flatten() {
-            if let Ok(item_value) = RegItemValue::try_from(value) {
-                values.insert(name.clone(), item_value);
-            }
+        for (name, value) in backend.enum_values(root, path)? {
+            values.insert(name, RegItemValue::from(value));
         }
 
         let mut children: Vec<RegItem> = Vec::new();
-        for subkey_name in reg_key.enum_keys().flat_map(|x| x.ok()) {
+        for subkey_name in backend.enum_keys(root, path)? {
             let subkey_path = if path.is_empty() {
                 subkey_name
             } else {
                 format!("{path}\\{subkey_name}")
             };
-            let subkey_item = RegItem::from_path(root, &subkey_path)?;
+            let subkey_item = RegItem::from_path_in(backend, root, &subkey_path)?;
             children.push(subkey_item);
         }
 
@@ -476,84 +862,107 @@ impl RegItem {
         })
     }
 
-    fn is_safe(&self) -> bool {
-        for scene_type in SceneType::iter() {
-            for (_, reg_path) in scene_type.registry_path() {
-                if self.path.starts_with(reg_path) {
-                    return true;
-                }
-            }
-        }
-        false
+    /// Whether the item lives in one of the context menu locations wcm
+    /// manages, see [`is_safe_path`].
+    pub fn is_safe(&self) -> bool {
+        is_safe_path(self.root, &self.path)
     }
 
-    pub fn write(&self) {
-        if !self.is_safe() {
-            return;
+    #[cfg(all(windows, feature = "live"))]
+    pub fn write(&self) -> io::Result<()> {
+        self.write_in(&WinRegBackend)
+    }
+
+    /// Creates the key and its subkeys with their values in `backend`,
+    /// keeping values that are there already.
+    pub fn write_in(&self, backend: &dyn RegistryBackend) -> io::Result<()> {
+        self.check_safe()?;
+        backend.create_key(self.root, &self.path)?;
+        for (name, value) in &self.values {
+            backend.set_value(self.root, &self.path, name, &value.to_raw())?;
         }
-        let root = RegKey::predef(self.root.get_reg());
-        if let Ok((key, _disp)) = root.create_subkey(&self.path) {
-            for (name, value) in &self.values {
-                value.write(&key, name);
-            }
-            for child in &self.children {
-                child.write();
-            }
+        for child in &self.children {
+            child.write_in(backend)?;
         }
+        Ok(())
     }
 
+    #[cfg(all(windows, feature = "live"))]
     pub fn delete(&self) -> io::Result<()> {
-        if !self.is_safe() {
-            return Ok(());
+        self.delete_in(&WinRegBackend)
+    }
+
+    /// Deletes the key with every subkey it has in `backend`, including ones
+    /// added since it was read.
+    pub fn delete_in(&self, backend: &dyn RegistryBackend) -> io::Result<()> {
+        self.check_safe()?;
+        if !backend.key_exists(self.root, &self.path) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, self.path.clone()));
         }
-        let reg_key = RegKey::predef(self.root.get_reg())
-            .open_subkey_with_flags(self.path.clone(), KEY_WRITE)?;
-        for i in &self.children {
-            let _ = i.delete();
+        backend.delete_tree(self.root, &self.path)
+    }
+
+    fn check_safe(&self) -> io::Result<()> {
+        if self.is_safe() {
+            return Ok(());
         }
-        reg_key.delete_subkey_with_flags("", KEY_WRITE)?;
-        Ok(())
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{}: outside the context menu locations", self.path),
+        ))
     }
 
+    /// This key and its subkeys in the text format regedit exports.
     pub fn to_reg_txt(&self) -> String {
-        fn write_item(item: &RegItem, out: &mut String) {
-            out.push_str(&format!("\n[HKEY_CLASSES_ROOT\\{}]\n", item.path));
-            for (name, value) in &item.values {
-                let key = if name.is_empty() {
-                    "@".to_string()
-                } else {
-                    format!(r#""{}""#, escape_str(name))
-                };
-
-                let v = match value {
-                    RegItemValue::SZ(v) => format!(r#""{}""#, escape_str(v)),
-                    RegItemValue::DWORD(v) => format!("dword:{v:08x}"),
-                    RegItemValue::QWORD(v) => format!("qword:{v:016x}"),
-                    RegItemValue::ExpandSz(v) => escape_str(&to_hex_line(v, 2)),
-                    RegItemValue::MultiSz(v) => escape_str(v),
-                    RegItemValue::BINARY(v) => to_hex_line(v, 0),
-                    RegItemValue::None(v) => {
-                        let hex = v
-                            .iter()
-                            .map(|b| format!("{b:02x}"))
-                            .collect::<Vec<_>>()
-                            .join(",");
-                        format!("hex(0):{hex}")
-                    }
-                };
-                let line = format!("{key}={v}\n");
-                out.push_str(&line);
-            }
-            for child in &item.children {
-                write_item(child, out);
-            }
-        }
-        let mut out = String::from("Windows Registry Editor Version 5.00\n");
-        write_item(self, &mut out);
-        out
+        reg_txt(std::slice::from_ref(self))
+    }
+
+    /// Byte-for-byte what regedit exports for this key: `to_reg_txt` as
+    /// UTF-16LE with a BOM.
+    pub fn to_reg_file(&self) -> Vec<u8> {
+        encode_reg_file(&self.to_reg_txt())
     }
 }
 
+/// Maps keys under the user or machine `Classes` key to their
+/// HKEY_CLASSES_ROOT equivalent.
+fn classes_view(root: SceneRoot, path: &str) -> (SceneRoot, &str) {
+    let classes = match root {
+        SceneRoot::HKCR => return (root, path),
+        SceneRoot::HKCU => r"Software\Classes",
+        SceneRoot::HKLM => r"SOFTWARE\Classes",
+    };
+    match strip_path_prefix(path, classes) {
+        Some(rest) => (SceneRoot::HKCR, rest),
+        None => (root, path),
+    }
+}
+
+/// Splits a leading root such as `HKLM\` off a Win10 item id.
+pub(crate) fn split_root(id: &str) -> (Option<SceneRoot>, &str) {
+    if let Some((root, path)) = id.split_once('\\')
+        && let Some(root) = SceneRoot::from_hive_name(root)
+    {
+        return (Some(root), path);
+    }
+    (None, id)
+}
+
+/// Whether `root\path` is one of the context menu locations wcm manages or
+/// lies below one, ignoring case.
+pub fn is_safe_path(root: SceneRoot, path: &str) -> bool {
+    let (root, path) = classes_view(root, path);
+    SceneType::iter().any(|scene_type| {
+        scene_type
+            .registry_path()
+            .iter()
+            .any(|(scene_root, prefix)| {
+                let (scene_root, prefix) = classes_view(*scene_root, prefix);
+                scene_root == root && strip_path_prefix(path, prefix).is_some()
+            })
+    })
+}
+
 #[derive(
     Debug,
     Clone,
@@ -561,6 +970,8 @@ impl RegItem {
     Copy,
     PartialEq,
     Eq,
+    PartialOrd,
+    Ord,
     Hash,
     Deserialize,
     Serialize,
@@ -579,6 +990,24 @@ pub enum SceneRoot {
 }
 
 impl SceneRoot {
+    /// The full name used in .reg files, like `HKEY_CLASSES_ROOT`.
+    pub fn hive_name(&self) -> &'static str {
+        match self {
+            SceneRoot::HKCR => "HKEY_CLASSES_ROOT",
+            SceneRoot::HKCU => "HKEY_CURRENT_USER",
+            SceneRoot::HKLM => "HKEY_LOCAL_MACHINE",
+        }
+    }
+
+    /// Parses a full or abbreviated root key name, ignoring case.
+    pub fn from_hive_name(name: &str) -> Option<SceneRoot> {
+        SceneRoot::iter().find(|root| {
+            name.eq_ignore_ascii_case(root.hive_name())
+                || name.eq_ignore_ascii_case(&root.to_string())
+        })
+    }
+
+    #[cfg(all(windows, feature = "live"))]
     pub fn get_reg(&self) -> HKEY {
         match self {
             SceneRoot::HKCR => HKEY_CLASSES_ROOT,
@@ -602,12 +1031,14 @@ impl SceneRoot {
     EnumString,
     Display,
 )]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
 pub enum Scene {
     #[default]
     File,
     Folder,
     Desktop,
     Directory,
+    #[cfg_attr(feature = "clap", value(alias = "directory-background"))]
     Background,
     Drive,
     AllObjects,
@@ -620,6 +1051,60 @@ pub enum Scene {
     SystemFileAssociations,
     // Unknown,
 }
+
+/// The keys whose `Shell` and `ShellEx` subkeys make up each scene.
+const SCENE_KEYS: &[(&str, Scene)] = &[
+    ("*", Scene::File),
+    ("Folder", Scene::Folder),
+    ("Directory", Scene::Directory),
+    (r"Directory\Background", Scene::Background),
+    ("DesktopBackground", Scene::Desktop),
+    ("Drive", Scene::Drive),
+    ("AllFilesystemObjects", Scene::AllObjects),
+    ("LibraryFolder", Scene::Library),
+    (r"LibraryFolder\Background", Scene::LibraryBackground),
+    ("UserLibraryFolder", Scene::User),
+    ("Launcher.ImmersiveApplication", Scene::Uwp),
+    (
+        r"CLSID\{20D04FE0-3AEA-1069-A2D8-08002B30309D}",
+        Scene::Computer,
+    ),
+    (
+        r"CLSID\{645FF040-5081-101B-9F08-00AA002F954E}",
+        Scene::RecycleBin,
+    ),
+    ("SystemFileAssociations", Scene::SystemFileAssociations),
+];
+
+impl Scene {
+    /// The scene of the `Shell` and `ShellEx` subkeys of `key`, such as
+    /// `Directory\Background`.
+    pub fn from_key(key: &str) -> Option<Scene> {
+        SCENE_KEYS
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, scene)| *scene)
+    }
+
+    /// The scene of a key below a `Shell` or `ShellEx` key, such as
+    /// `Drive\Shell\format`.
+    pub fn from_item_path(path: &str) -> Option<Scene> {
+        let parts: Vec<&str> = path.split('\\').collect();
+        let end = parts
+            .iter()
+            .position(|p| p.eq_ignore_ascii_case("Shell") || p.eq_ignore_ascii_case("ShellEx"))?;
+        Scene::from_key(&parts[..end].join("\\"))
+    }
+    /// The `Shell` key new verbs of the scene go under, `None` when the
+    /// scene is a set of keys rather than one.
+    pub fn shell_key(&self) -> Option<String> {
+        if *self == Scene::SystemFileAssociations {
+            return None;
+        }
+        let (key, _) = SCENE_KEYS.iter().find(|(_, scene)| scene == self)?;
+        Some(format!(r"{key}\Shell"))
+    }
+}
 #[derive(
     Debug,
     Clone,
@@ -723,8 +1208,10 @@ impl GuidManager {
         self.items.get(guid)
     }
 }
+#[cfg(all(windows, feature = "live"))]
 use std::os::windows::process::CommandExt;
 
+#[cfg(all(windows, feature = "live"))]
 pub fn export_reg(reg_path: &str) -> io::Result<Vec<u8>> {
     let temp_file = NamedTempFile::new()?;
     let temp_path = temp_file.path().to_path_buf();
@@ -0,0 +1,306 @@
+use crate::RegItem;
+use crate::RegItemValue;
+use crate::RegOp;
+use crate::SceneRoot;
+use crate::backend::strip_path_prefix;
+use crate::ops::{key_name, value_name};
+use crate::regf::upcase;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+/// A difference between two sets of registry keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegChange {
+    AddedKey {
+        root: SceneRoot,
+        path: String,
+    },
+    RemovedKey {
+        root: SceneRoot,
+        path: String,
+    },
+    AddedValue {
+        root: SceneRoot,
+        path: String,
+        name: String,
+        value: RegItemValue,
+    },
+    RemovedValue {
+        root: SceneRoot,
+        path: String,
+        name: String,
+        value: RegItemValue,
+    },
+    ChangedValue {
+        root: SceneRoot,
+        path: String,
+        name: String,
+        old: RegItemValue,
+        new: RegItemValue,
+    },
+}
+
+impl RegChange {
+    pub fn root(&self) -> SceneRoot {
+        match self {
+            RegChange::AddedKey { root, .. }
+            | RegChange::RemovedKey { root, .. }
+            | RegChange::AddedValue { root, .. }
+            | RegChange::RemovedValue { root, .. }
+            | RegChange::ChangedValue { root, .. } => *root,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        match self {
+            RegChange::AddedKey { path, .. }
+            | RegChange::RemovedKey { path, .. }
+            | RegChange::AddedValue { path, .. }
+            | RegChange::RemovedValue { path, .. }
+            | RegChange::ChangedValue { path, .. } => path,
+        }
+    }
+}
+
+impl Display for RegChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let key = key_name(self.root(), self.path());
+        match self {
+            RegChange::AddedKey { .. } => write!(f, "+ [{key}]"),
+            RegChange::RemovedKey { .. } => write!(f, "- [{key}]"),
+            RegChange::AddedValue { name, value, .. } => {
+                write!(f, "+ [{key}] {} = {value}", value_name(name))
+            }
+            RegChange::RemovedValue { name, value, .. } => {
+                write!(f, "- [{key}] {} = {value}", value_name(name))
+            }
+            RegChange::ChangedValue { name, old, new, .. } => {
+                write!(f, "~ [{key}] {} = {old} -> {new}", value_name(name))
+            }
+        }
+    }
+}
+
+type KeyId = (SceneRoot, Vec<Vec<u16>>);
+
+/// Indexes every key below `items` by root and upcased path, so parents sort
+/// before their subkeys.
+fn index<'a>(items: &'a [RegItem], keys: &mut BTreeMap<KeyId, &'a RegItem>) {
+    for item in items {
+        let path = item.path.split('\\').filter(|c| !c.is_empty()).map(upcase);
+        keys.insert((item.root, path.collect()), item);
+        index(&item.children, keys);
+    }
+}
+
+/// Values of `item` sorted by upcased name, the way regedit lists them.
+fn sorted_values(item: &RegItem) -> BTreeMap<Vec<u16>, (&String, &RegItemValue)> {
+    item.values().map(|(n, v)| (upcase(n), (n, v))).collect()
+}
+
+/// What changed going from `old` to `new`. Keys are matched by root and full
+/// path and names are compared ignoring case, like the registry does, so two
+/// exports of the same keys compare equal however they are nested.
+pub fn diff_items(old: &[RegItem], new: &[RegItem]) -> Vec<RegChange> {
+    let (mut old_keys, mut new_keys) = (BTreeMap::new(), BTreeMap::new());
+    index(old, &mut old_keys);
+    index(new, &mut new_keys);
+
+    let mut ids: Vec<&KeyId> = old_keys.keys().chain(new_keys.keys()).collect();
+    ids.sort();
+    ids.dedup();
+
+    let mut changes = vec![];
+    for id in ids {
+        match (old_keys.get(id), new_keys.get(id)) {
+            (Some(old), None) => {
+                let (root, path) = (old.root, old.path.clone());
+                changes.push(RegChange::RemovedKey {
+                    root,
+                    path: path.clone(),
+                });
+                for (name, value) in sorted_values(old).into_values() {
+                    changes.push(RegChange::RemovedValue {
+                        root,
+                        path: path.clone(),
+                        name: name.clone(),
+                        value: value.clone(),
+                    });
+                }
+            }
+            (None, Some(new)) => {
+                let (root, path) = (new.root, new.path.clone());
+                changes.push(RegChange::AddedKey {
+                    root,
+                    path: path.clone(),
+                });
+                for (name, value) in sorted_values(new).into_values() {
+                    changes.push(RegChange::AddedValue {
+                        root,
+                        path: path.clone(),
+                        name: name.clone(),
+                        value: value.clone(),
+                    });
+                }
+            }
+            (Some(old), Some(new)) => diff_values(old, new, &mut changes),
+            (None, None) => {}
+        }
+    }
+    changes
+}
+
+fn diff_values(old: &RegItem, new: &RegItem, changes: &mut Vec<RegChange>) {
+    let (root, path) = (new.root, &new.path);
+    let old_values = sorted_values(old);
+    let new_values = sorted_values(new);
+    let mut names: Vec<&Vec<u16>> = old_values.keys().chain(new_values.keys()).collect();
+    names.sort();
+    names.dedup();
+    for name in names {
+        let path = path.clone();
+        match (old_values.get(name), new_values.get(name)) {
+            (Some((name, old)), None) => changes.push(RegChange::RemovedValue {
+                root,
+                path,
+                name: name.to_string(),
+                value: (*old).clone(),
+            }),
+            (None, Some((name, new))) => changes.push(RegChange::AddedValue {
+                root,
+                path,
+                name: name.to_string(),
+                value: (*new).clone(),
+            }),
+            (Some((_, old)), Some((name, new))) if old != new => {
+                changes.push(RegChange::ChangedValue {
+                    root,
+                    path,
+                    name: name.to_string(),
+                    old: (*old).clone(),
+                    new: (*new).clone(),
+                })
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The registry changes that turn the old side of `changes` into the new
+/// one. Removing a key removes its subkeys and values with it.
+pub fn ops_from_changes(changes: &[RegChange]) -> Vec<RegOp> {
+    let mut removed: Vec<(SceneRoot, &str)> = vec![];
+    let mut ops = vec![];
+    for change in changes {
+        let (root, path) = (change.root(), change.path().to_string());
+        if removed
+            .iter()
+            .any(|(r, p)| *r == root && strip_path_prefix(&path, p).is_some())
+        {
+            continue;
+        }
+        ops.push(match change {
+            RegChange::AddedKey { .. } => RegOp::CreateKey { root, path },
+            RegChange::RemovedKey { .. } => {
+                removed.push((root, change.path()));
+                RegOp::DeleteKey { root, path }
+            }
+            RegChange::AddedValue { name, value, .. }
+            | RegChange::ChangedValue {
+                name, new: value, ..
+            } => RegOp::SetValue {
+                root,
+                path,
+                name: name.clone(),
+                value: value.clone(),
+            },
+            RegChange::RemovedValue { name, .. } => RegOp::DeleteValue {
+                root,
+                path,
+                name: name.clone(),
+            },
+        });
+    }
+    ops
+}
+
+impl RegItem {
+    /// What changed going from `self` to `new`, see [`diff_items`].
+    pub fn diff(&self, new: &RegItem) -> Vec<RegChange> {
+        diff_items(std::slice::from_ref(self), std::slice::from_ref(new))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RegChange, diff_items};
+    use crate::{RegFile, RegItem, RegItemValue, SceneRoot};
+
+    #[test]
+    fn test_diff_ignores_case_and_nesting() {
+        let old = RegFile::parse(
+            r#"Windows Registry Editor Version 5.00
+
+[HKEY_CLASSES_ROOT\Directory\shell\git]
+@="Git Bash"
+"Icon"="git.exe"
+
+[HKEY_CLASSES_ROOT\Directory\shell\git\command]
+@="git-bash.exe"
+
+[HKEY_CLASSES_ROOT\Directory\shell\old]
+"Extended"=""
+"#,
+        )
+        .unwrap()
+        .to_items();
+        let new = RegFile::parse(
+            r#"Windows Registry Editor Version 5.00
+
+[HKEY_CLASSES_ROOT\Directory\Shell\GIT]
+@="Git Bash Here"
+"icon"="git.exe"
+"Position"="Top"
+
+[HKEY_CLASSES_ROOT\Directory\Shell\GIT\Command]
+@="git-bash.exe"
+
+[HKEY_CLASSES_ROOT\Directory\Shell\vscode]
+"#,
+        )
+        .unwrap()
+        .to_items();
+
+        let changes = diff_items(&old, &new);
+        assert_eq!(
+            changes.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+            [
+                r"~ [HKEY_CLASSES_ROOT\Directory\Shell\GIT] @ = Git Bash -> Git Bash Here",
+                r#"+ [HKEY_CLASSES_ROOT\Directory\Shell\GIT] "Position" = Top"#,
+                r"- [HKEY_CLASSES_ROOT\Directory\shell\old]",
+                r#"- [HKEY_CLASSES_ROOT\Directory\shell\old] "Extended" = "#,
+                r"+ [HKEY_CLASSES_ROOT\Directory\Shell\vscode]",
+            ]
+        );
+        assert!(diff_items(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_diff_value_types() {
+        let mut old = RegItem::new(SceneRoot::HKCU, r"Software\Classes\*\shell\x");
+        old.set_value("Flags", RegItemValue::DWORD(1));
+        let mut new = old.clone();
+        new.set_value("Flags", RegItemValue::QWORD(1));
+
+        assert_eq!(
+            old.diff(&new),
+            [RegChange::ChangedValue {
+                root: SceneRoot::HKCU,
+                path: r"Software\Classes\*\shell\x".to_string(),
+                name: "Flags".to_string(),
+                old: RegItemValue::DWORD(1),
+                new: RegItemValue::QWORD(1),
+            }]
+        );
+    }
+}
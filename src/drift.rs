@@ -0,0 +1,214 @@
+use crate::MemoryBackend;
+use crate::MenuItem;
+use crate::Profile;
+use crate::ProfileItem;
+use crate::RegistryBackend;
+use crate::Scope;
+use crate::Snapshot;
+use crate::Type;
+use crate::profile::list_win10_in;
+use crate::win11::Blocks;
+use std::fmt::Display;
+use strum::IntoEnumIterator;
+
+/// How an item differs from the reference it's checked against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriftKind {
+    /// In the reference, but not installed here.
+    Missing,
+    /// Installed here, but not in the reference.
+    Added,
+    /// Enabled here and disabled in the reference.
+    Enabled,
+    /// Disabled here and enabled in the reference.
+    Disabled,
+    /// Enabled in both, but its registry keys differ.
+    Changed,
+}
+
+impl Display for DriftKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DriftKind::Missing => write!(f, "missing"),
+            DriftKind::Added => write!(f, "added"),
+            DriftKind::Enabled => write!(f, "enabled"),
+            DriftKind::Disabled => write!(f, "disabled"),
+            DriftKind::Changed => write!(f, "changed"),
+        }
+    }
+}
+
+/// An item whose state isn't the one of the reference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Drift {
+    /// The item as it is here, or as the reference has it when it's missing.
+    pub item: ProfileItem,
+    pub kind: DriftKind,
+}
+
+impl Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.item, self.kind)
+    }
+}
+
+/// The items of `backend` whose enabled state differs from `profile`. Items
+/// the profile doesn't mention aren't drift.
+pub fn drift_from_profile(
+    backend: &dyn RegistryBackend,
+    backup: &[MenuItem],
+    profile: &Profile,
+) -> Vec<Drift> {
+    let current = Profile::capture_in(backend, &profile.name, backup, 0);
+    let mut drift = vec![];
+    for wanted in &profile.items {
+        let (item, kind) = match current.items.iter().find(|i| i.matches(wanted)) {
+            None => (wanted, DriftKind::Missing),
+            Some(have) if have.enabled == wanted.enabled => continue,
+            Some(have) if have.enabled => (have, DriftKind::Enabled),
+            Some(have) => (have, DriftKind::Disabled),
+        };
+        drift.push(Drift {
+            item: item.clone(),
+            kind,
+        });
+    }
+    drift
+}
+
+/// The items of `backend` whose enabled state or registry keys differ from
+/// `snapshot`. Win10 items outside the keys the snapshot holds, such as
+/// file type associations, are left out. Win11 packages aren't in snapshots,
+/// so only whether they're blocked is compared.
+pub fn drift_from_snapshot(
+    backend: &dyn RegistryBackend,
+    backup: &[MenuItem],
+    snapshot: &Snapshot,
+) -> anyhow::Result<Vec<Drift>> {
+    let scratch = MemoryBackend::new();
+    snapshot.restore(&scratch)?;
+    let reg = |item: &MenuItem| item.info.as_ref().and_then(|i| i.reg.clone());
+    let win10 = |backend: &dyn RegistryBackend| -> Vec<(ProfileItem, MenuItem)> {
+        list_win10_in(backend, backup)
+            .into_iter()
+            .filter(|item| reg(item).is_some_and(|r| snapshot.covers(r.root, &r.path)))
+            .map(|item| (ProfileItem::new(Type::Win10, None, &item), item))
+            .collect()
+    };
+    let (before, now) = (win10(&scratch), win10(backend));
+
+    let mut drift = vec![];
+    for (wanted, old) in &before {
+        let (item, kind) = match now.iter().find(|(have, _)| have.matches(wanted)) {
+            None => (wanted, DriftKind::Missing),
+            Some((have, _)) if have.enabled != wanted.enabled => (have, enabled_kind(have)),
+            Some((have, new)) if have.enabled && reg(new) != reg(old) => (have, DriftKind::Changed),
+            Some(_) => continue,
+        };
+        drift.push(Drift {
+            item: item.clone(),
+            kind,
+        });
+    }
+    for (have, _) in &now {
+        if !before.iter().any(|(wanted, _)| wanted.matches(have)) {
+            drift.push(Drift {
+                item: have.clone(),
+                kind: DriftKind::Added,
+            });
+        }
+    }
+    for scope in Scope::iter() {
+        let blocked = Blocks::new(&scratch, scope);
+        for item in Type::Win11.list_in(backend, Some(scope)) {
+            if item.enabled == blocked.contains(&item.id) {
+                let item = ProfileItem::new(Type::Win11, Some(scope), &item);
+                let kind = enabled_kind(&item);
+                drift.push(Drift { item, kind });
+            }
+        }
+    }
+    Ok(drift)
+}
+
+fn enabled_kind(item: &ProfileItem) -> DriftKind {
+    if item.enabled {
+        DriftKind::Enabled
+    } else {
+        DriftKind::Disabled
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DriftKind, drift_from_profile, drift_from_snapshot};
+    use crate::{MemoryBackend, Profile, RegItemValue, RegistryBackend, SceneRoot, Snapshot, Type};
+
+    #[test]
+    fn test_drift() {
+        let reg = MemoryBackend::new();
+        let (wt, format, git) = (
+            r"Directory\Background\Shell\wt",
+            r"Drive\Shell\format",
+            r"Folder\shell\git",
+        );
+        reg.add_verb(wt, "Open in Terminal", "wt.exe");
+        reg.add_verb(format, "Format", "format.exe");
+        let backup = Type::Win10.list_in(&reg, None);
+        let profile = Profile::capture_in(&reg, "fleet", &backup, 0);
+        let snapshot = Snapshot::capture(&reg).unwrap();
+        assert!(drift_from_profile(&reg, &backup, &profile).is_empty());
+        assert!(
+            drift_from_snapshot(&reg, &backup, &snapshot)
+                .unwrap()
+                .is_empty()
+        );
+
+        reg.delete_tree(SceneRoot::HKCR, format).unwrap();
+        let title = RegItemValue::SZ("Terminal".to_string()).to_raw();
+        reg.set_value(SceneRoot::HKCR, wt, "", &title).unwrap();
+        reg.add_verb(git, "Git", "git.exe");
+        let kinds = |drift: Vec<super::Drift>| {
+            let mut kinds: Vec<_> = drift.into_iter().map(|d| (d.item.id, d.kind)).collect();
+            kinds.sort_by(|a, b| a.0.cmp(&b.0));
+            kinds
+        };
+        assert_eq!(
+            kinds(drift_from_profile(&reg, &backup, &profile)),
+            [(format.to_string(), DriftKind::Disabled)]
+        );
+        assert_eq!(
+            kinds(drift_from_snapshot(&reg, &backup, &snapshot).unwrap()),
+            [
+                (wt.to_string(), DriftKind::Changed),
+                (format.to_string(), DriftKind::Disabled),
+                (git.to_string(), DriftKind::Added),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_snapshot_covers_only_its_keys() {
+        // no Win11 packages on this registry, on Windows too
+        let reg = MemoryBackend::new();
+        reg.add_verb(r"Drive\Shell\format", "Format", "format.exe");
+        reg.create_key(SceneRoot::HKCR, ".txt").unwrap();
+        let txtfile = RegItemValue::SZ("txtfile".to_string()).to_raw();
+        reg.set_value(SceneRoot::HKCR, ".txt", "", &txtfile)
+            .unwrap();
+        let ids: Vec<_> = Type::Win10
+            .list_in(&reg, None)
+            .into_iter()
+            .map(|i| i.id)
+            .collect();
+        assert!(ids.iter().any(|id| id == ".txt"), "{ids:?}");
+
+        let snapshot = Snapshot::capture(&reg).unwrap();
+        assert!(!snapshot.covers(SceneRoot::HKCR, ".txt"));
+        assert!(
+            drift_from_snapshot(&reg, &[], &snapshot)
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,350 @@
+use crate::Hive;
+use crate::RawValue;
+use crate::RegistryBackend;
+use crate::SceneRoot;
+use crate::backend::{join_path, strip_path_prefix};
+use crate::regf::names_equal;
+use std::cell::RefCell;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use strum_macros::Display;
+use strum_macros::EnumIter;
+use strum_macros::EnumString;
+
+/// The offline hive files that hold context menu registrations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumIter, EnumString, Display)]
+#[strum(ascii_case_insensitive)]
+pub enum HiveKind {
+    /// `NTUSER.DAT`, loaded as HKEY_CURRENT_USER
+    NtUser,
+    /// `UsrClass.dat`, loaded as HKEY_CURRENT_USER\Software\Classes
+    UsrClass,
+    /// `SOFTWARE`, loaded as HKEY_LOCAL_MACHINE\SOFTWARE
+    Software,
+}
+
+impl HiveKind {
+    /// Where the hive is mounted on a running system.
+    pub fn mount_point(&self) -> (SceneRoot, &'static str) {
+        match self {
+            HiveKind::NtUser => (SceneRoot::HKCU, ""),
+            HiveKind::UsrClass => (SceneRoot::HKCU, r"Software\Classes"),
+            HiveKind::Software => (SceneRoot::HKLM, "SOFTWARE"),
+        }
+    }
+
+    /// Guesses the kind from the file name, falling back to the name stored
+    /// inside the hive.
+    pub fn detect(path: &Path, hive: &Hive) -> Option<HiveKind> {
+        let file_name = path
+            .file_name()
+            .map(|s| s.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let stored = hive.file_name().to_lowercase().replace('/', "\\");
+        for name in [
+            file_name.as_str(),
+            stored.rsplit('\\').next().unwrap_or_default(),
+        ] {
+            match name {
+                "ntuser.dat" => return Some(HiveKind::NtUser),
+                "usrclass.dat" => return Some(HiveKind::UsrClass),
+                "software" => return Some(HiveKind::Software),
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+fn not_found(root: SceneRoot, path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{root}\\{path}"))
+}
+
+#[derive(Debug)]
+struct Mounted {
+    kind: HiveKind,
+    hive: RefCell<Hive>,
+    /// The file `save` writes the hive back to.
+    path: Option<PathBuf>,
+}
+
+/// A registry assembled from offline hive files, laid out the way Windows
+/// mounts them. HKEY_CLASSES_ROOT is the merged view of the user and machine
+/// `Classes` keys, with the user hive taking precedence.
+///
+/// Changes are made to the in-memory hives and written back by `save`.
+#[derive(Debug, Default)]
+pub struct HiveBackend {
+    hives: Vec<Mounted>,
+}
+
+impl HiveBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn mount(&mut self, kind: HiveKind, hive: Hive) {
+        self.hives.push(Mounted {
+            kind,
+            hive: RefCell::new(hive),
+            path: None,
+        });
+    }
+
+    /// Loads a hive file, detecting its kind when `kind` is `None`.
+    pub fn load(&mut self, path: &Path, kind: Option<HiveKind>) -> io::Result<HiveKind> {
+        let hive = Hive::open(path)?;
+        let kind = kind
+            .or_else(|| HiveKind::detect(path, &hive))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("cannot tell which hive {} is", path.display()),
+                )
+            })?;
+        self.mount(kind, hive);
+        if let Some(mounted) = self.hives.last_mut() {
+            mounted.path = Some(path.to_path_buf());
+        }
+        Ok(kind)
+    }
+
+    /// Writes every modified hive back to the file it was loaded from.
+    pub fn save(&self) -> io::Result<()> {
+        for mounted in &self.hives {
+            let mut hive = mounted.hive.borrow_mut();
+            if let Some(path) = &mounted.path
+                && hive.is_modified()
+            {
+                hive.save(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Hives and paths inside them that back `root\path`, most specific first.
+    fn resolve(&self, root: SceneRoot, path: &str) -> Vec<(&Mounted, String)> {
+        if root == SceneRoot::HKCR {
+            let mut v = self.resolve(SceneRoot::HKCU, &join_path(r"Software\Classes", path));
+            v.extend(self.resolve(SceneRoot::HKLM, &join_path(r"SOFTWARE\Classes", path)));
+            return v;
+        }
+
+        let mut v: Vec<_> = self
+            .hives
+            .iter()
+            .filter_map(|mounted| {
+                let (mount_root, prefix) = mounted.kind.mount_point();
+                if mount_root != root {
+                    return None;
+                }
+                let inner = strip_path_prefix(path, prefix)?;
+                Some((prefix.len(), mounted, inner.to_string()))
+            })
+            .collect();
+        v.sort_by_key(|(prefix_len, ..)| std::cmp::Reverse(*prefix_len));
+        v.into_iter()
+            .map(|(_, mounted, inner)| (mounted, inner))
+            .collect()
+    }
+
+    /// The first hive that has `root\path`.
+    fn existing(&self, root: SceneRoot, path: &str) -> io::Result<(&Mounted, String)> {
+        for (mounted, inner) in self.resolve(root, path) {
+            if mounted.hive.borrow().find_key(&inner)?.is_some() {
+                return Ok((mounted, inner));
+            }
+        }
+        Err(not_found(root, path))
+    }
+
+    /// The hive a new key lands in: the one that already has most of its
+    /// parents, with ties under HKEY_CLASSES_ROOT going to the machine hive
+    /// like they do on Windows.
+    fn create_target(&self, root: SceneRoot, path: &str) -> io::Result<Option<(&Mounted, String)>> {
+        let mut best: Option<(usize, &Mounted, String)> = None;
+        for (mounted, inner) in self.resolve(root, path) {
+            let depth = mounted.hive.borrow().existing_depth(&inner)?;
+            if best
+                .as_ref()
+                .is_none_or(|(d, ..)| depth > *d || (depth == *d && root == SceneRoot::HKCR))
+            {
+                best = Some((depth, mounted, inner));
+            }
+        }
+        Ok(best.map(|(_, mounted, inner)| (mounted, inner)))
+    }
+
+    /// Keys that only exist because a hive is mounted below them, such as
+    /// `Software` when only `UsrClass.dat` is loaded.
+    fn mount_parents(&self, root: SceneRoot, path: &str) -> Vec<String> {
+        self.hives
+            .iter()
+            .filter_map(|mounted| {
+                let (mount_root, prefix) = mounted.kind.mount_point();
+                if mount_root != root || prefix.is_empty() {
+                    return None;
+                }
+                let rest = strip_path_prefix(prefix, path)?;
+                rest.split('\\')
+                    .next()
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_string)
+            })
+            .collect()
+    }
+}
+
+impl RegistryBackend for HiveBackend {
+    fn enum_keys(&self, root: SceneRoot, path: &str) -> io::Result<Vec<String>> {
+        let mut found = false;
+        let mut names: Vec<String> = vec![];
+        let mut add = |name: String| {
+            if !names.iter().any(|n| names_equal(n, &name)) {
+                names.push(name);
+            }
+        };
+        for (mounted, inner) in self.resolve(root, path) {
+            match mounted.hive.borrow().subkey_names(&inner) {
+                Ok(keys) => {
+                    found = true;
+                    keys.into_iter().for_each(&mut add);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let parents = if root == SceneRoot::HKCR {
+            vec![]
+        } else {
+            self.mount_parents(root, path)
+        };
+        found |= !parents.is_empty();
+        parents.into_iter().for_each(add);
+
+        if found {
+            Ok(names)
+        } else {
+            Err(not_found(root, path))
+        }
+    }
+
+    fn enum_values(&self, root: SceneRoot, path: &str) -> io::Result<Vec<(String, RawValue)>> {
+        for (mounted, inner) in self.resolve(root, path) {
+            match mounted.hive.borrow().values(&inner) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => return result,
+            }
+        }
+        if root != SceneRoot::HKCR && !self.mount_parents(root, path).is_empty() {
+            return Ok(vec![]);
+        }
+        Err(not_found(root, path))
+    }
+
+    fn create_key(&self, root: SceneRoot, path: &str) -> io::Result<()> {
+        match self.create_target(root, path)? {
+            Some((mounted, inner)) => mounted.hive.borrow_mut().create_key(&inner),
+            None if !self.mount_parents(root, path).is_empty() => Ok(()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no hive is mounted for {root}\\{path}"),
+            )),
+        }
+    }
+
+    fn set_value(
+        &self,
+        root: SceneRoot,
+        path: &str,
+        name: &str,
+        value: &RawValue,
+    ) -> io::Result<()> {
+        let (mounted, inner) = self.existing(root, path)?;
+        mounted.hive.borrow_mut().set_value(&inner, name, value)
+    }
+
+    fn delete_value(&self, root: SceneRoot, path: &str, name: &str) -> io::Result<()> {
+        let (mounted, inner) = self.existing(root, path)?;
+        mounted.hive.borrow_mut().delete_value(&inner, name)
+    }
+
+    fn delete_key(&self, root: SceneRoot, path: &str) -> io::Result<()> {
+        let (mounted, inner) = self.existing(root, path)?;
+        mounted.hive.borrow_mut().delete_key(&inner)
+    }
+
+    fn last_write_time(&self, root: SceneRoot, path: &str) -> io::Result<u64> {
+        for (mounted, inner) in self.resolve(root, path) {
+            match mounted.hive.borrow().last_write_time(&inner) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => return result,
+            }
+        }
+        Err(not_found(root, path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HiveBackend, HiveKind};
+    use crate::regf::test::sample_hive;
+    use crate::test_support::TempDir;
+    use crate::{Hive, RegItem, RegItemValue, RegistryBackend, SceneRoot};
+
+    #[test]
+    fn test_classes_root_view() {
+        let mut reg = HiveBackend::new();
+        reg.mount(HiveKind::UsrClass, Hive::from_bytes(sample_hive()).unwrap());
+
+        assert_eq!(reg.enum_keys(SceneRoot::HKCR, "").unwrap(), ["Shell"]);
+        assert_eq!(
+            reg.enum_keys(SceneRoot::HKCU, "software").unwrap(),
+            ["Classes"]
+        );
+        assert!(reg.key_exists(SceneRoot::HKCU, r"Software\Classes\Shell\Open"));
+        assert!(!reg.key_exists(SceneRoot::HKLM, "SOFTWARE"));
+
+        let item = RegItem::from_path_in(&reg, SceneRoot::HKCR, r"Shell\Open").unwrap();
+        assert_eq!(
+            item.get_value(""),
+            Some(&RegItemValue::SZ("Open".to_string()))
+        );
+        assert_eq!(item.get_value("flags"), Some(&RegItemValue::DWORD(2)));
+        assert!(reg.create_key(SceneRoot::HKLM, "SOFTWARE").is_err());
+    }
+
+    #[test]
+    fn test_write_and_save() {
+        let dir = TempDir::new("hive");
+        let path = dir.join("UsrClass.dat");
+        std::fs::write(&path, sample_hive()).unwrap();
+        let mut reg = HiveBackend::new();
+        assert_eq!(reg.load(&path, None).unwrap(), HiveKind::UsrClass);
+
+        let item_path = r"Directory\Background\Shell\wt";
+        reg.create_key(SceneRoot::HKCR, &format!(r"{item_path}\command"))
+            .unwrap();
+        reg.set_value(
+            SceneRoot::HKCR,
+            item_path,
+            "",
+            &RegItemValue::SZ("Open in Terminal".to_string()).to_raw(),
+        )
+        .unwrap();
+        let item = RegItem::from_path_in(&reg, SceneRoot::HKCR, item_path).unwrap();
+        item.delete_in(&reg).unwrap();
+        assert!(!reg.key_exists(SceneRoot::HKCR, item_path));
+        item.write_in(&reg).unwrap();
+        reg.save().unwrap();
+
+        let mut reloaded = HiveBackend::new();
+        reloaded.load(&path, None).unwrap();
+        assert_eq!(
+            RegItem::from_path_in(&reloaded, SceneRoot::HKCR, item_path).unwrap(),
+            item
+        );
+        assert!(reloaded.key_exists(SceneRoot::HKCU, r"Software\Classes\Shell\Open"));
+    }
+}
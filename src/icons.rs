@@ -0,0 +1,119 @@
+use crate::MenuItemInfo;
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A directory of icons, each stored once in a file named after the SHA-256
+/// of its bytes.
+#[derive(Debug, Clone)]
+pub struct IconStore {
+    dir: PathBuf,
+}
+
+impl IconStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        IconStore { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The name `icon` is stored under.
+    pub fn hash(icon: &[u8]) -> String {
+        Sha256::digest(icon)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    fn file(&self, hash: &str) -> io::Result<PathBuf> {
+        if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid icon hash {hash:?}"),
+            ));
+        }
+        Ok(self.dir.join(hash))
+    }
+
+    /// Stores `icon` unless it's already there and returns its hash.
+    pub fn put(&self, icon: &[u8]) -> io::Result<String> {
+        let hash = IconStore::hash(icon);
+        let file = self.file(&hash)?;
+        if !file.exists() {
+            std::fs::create_dir_all(&self.dir)?;
+            let tmp = self.dir.join(format!("{hash}.{}.tmp", std::process::id()));
+            std::fs::write(&tmp, icon)?;
+            std::fs::rename(tmp, file)?;
+        }
+        Ok(hash)
+    }
+
+    /// Reads the icon stored under `hash`, checking it wasn't damaged.
+    pub fn get(&self, hash: &str) -> io::Result<Vec<u8>> {
+        let icon = std::fs::read(self.file(hash)?)?;
+        if IconStore::hash(&icon) != hash.to_ascii_lowercase() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("icon {hash} is damaged"),
+            ));
+        }
+        Ok(icon)
+    }
+
+    /// Moves the icon of `info` into the store, leaving its hash behind.
+    pub fn store_icon(&self, info: &mut MenuItemInfo) -> io::Result<()> {
+        if let Some(icon) = info.icon.take() {
+            info.icon_hash = Some(self.put(&icon)?);
+        }
+        Ok(())
+    }
+
+    /// Loads the icon `info` refers to back in. The reference stays when the
+    /// icon can't be read, so writing `info` out again doesn't lose it.
+    pub fn load_icon(&self, info: &mut MenuItemInfo) {
+        if let Some(hash) = &info.icon_hash
+            && let Ok(icon) = self.get(hash)
+        {
+            info.icon = Some(icon);
+            info.icon_hash = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::IconStore;
+    use crate::MenuItemInfo;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn test_icon_store() {
+        let dir = TempDir::new("icons");
+        let store = IconStore::new(dir.path());
+        let hash = store.put(b"icon").unwrap();
+        assert_eq!(hash, IconStore::hash(b"icon"));
+        assert_eq!(hash.len(), 64);
+        assert_eq!(store.put(b"icon").unwrap(), hash);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert_eq!(store.get(&hash).unwrap(), b"icon");
+        assert!(store.get("../backup.json").is_err());
+
+        let mut info = MenuItemInfo {
+            icon: Some(b"icon".to_vec()),
+            ..Default::default()
+        };
+        store.store_icon(&mut info).unwrap();
+        assert_eq!(
+            (info.icon.as_ref(), info.icon_hash.as_ref()),
+            (None, Some(&hash))
+        );
+        store.load_icon(&mut info);
+        assert_eq!(info.icon.as_deref(), Some(&b"icon"[..]));
+        assert_eq!(info.icon_hash, None);
+
+        std::fs::write(dir.join(&hash), b"other").unwrap();
+        assert!(store.get(&hash).is_err());
+    }
+}
@@ -0,0 +1,391 @@
+use crate::APP_NAME;
+use crate::MemoryBackend;
+use crate::RegItem;
+use crate::RegOp;
+use crate::RegistryBackend;
+use crate::SceneRoot;
+use crate::SnapshotKey;
+use crate::apply_ops;
+use crate::snapshot::{read_key, timestamp, unix_now};
+use crate::transaction::capture;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::io;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+#[cfg(all(windows, feature = "live"))]
+use crate::WinRegBackend;
+
+/// One key a journal entry changed, `None` when it didn't exist.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct JournalKey {
+    pub root: SceneRoot,
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<RegItem>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<RegItem>,
+}
+
+/// A change wcm made to the registry.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct JournalEntry {
+    /// Position in the journal, starting at 1.
+    pub seq: u64,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    /// The command that made the change, such as `win10 disable foo`.
+    pub action: String,
+    pub keys: Vec<JournalKey>,
+    /// The entries an undo reverted.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub undoes: Vec<u64>,
+}
+
+impl JournalEntry {
+    /// The changes that put every key back to its state before the entry.
+    pub fn plan_undo(&self, backend: &dyn RegistryBackend) -> io::Result<Vec<RegOp>> {
+        let mut ops = vec![];
+        for key in self.keys.iter().rev() {
+            let before = SnapshotKey {
+                root: key.root,
+                path: key.path.clone(),
+                item: key.before.clone(),
+            };
+            ops.extend(before.plan_restore(backend)?);
+        }
+        Ok(ops)
+    }
+}
+
+impl Display for JournalEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "#{} {} {}",
+            self.seq,
+            timestamp(self.created),
+            self.action
+        )
+    }
+}
+
+/// The changes that revert `entries`, newest first.
+///
+/// Later entries may have changed the keys of earlier ones, so each entry is
+/// planned against a copy of the keys with the newer ones already reverted.
+pub fn plan_undo(
+    backend: &dyn RegistryBackend,
+    entries: &[JournalEntry],
+) -> io::Result<Vec<RegOp>> {
+    let scratch = MemoryBackend::new();
+    for key in entries.iter().flat_map(|e| &e.keys) {
+        let current = SnapshotKey::capture(backend, key.root, &key.path)?;
+        for op in current.plan_restore(&scratch)? {
+            op.apply(&scratch)?;
+        }
+    }
+    let mut ops = vec![];
+    for entry in entries.iter().rev() {
+        let entry_ops = entry.plan_undo(&scratch)?;
+        for op in &entry_ops {
+            op.apply(&scratch)?;
+        }
+        ops.extend(entry_ops);
+    }
+    Ok(ops)
+}
+
+/// An append-only file of every change wcm made, one JSON entry per line.
+#[derive(Debug, Clone)]
+pub struct Journal {
+    file: PathBuf,
+}
+
+impl Journal {
+    pub fn new(file: impl Into<PathBuf>) -> Self {
+        Journal { file: file.into() }
+    }
+
+    /// `journal.jsonl` in the wcm config directory.
+    pub fn open_default() -> Option<Self> {
+        let dir = dirs::config_local_dir()?.join(APP_NAME);
+        Some(Journal::new(dir.join("journal.jsonl")))
+    }
+
+    /// Every entry, oldest first.
+    pub fn entries(&self) -> io::Result<Vec<JournalEntry>> {
+        let file = match std::fs::File::open(&self.file) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut entries = vec![];
+        for line in io::BufReader::new(file).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                entries.push(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Entries `undo` can still revert, oldest first. Undos themselves and
+    /// the entries they reverted are left out.
+    pub fn undoable(&self) -> io::Result<Vec<JournalEntry>> {
+        let entries = self.entries()?;
+        let undone: Vec<u64> = entries.iter().flat_map(|e| e.undoes.clone()).collect();
+        Ok(entries
+            .into_iter()
+            .filter(|e| e.undoes.is_empty() && !undone.contains(&e.seq))
+            .collect())
+    }
+
+    /// Locks the journal against other wcm processes until the file is
+    /// dropped.
+    fn lock(&self) -> io::Result<std::fs::File> {
+        if let Some(dir) = self.file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut path = self.file.clone().into_os_string();
+        path.push(".lock");
+        let file = std::fs::File::create(path)?;
+        file.lock()?;
+        Ok(file)
+    }
+
+    /// Adds `entry` after the last one. The caller holds the lock, so the
+    /// sequence number stays unique.
+    fn append(&self, entry: &mut JournalEntry) -> io::Result<()> {
+        entry.seq = self.entries()?.last().map_or(1, |e| e.seq + 1);
+        if let Some(dir) = self.file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.file)?;
+        let mut line = serde_json::to_string(&*entry)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())
+    }
+
+    /// Runs `apply`, which makes the changes in `ops`, and records the state
+    /// of the keys they touch before and after. Nothing is recorded when
+    /// nothing changed.
+    pub fn record<T, E: From<io::Error>>(
+        &self,
+        backend: &dyn RegistryBackend,
+        action: &str,
+        ops: &[RegOp],
+        apply: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        self.record_undo(backend, action, ops, vec![], apply)
+    }
+
+    fn record_undo<T, E: From<io::Error>>(
+        &self,
+        backend: &dyn RegistryBackend,
+        action: &str,
+        ops: &[RegOp],
+        undoes: Vec<u64>,
+        apply: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        // held from before the keys are read until the entry is written, so
+        // the changes of other wcm processes don't end up in this entry
+        let _lock = self.lock()?;
+        if !undoes.is_empty() {
+            let undoable = self.undoable()?;
+            if let Some(seq) = undoes
+                .iter()
+                .find(|seq| !undoable.iter().any(|e| e.seq == **seq))
+            {
+                return Err(io::Error::other(format!("#{seq} can't be undone anymore")).into());
+            }
+        }
+        let mut captured = vec![];
+        for op in ops {
+            capture(backend, &mut captured, op.root(), op.path())?;
+        }
+        let result = apply();
+        // whatever `apply` got through before failing is recorded too
+        let recorded = (|| {
+            let mut keys = vec![];
+            for before in captured {
+                let after = read_key(backend, before.root, &before.path)?;
+                if after != before.item {
+                    keys.push(JournalKey {
+                        root: before.root,
+                        path: before.path,
+                        before: before.item,
+                        after,
+                    });
+                }
+            }
+            if keys.is_empty() && undoes.is_empty() {
+                return Ok(());
+            }
+            self.append(&mut JournalEntry {
+                seq: 0,
+                created: unix_now(),
+                action: action.to_string(),
+                keys,
+                undoes,
+            })
+        })();
+        let value = result?;
+        recorded?;
+        Ok(value)
+    }
+
+    /// Reverts `entries`, newest first, and records that as one entry.
+    pub fn undo(
+        &self,
+        backend: &dyn RegistryBackend,
+        entries: &[JournalEntry],
+    ) -> anyhow::Result<()> {
+        let ops = plan_undo(backend, entries)?;
+        let seqs: Vec<u64> = entries.iter().map(|e| e.seq).collect();
+        let names: Vec<String> = seqs.iter().map(|seq| format!("#{seq}")).collect();
+        let action = format!("undo {}", names.join(", "));
+        // the journal holds keys outside the scene roots, like `Blocked`
+        self.record_undo(backend, &action, &ops, seqs, || {
+            apply_ops(backend, &ops, true)
+        })
+    }
+}
+
+/// Runs `apply` against the live registry, recording it in the default
+/// journal when there is one.
+#[cfg(all(windows, feature = "live"))]
+pub(crate) fn record_live<T, E: From<io::Error>>(
+    action: &str,
+    ops: &[RegOp],
+    apply: impl FnOnce() -> Result<T, E>,
+) -> Result<T, E> {
+    match Journal::open_default() {
+        Some(journal) => journal.record(&WinRegBackend, action, ops, apply),
+        None => apply(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Journal, plan_undo};
+    use crate::test_support::TempDir;
+    use crate::{
+        MemoryBackend, MenuItem, MenuItemInfo, RegItem, RegItemValue, RegistryBackend, SceneRoot,
+        Transaction, Type, apply_ops, get_context_menu_style_in, plan_context_menu_style_in,
+        set_context_menu_style_in,
+    };
+
+    #[test]
+    fn test_record_and_undo() {
+        let dir = TempDir::new("journal");
+        let file = dir.join("journal.jsonl");
+        let journal = Journal::new(&file);
+        let reg = MemoryBackend::new();
+        reg.create_key(SceneRoot::HKCU, r"SOFTWARE\Classes\CLSID")
+            .unwrap();
+        let format = r"Drive\shell\format";
+        reg.create_key(SceneRoot::HKCR, &format!(r"{format}\command"))
+            .unwrap();
+        let title = RegItemValue::SZ("Format".to_string()).to_raw();
+        reg.set_value(SceneRoot::HKCR, format, "", &title).unwrap();
+        let backup = [MenuItem {
+            id: "format".to_string(),
+            info: Some(MenuItemInfo {
+                reg: Some(RegItem::from_path_in(&reg, SceneRoot::HKCR, format).unwrap()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }];
+
+        let mut tx = Transaction::new();
+        tx.disable(Type::Win10, "format", None);
+        let ops = tx.plan(&reg, &backup).unwrap().concat();
+        journal
+            .record(&reg, "win10 disable format", &ops, || {
+                apply_ops(&reg, &ops, false)
+            })
+            .unwrap();
+        let ops = plan_context_menu_style_in(&reg, false);
+        journal
+            .record(&reg, "switch win10", &ops, || {
+                set_context_menu_style_in(&reg, false)
+            })
+            .unwrap();
+        // nothing changes, nothing is recorded
+        journal
+            .record(&reg, "switch win10", &[], || {
+                set_context_menu_style_in(&reg, false)
+            })
+            .unwrap();
+        let entries = journal.entries().unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| e.action.as_str())
+                .collect::<Vec<_>>(),
+            ["win10 disable format", "switch win10"]
+        );
+        assert!(!reg.key_exists(SceneRoot::HKCR, format));
+
+        assert_eq!(
+            plan_undo(&reg, &entries)
+                .unwrap()
+                .iter()
+                .map(|op| op.to_string())
+                .collect::<Vec<_>>(),
+            [
+                r"- [HKEY_CURRENT_USER\SOFTWARE\Classes\CLSID\{86ca1aa0-34aa-4e8b-a509-50c905bae2a2}]",
+                r"+ [HKEY_CLASSES_ROOT\Drive\shell\format]",
+                r"~ [HKEY_CLASSES_ROOT\Drive\shell\format] @ = Format",
+                r"+ [HKEY_CLASSES_ROOT\Drive\shell\format\command]",
+            ]
+        );
+        journal.undo(&reg, &entries[1..]).unwrap();
+        assert!(!get_context_menu_style_in(&reg));
+        let undoable = journal.undoable().unwrap();
+        assert_eq!(undoable, entries[..1]);
+        journal.undo(&reg, &undoable).unwrap();
+        assert!(reg.key_exists(SceneRoot::HKCR, &format!(r"{format}\command")));
+        assert!(journal.undoable().unwrap().is_empty());
+        assert_eq!(journal.entries().unwrap().last().unwrap().action, "undo #1");
+        assert!(journal.undo(&reg, &entries[..1]).is_err());
+    }
+
+    #[test]
+    fn test_concurrent_record() {
+        let dir = TempDir::new("journal-mt");
+        let file = dir.join("journal.jsonl");
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let journal = Journal::new(&file);
+                std::thread::spawn(move || {
+                    let reg = MemoryBackend::new();
+                    for n in 0..5 {
+                        let ops = [crate::RegOp::CreateKey {
+                            root: SceneRoot::HKCR,
+                            path: format!(r"Drive\shell\t{t}-{n}"),
+                        }];
+                        journal
+                            .record(&reg, "test", &ops, || apply_ops(&reg, &ops, false))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let seqs: Vec<u64> = Journal::new(&file)
+            .entries()
+            .unwrap()
+            .iter()
+            .map(|e| e.seq)
+            .collect();
+        assert_eq!(seqs, (1..=20).collect::<Vec<_>>());
+    }
+}
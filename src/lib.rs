@@ -1,5 +1,39 @@
 mod win10;
 mod win11;
 
+mod backend;
+mod backup;
 mod core;
+mod diff;
+mod drift;
+mod hive;
+mod icons;
+mod journal;
+mod memory;
+mod ops;
+mod profile;
+mod reg_file;
+mod regf;
+mod snapshot;
+mod state;
+#[cfg(test)]
+mod test_support;
+mod transaction;
+mod verb;
+pub use backend::*;
+pub use backup::*;
 pub use core::*;
+pub use diff::*;
+pub use drift::*;
+pub use hive::*;
+pub use icons::*;
+pub use journal::*;
+pub use memory::*;
+pub use ops::*;
+pub use profile::*;
+pub use reg_file::*;
+pub use regf::*;
+pub use snapshot::*;
+pub use state::*;
+pub use transaction::*;
+pub use verb::*;
@@ -1,4 +1,6 @@
 use clap::{Parser, Subcommand};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use wcm::*;
 
 #[derive(Parser)]
@@ -13,6 +15,9 @@ enum Commands {
     Switch {
         #[clap(value_enum)]
         r#type: Option<Type>,
+        /// Print the changes instead of making them
+        #[clap(long)]
+        dry_run: bool,
     },
     Win10 {
         #[clap(subcommand)]
@@ -23,80 +28,1179 @@ enum Commands {
         command: Win11Command,
     },
     RestartExplorer,
+    /// Apply a .reg file, showing the changes first
+    Import {
+        file: PathBuf,
+        /// Apply without asking for confirmation
+        #[clap(long, short)]
+        yes: bool,
+        /// Allow changes outside the known context menu locations
+        #[clap(long)]
+        force: bool,
+        /// Apply to offline hive files instead of the live registry
+        #[clap(long)]
+        hive: Vec<String>,
+        /// Print the changes instead of making them
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Show what changed between two sets of keys, each given as a .reg
+    /// file, a backup .json file, a key such as `HKCR\Directory\shell`, or
+    /// `live` for the live copies of the keys on the other side
+    Diff {
+        old: String,
+        new: String,
+        /// Read keys from offline hive files instead of the live registry
+        #[clap(long)]
+        hive: Vec<String>,
+    },
+    /// Save and restore the whole context menu state
+    Snapshot {
+        #[clap(subcommand)]
+        command: SnapshotCommand,
+    },
+    /// Revert the last changes made through wcm
+    Undo {
+        #[clap(default_value_t = 1)]
+        count: usize,
+        /// Apply without asking for confirmation
+        #[clap(long, short)]
+        yes: bool,
+        /// Print the changes instead of making them
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// List the changes made through wcm
+    History,
+    /// Manage the copies of Win10 items kept to enable them again
+    Backup {
+        #[clap(subcommand)]
+        command: BackupCommand,
+    },
+    /// Compare the items with a profile or snapshot and list the ones that
+    /// drifted. Exits with 0 when nothing drifted, 1 when something did and
+    /// 2 when the check couldn't be made
+    Check {
+        /// A profile name, a snapshot id, or a profile or snapshot .json file
+        #[clap(long)]
+        against: String,
+        /// Read offline hive files instead of the live registry
+        #[clap(long)]
+        hive: Vec<String>,
+    },
+    /// Show what it takes to reach the state described in a TOML or JSON
+    /// file
+    Plan {
+        file: PathBuf,
+        /// Read offline hive files instead of the live registry
+        #[clap(long)]
+        hive: Vec<String>,
+    },
+    /// Enable and disable items and switch the menu style as a TOML or JSON
+    /// file describes
+    Apply {
+        file: PathBuf,
+        /// Apply without asking for confirmation
+        #[clap(long, short)]
+        yes: bool,
+        /// Write changes to offline hive files instead of the live registry
+        #[clap(long)]
+        hive: Vec<String>,
+        /// Show the registry changes without making them
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Save and apply which items are enabled, to set up machines the same
+    /// way
+    Profile {
+        #[clap(subcommand)]
+        command: ProfileCommand,
+    },
 }
 
 #[derive(Subcommand)]
-enum Win10Command {
+enum ProfileCommand {
+    /// Save whether each item is enabled now
+    Save {
+        name: String,
+        /// Read offline hive files instead of the live registry
+        #[clap(long)]
+        hive: Vec<String>,
+    },
+    /// Enable and disable items as the profile says, listing the items it
+    /// mentions that aren't installed and the ones it doesn't mention
+    Apply {
+        name: String,
+        /// Apply without asking for confirmation
+        #[clap(long, short)]
+        yes: bool,
+        /// Apply to offline hive files instead of the live registry
+        #[clap(long)]
+        hive: Vec<String>,
+        /// Print the changes instead of making them
+        #[clap(long)]
+        dry_run: bool,
+    },
     List,
-    Enable { id: String },
-    Disable { id: String },
+    Delete {
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum BackupCommand {
+    /// List backed up items, when they were seen and whether they are
+    /// present now
+    List {
+        /// Read offline hive files instead of the live registry
+        #[clap(long)]
+        hive: Vec<String>,
+    },
+    /// Show every stored revision of an item
+    Show { id: String },
+    /// Write some or all items to a file another machine can import, with
+    /// their icons in an `icons` directory next to it
+    Export {
+        file: PathBuf,
+        /// The items to export, all of them if none are given
+        ids: Vec<String>,
+    },
+    /// Add the items of an exported backup
+    Import { file: PathBuf },
+    /// Remove items that are gone from the registry and weren't seen for a
+    /// while
+    Prune {
+        /// Only items not seen for this many days
+        #[clap(long, default_value_t = 180)]
+        days: u64,
+        /// Remove without asking for confirmation
+        #[clap(long, short)]
+        yes: bool,
+        /// Print the items instead of removing them
+        #[clap(long)]
+        dry_run: bool,
+        /// Read offline hive files instead of the live registry
+        #[clap(long)]
+        hive: Vec<String>,
+    },
+    /// Check that every item can be read and written back
+    Verify,
+}
+
+#[derive(Subcommand)]
+enum SnapshotCommand {
+    Create {
+        /// Read offline hive files instead of the live registry
+        #[clap(long)]
+        hive: Vec<String>,
+    },
+    List,
+    /// Show the changes and return to a snapshot
+    Restore {
+        id: String,
+        /// Apply without asking for confirmation
+        #[clap(long, short)]
+        yes: bool,
+        /// Apply to offline hive files instead of the live registry
+        #[clap(long)]
+        hive: Vec<String>,
+        /// Print the changes instead of making them
+        #[clap(long)]
+        dry_run: bool,
+    },
+    Delete {
+        id: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum Win10Command {
+    List {
+        /// Read an offline hive file instead of the live registry,
+        /// as `path` or `ntuser|usrclass|software=path`
+        #[clap(long)]
+        hive: Vec<String>,
+        /// Only the items on these menus, such as `drive,background`
+        #[clap(long, value_enum, value_delimiter = ',')]
+        scene: Vec<Scene>,
+    },
+    Enable {
+        /// The id `wcm win10 list` shows, with the root in front, such as
+        /// `HKLM\`, when more than one root holds it
+        id: String,
+        /// Apply to offline hive files instead of the live registry
+        #[clap(long)]
+        hive: Vec<String>,
+        /// Print the changes instead of making them
+        #[clap(long)]
+        dry_run: bool,
+        /// Write back an earlier copy from the backup, as numbered by
+        /// `wcm backup show`
+        #[clap(long)]
+        revision: Option<usize>,
+    },
+    Disable {
+        /// The id `wcm win10 list` shows, with the root in front, such as
+        /// `HKLM\`, when more than one root holds it
+        id: String,
+        /// Apply to offline hive files instead of the live registry
+        #[clap(long)]
+        hive: Vec<String>,
+        /// Print the changes instead of making them
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Create a verb, such as `--scene directory-background --name
+    /// "Open terminal here" --command "wt.exe -d \"%V\""`
+    Add {
+        #[clap(long, value_enum)]
+        scene: Scene,
+        /// The text shown in the menu
+        #[clap(long)]
+        name: String,
+        /// The command line, `%1` or `%V` stand for the clicked item
+        #[clap(long)]
+        command: String,
+        /// An icon file, or `file.dll,index`
+        #[clap(long)]
+        icon: Option<String>,
+        /// Only show it with Shift held down
+        #[clap(long)]
+        extended: bool,
+        #[clap(long, value_enum)]
+        position: Option<Position>,
+        /// Apply to offline hive files instead of the live registry
+        #[clap(long)]
+        hive: Vec<String>,
+        /// Print the changes instead of making them
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Change the values of a verb, the flags take `true` or `false`
+    Edit {
+        id: String,
+        /// The text shown in the menu
+        #[clap(long)]
+        name: Option<String>,
+        /// An icon file, or `file.dll,index`, empty to remove it
+        #[clap(long)]
+        icon: Option<String>,
+        #[clap(long)]
+        command: Option<String>,
+        #[clap(long, value_enum)]
+        position: Option<Position>,
+        /// Remove the position
+        #[clap(long, conflicts_with = "position")]
+        no_position: bool,
+        /// Only show it with Shift held down
+        #[clap(long)]
+        extended: Option<bool>,
+        #[clap(long)]
+        separator_before: Option<bool>,
+        #[clap(long)]
+        separator_after: Option<bool>,
+        /// Never run it on double click
+        #[clap(long)]
+        never_default: Option<bool>,
+        /// Show the administrator shield
+        #[clap(long)]
+        has_lua_shield: Option<bool>,
+        /// Put the verb back the way it was before wcm first edited it
+        #[clap(long)]
+        revert: bool,
+        /// Apply to offline hive files instead of the live registry
+        #[clap(long)]
+        hive: Vec<String>,
+        /// Print the changes instead of making them
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Delete a verb created by `wcm win10 add`
+    Remove {
+        id: String,
+        /// Apply to offline hive files instead of the live registry
+        #[clap(long)]
+        hive: Vec<String>,
+        /// Print the changes instead of making them
+        #[clap(long)]
+        dry_run: bool,
+    },
 }
 
 #[derive(Subcommand)]
 enum Win11Command {
-    List { scope: Scope },
-    Enable { scope: Scope, id: String },
-    Disable { scope: Scope, id: String },
+    List {
+        scope: Scope,
+        /// Only the items on these menus, such as `file,directory`
+        #[clap(long, value_enum, value_delimiter = ',')]
+        scene: Vec<Scene>,
+    },
+    Enable {
+        scope: Scope,
+        id: String,
+        /// Apply to offline hive files instead of the live registry
+        #[clap(long)]
+        hive: Vec<String>,
+        /// Print the changes instead of making them
+        #[clap(long)]
+        dry_run: bool,
+    },
+    Disable {
+        scope: Scope,
+        id: String,
+        /// Apply to offline hive files instead of the live registry
+        #[clap(long)]
+        hive: Vec<String>,
+        /// Print the changes instead of making them
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
+fn try_load_hives(specs: &[String]) -> anyhow::Result<HiveBackend> {
+    let mut backend = HiveBackend::new();
+    for spec in specs {
+        let (kind, path) = match spec.split_once('=') {
+            Some((kind, path)) if kind.parse::<HiveKind>().is_ok() => (kind.parse().ok(), path),
+            _ => (None, spec.as_str()),
+        };
+        backend
+            .load(Path::new(path), kind)
+            .map_err(|e| anyhow::anyhow!("{path}: {e}"))?;
+    }
+    Ok(backend)
+}
+
+/// Where the items of offline hives are backed up, next to the first hive
+/// file rather than in the backup of the live registry.
+fn hive_backup_store(specs: &[String]) -> BackupStore {
+    let spec = specs.first().map(String::as_str).unwrap_or_default();
+    let path = match spec.split_once('=') {
+        Some((kind, path)) if kind.parse::<HiveKind>().is_ok() => path,
+        _ => spec,
+    };
+    BackupStore::new(format!("{path}.wcm-backup.json"))
+}
+
+fn load_hives(specs: &[String]) -> HiveBackend {
+    match try_load_hives(specs) {
+        Ok(backend) => backend,
+        Err(e) => {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn exit_on_error(result: anyhow::Result<()>) {
+    if let Err(e) = result {
+        eprintln!("{e:#}");
+        std::process::exit(1);
+    }
+}
+
+/// Runs `f` against offline hive files and writes the changes back. `f`
+/// backs items up in a staged copy of [`hive_backup_store`], which only
+/// replaces it once the hives are saved.
+fn with_hives(specs: &[String], f: impl FnOnce(&HiveBackend, &BackupStore) -> anyhow::Result<()>) {
+    let backend = load_hives(specs);
+    let store = hive_backup_store(specs);
+    exit_on_error(store.stage().and_then(|staged| {
+        let result = f(&backend, &staged).and_then(|_| Ok(backend.save()?));
+        store.unstage(staged, result.is_ok())?;
+        result
+    }));
+}
+
+fn confirm(prompt: &str) -> bool {
+    print!("{prompt} [y/N] ");
+    let _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
+}
+
+/// Prints the changes a dry run would make.
+fn print_plan(ops: anyhow::Result<Vec<RegOp>>) -> anyhow::Result<()> {
+    let ops = ops?;
+    if ops.is_empty() {
+        println!("Nothing to change.");
+    }
+    for op in &ops {
+        println!("{op}");
+    }
+    Ok(())
+}
+
+/// Applies a .reg file, recording the change in `journal` if there is one.
+fn import(
+    backend: &dyn RegistryBackend,
+    path: &Path,
+    yes: bool,
+    force: bool,
+    dry_run: bool,
+    journal: Option<&Journal>,
+) -> anyhow::Result<()> {
+    let file = RegFile::open(path)?;
+    let ops = file.plan(backend);
+    if ops.is_empty() {
+        println!("Nothing to change.");
+        return Ok(());
+    }
+    for op in &ops {
+        let note = if op.is_safe() {
+            ""
+        } else {
+            "  (outside the context menu locations)"
+        };
+        println!("{op}{note}");
+    }
+    if dry_run {
+        return Ok(());
+    }
+    if !force && ops.iter().any(|op| !op.is_safe()) {
+        anyhow::bail!("refusing to import, pass --force to change these keys anyway");
+    }
+    if yes || confirm("Apply these changes?") {
+        let apply = || apply_ops(backend, &ops, force);
+        match journal {
+            Some(journal) => {
+                let action = format!("import {}", path.display());
+                journal.record(backend, &action, &ops, apply)?
+            }
+            None => apply()?,
+        }
+    }
+    Ok(())
+}
+
+/// Reads the keys a `wcm diff` argument names. `live` reads the keys in
+/// `other` from `backend`.
+fn diff_source(
+    backend: &dyn RegistryBackend,
+    spec: &str,
+    other: &[RegItem],
+) -> anyhow::Result<Vec<RegItem>> {
+    if spec.eq_ignore_ascii_case("live") {
+        return Ok(other
+            .iter()
+            .filter_map(|i| RegItem::from_path_in(backend, i.root, &i.path).ok())
+            .collect());
+    }
+    let path = Path::new(spec);
+    if path.is_file() {
+        if path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+        {
+            let s = std::fs::read_to_string(path)?;
+            let items = Backup::from_json(&s, 0)?.items();
+            return Ok(items.into_iter().filter_map(|i| i.info?.reg).collect());
+        }
+        return RegFile::open(path).map(|file| file.to_items());
+    }
+    let (root, key) = spec.split_once('\\').unwrap_or((spec, ""));
+    let Some(root) = SceneRoot::from_hive_name(root) else {
+        anyhow::bail!("{spec}: not a file or registry key");
+    };
+    Ok(vec![RegItem::from_path_in(backend, root, key)?])
+}
+
+fn diff(backend: &dyn RegistryBackend, old: &str, new: &str) -> anyhow::Result<()> {
+    let old_is_live = old.eq_ignore_ascii_case("live");
+    let mut old_items = vec![];
+    if !old_is_live {
+        old_items = diff_source(backend, old, &[])?;
+    }
+    let new_items = diff_source(backend, new, &old_items)?;
+    if old_is_live {
+        old_items = diff_source(backend, old, &new_items)?;
+    }
+
+    let changes = diff_items(&old_items, &new_items);
+    if changes.is_empty() {
+        println!("No differences.");
+    }
+    for change in changes {
+        println!("{change}");
+    }
+    Ok(())
+}
+
+fn snapshot_store() -> anyhow::Result<SnapshotStore> {
+    SnapshotStore::open_default().ok_or_else(|| anyhow::anyhow!("no config directory"))
+}
+
+fn create_snapshot(backend: &dyn RegistryBackend) -> anyhow::Result<()> {
+    let mut snapshot = Snapshot::capture(backend)?;
+    snapshot_store()?.save(&mut snapshot)?;
+    println!("{}", snapshot.id);
+    Ok(())
+}
+
+fn restore_snapshot(
+    backend: &dyn RegistryBackend,
+    id: &str,
+    yes: bool,
+    dry_run: bool,
+    journal: Option<&Journal>,
+) -> anyhow::Result<()> {
+    let snapshot = snapshot_store()?.load(id)?;
+    let ops = snapshot.plan_restore(backend)?;
+    if ops.is_empty() {
+        println!("Nothing to change.");
+        return Ok(());
+    }
+    for op in &ops {
+        println!("{op}");
+    }
+    if dry_run {
+        return Ok(());
+    }
+    if yes || confirm("Apply these changes?") {
+        // the snapshot holds keys outside the scene roots, like `Blocked`
+        let apply = || apply_ops(backend, &ops, true);
+        match journal {
+            Some(journal) => {
+                let action = format!("snapshot restore {id}");
+                journal.record(backend, &action, &ops, apply)?
+            }
+            None => apply()?,
+        }
+    }
+    Ok(())
+}
+
+fn journal() -> anyhow::Result<Journal> {
+    Journal::open_default().ok_or_else(|| anyhow::anyhow!("no config directory"))
+}
+
+fn undo(
+    backend: &dyn RegistryBackend,
+    count: usize,
+    yes: bool,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let journal = journal()?;
+    let undoable = journal.undoable()?;
+    let entries = &undoable[undoable.len().saturating_sub(count)..];
+    if entries.is_empty() {
+        println!("Nothing to undo.");
+        return Ok(());
+    }
+    for entry in entries.iter().rev() {
+        println!("{entry}");
+    }
+    let ops = plan_undo(backend, entries)?;
+    for op in &ops {
+        println!("{op}");
+    }
+    if dry_run {
+        return Ok(());
+    }
+    if yes || confirm("Undo these changes?") {
+        journal.undo(backend, entries)?;
+    }
+    Ok(())
+}
+
+fn history() -> anyhow::Result<()> {
+    let entries = journal()?.entries()?;
+    let undone: Vec<u64> = entries.iter().flat_map(|e| e.undoes.clone()).collect();
+    for entry in entries {
+        let note = if undone.contains(&entry.seq) {
+            " (undone)"
+        } else {
+            ""
+        };
+        println!("{entry}{note}");
+    }
+    Ok(())
+}
+
+/// Exit code of `wcm check` when something drifted.
+const CHECK_DRIFTED: i32 = 1;
+/// Exit code of `wcm check` when the check couldn't be made.
+const CHECK_ERROR: i32 = 2;
+
+/// The items that drifted from `against`, a profile or snapshot given by
+/// file, name or id.
+fn check(backend: &dyn RegistryBackend, against: &str) -> anyhow::Result<Vec<Drift>> {
+    let backup = backup_store()?.load()?.items();
+    let path = Path::new(against);
+    if path.is_file() {
+        let s = std::fs::read_to_string(path)?;
+        if let Ok(profile) = serde_json::from_str::<Profile>(&s) {
+            return Ok(drift_from_profile(backend, &backup, &profile));
+        }
+        let snapshot: Snapshot = serde_json::from_str(&s)
+            .map_err(|_| anyhow::anyhow!("{against}: not a profile or snapshot"))?;
+        return drift_from_snapshot(backend, &backup, &snapshot);
+    }
+    if let Ok(profile) = profile_store()?.load(against) {
+        return Ok(drift_from_profile(backend, &backup, &profile));
+    }
+    match snapshot_store()?.load(against) {
+        Ok(snapshot) => drift_from_snapshot(backend, &backup, &snapshot),
+        Err(_) => anyhow::bail!("{against}: no such profile or snapshot"),
+    }
+}
+
+fn style_name(style: Type) -> &'static str {
+    match style {
+        Type::Win10 => "win10",
+        Type::Win11 => "win11",
+    }
+}
+
+/// Prints what it takes to reach the desired state in `file`, with Win10
+/// items backed up in `store`.
+fn plan_state(
+    backend: &dyn RegistryBackend,
+    store: &BackupStore,
+    file: &Path,
+) -> anyhow::Result<StatePlan> {
+    let state = DesiredState::open(file)?;
+    let backup = store.load()?;
+    let plan = state.plan_in(backend, &backup.items())?;
+    for n in &plan.unmatched {
+        println!("rule {n}: matches no item");
+    }
+    if plan.is_empty() {
+        println!("Nothing to change.");
+        return Ok(plan);
+    }
+    for item in &plan.items {
+        let verb = if item.enabled { "enable" } else { "disable" };
+        println!("{verb} {item}");
+    }
+    if let Some(style) = plan.menu_style {
+        println!("switch {}", style_name(style));
+    }
+    for op in plan.ops(backend, &backup)? {
+        println!("{op}");
+    }
+    Ok(plan)
+}
+
+/// Brings `backend` to the desired state in `file`, recording the change in
+/// `journal` if there is one.
+fn apply_state(
+    backend: &dyn RegistryBackend,
+    store: &BackupStore,
+    file: &Path,
+    yes: bool,
+    dry_run: bool,
+    journal: Option<&Journal>,
+) -> anyhow::Result<()> {
+    let plan = plan_state(backend, store, file)?;
+    if plan.is_empty() || dry_run {
+        return Ok(());
+    }
+    // only the live registry has a journal, hive files need no rights
+    if journal.is_some()
+        && plan
+            .items
+            .iter()
+            .any(|i| i.ty == Type::Win11 && i.scope == Some(Scope::Machine))
+        && !is_admin::is_admin()
+    {
+        anyhow::bail!("You must run this command as an administrator.");
+    }
+    if yes || confirm("Apply these changes?") {
+        let apply = || plan.commit(backend, store);
+        match journal {
+            Some(journal) => {
+                let ops = plan.ops(backend, &store.load()?)?;
+                let action = format!("apply {}", file.display());
+                journal.record(backend, &action, &ops, apply)?
+            }
+            None => apply()?,
+        }
+    }
+    Ok(())
+}
+
+fn profile_store() -> anyhow::Result<ProfileStore> {
+    ProfileStore::open_default().ok_or_else(|| anyhow::anyhow!("no config directory"))
+}
+
+fn profile_save(
+    backend: &dyn RegistryBackend,
+    store: &BackupStore,
+    name: &str,
+) -> anyhow::Result<()> {
+    let backup = store.load()?.items();
+    let profile = Profile::capture_in(backend, name, &backup, unix_now());
+    profile_store()?.save(&profile)?;
+    println!("Saved {} items.", profile.items.len());
+    Ok(())
+}
+
+/// Applies a profile, backing up the Win10 items it disables in `store` and
+/// recording the change in `journal` if there is one.
+fn profile_apply(
+    backend: &dyn RegistryBackend,
+    store: &BackupStore,
+    name: &str,
+    yes: bool,
+    dry_run: bool,
+    journal: Option<&Journal>,
+) -> anyhow::Result<()> {
+    let profile = profile_store()?.load(name)?;
+    let backup = store.load()?;
+    let current = Profile::capture_in(backend, name, &backup.items(), unix_now());
+    let plan = profile.plan(&current);
+    for item in &plan.missing {
+        println!("{item}: not installed here");
+    }
+    for item in &plan.unmentioned {
+        println!("{item}: not in the profile, left as is");
+    }
+    let ops = plan.transaction.plan_backed_up(backend, &backup)?.concat();
+    if ops.is_empty() {
+        println!("Nothing to change.");
+        return Ok(());
+    }
+    for op in &ops {
+        println!("{op}");
+    }
+    if dry_run {
+        return Ok(());
+    }
+    if yes || confirm("Apply these changes?") {
+        let apply = || {
+            plan.transaction
+                .commit_backed_up(backend, store)?
+                .into_result()
+        };
+        match journal {
+            Some(journal) => {
+                let action = format!("profile apply {name}");
+                journal.record(backend, &action, &ops, apply)?
+            }
+            None => apply()?,
+        }
+    }
+    Ok(())
+}
+
+fn profile_list() -> anyhow::Result<()> {
+    for profile in profile_store()?.list()? {
+        let enabled = profile.items.iter().filter(|i| i.enabled).count();
+        println!(
+            "{} {} items, {enabled} enabled, saved {}",
+            profile.name,
+            profile.items.len(),
+            timestamp(profile.created)
+        );
+    }
+    Ok(())
+}
+
+fn backup_store() -> anyhow::Result<BackupStore> {
+    BackupStore::open_default().ok_or_else(|| anyhow::anyhow!("no config directory"))
+}
+
+fn backup_list(backend: &dyn RegistryBackend) -> anyhow::Result<()> {
+    let present = Type::Win10.list_in(backend, None);
+    for entry in backup_store()?.load()?.entries {
+        let icon = if present.iter().any(|i| entry.matches(i)) {
+            "✅"
+        } else {
+            "❌"
+        };
+        println!(
+            "{icon} {} {} (first seen {}, last seen {})",
+            entry.item.id,
+            entry.item.name,
+            timestamp(entry.first_seen),
+            timestamp(entry.last_seen)
+        );
+    }
+    Ok(())
+}
+
+fn backup_show(id: &str) -> anyhow::Result<()> {
+    let backup = backup_store()?.load()?;
+    let Some(entry) = backup.find(id) else {
+        anyhow::bail!("{id}: not found in the backup");
+    };
+    let current = entry.revisions.len() + 1;
+    for (n, (seen, item)) in (1..).zip(entry.revisions()) {
+        let note = if n == current { ", current" } else { "" };
+        println!("; revision {n}, first seen {}{note}", timestamp(seen));
+        let info = item.info.as_ref();
+        match info.and_then(|i| i.reg.as_ref()) {
+            Some(reg) => println!("{}", reg.to_reg_txt()),
+            None => println!(
+                "{}",
+                info.and_then(|i| i.reg_txt.as_deref()).unwrap_or_default()
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn backup_export(file: &Path, ids: &[String]) -> anyhow::Result<()> {
+    let backup = backup_store()?.load()?;
+    if let Some(id) = ids.iter().find(|id| backup.find(id).is_none()) {
+        anyhow::bail!("{id}: not found in the backup");
+    }
+    let exported = backup.export(ids);
+    BackupStore::new(file).save(&exported)?;
+    println!("Exported {} items.", exported.entries.len());
+    Ok(())
+}
+
+fn backup_import(file: &Path) -> anyhow::Result<()> {
+    if !file.is_file() {
+        anyhow::bail!("{}: not found", file.display());
+    }
+    let other = BackupStore::new(file).load()?;
+    let added = backup_store()?.update(|backup| backup.import(&other))?;
+    println!("Added {added} items.");
+    Ok(())
+}
+
+fn backup_prune(
+    backend: &dyn RegistryBackend,
+    days: u64,
+    yes: bool,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let store = backup_store()?;
+    let before = unix_now().saturating_sub(days * 24 * 60 * 60);
+    let present = Type::Win10.list_in(backend, None);
+    let pruned = store.load()?.prune(before, &present);
+    if pruned.is_empty() {
+        println!("Nothing to prune.");
+        return Ok(());
+    }
+    for entry in &pruned {
+        println!(
+            "{} {} (last seen {})",
+            entry.item.id,
+            entry.item.name,
+            timestamp(entry.last_seen)
+        );
+    }
+    if dry_run {
+        return Ok(());
+    }
+    if yes || confirm("Remove these items from the backup?") {
+        store.update(|backup| backup.prune(before, &present))?;
+    }
+    Ok(())
+}
+
+fn backup_verify() -> anyhow::Result<()> {
+    let problems = backup_store()?.verify()?;
+    for problem in &problems {
+        println!("{problem}");
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("{} problems found", problems.len());
+    }
+    println!("No problems found.");
+    Ok(())
 }
 
 fn main() {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Switch { r#type: ty } => {
-            match ty {
-                None => {
-                    println!(
-                        "{}",
-                        if get_context_menu_style() {
-                            "win11"
-                        } else {
-                            "win10"
-                        }
-                    )
-                }
-                Some(Type::Win10) => {
-                    let _ = set_context_menu_style(false);
-                }
-                Some(Type::Win11) => {
-                    let _ = set_context_menu_style(true);
+        Commands::Switch {
+            r#type: ty,
+            dry_run,
+        } => match ty {
+            None => {
+                println!(
+                    "{}",
+                    if get_context_menu_style() {
+                        "win11"
+                    } else {
+                        "win10"
+                    }
+                )
+            }
+            Some(ty) => {
+                let is_win11_style = ty == Type::Win11;
+                if dry_run {
+                    exit_on_error(print_plan(Ok(plan_context_menu_style_in(
+                        &WinRegBackend,
+                        is_win11_style,
+                    ))));
+                } else {
+                    exit_on_error(set_context_menu_style(is_win11_style).map_err(Into::into));
                 }
             }
-            return;
-        }
+        },
         Commands::Win10 { command } => match command {
-            Win10Command::List => {
-                let v = Type::Win10.list(None);
-                for i in v {
+            Win10Command::List { hive, scene } => {
+                let v = if hive.is_empty() {
+                    Type::Win10.list(None, &scene)
+                } else {
+                    let reg = load_hives(&hive);
+                    let v = Type::list_backed_up_in(&reg, &hive_backup_store(&hive))
+                        .unwrap_or_else(|e| {
+                            eprintln!("{e:#}");
+                            std::process::exit(1);
+                        });
+                    v.into_iter().filter(|i| i.on_scene(&scene)).collect()
+                };
+                for i in &v {
                     let icon = if i.enabled { "✅" } else { "❌" };
-                    println!("{icon} {} {}", i.id, i.name);
+                    // ids held by more than one root, like Edge policies,
+                    // are shown with the root that picks this one
+                    let shared = v.iter().any(|o| o.id == i.id && o.root() != i.root());
+                    match i.root() {
+                        Some(root) if shared => println!(r"{icon} {root}\{} {}", i.id, i.name),
+                        _ => println!("{icon} {} {}", i.id, i.name),
+                    }
                 }
             }
-            Win10Command::Enable { id } => {
-                let _ = Type::Win10.enable(&id, None);
+            Win10Command::Enable {
+                id,
+                hive,
+                dry_run,
+                revision: Some(revision),
+            } => match (hive.is_empty(), dry_run) {
+                (true, true) => exit_on_error(backup_store().and_then(|store| {
+                    print_plan(Type::plan_enable_revision_in(
+                        &WinRegBackend,
+                        &store,
+                        &id,
+                        revision,
+                    ))
+                })),
+                (true, false) => exit_on_error(
+                    backup_store().and_then(|store| Type::enable_revision(&store, &id, revision)),
+                ),
+                (false, true) => exit_on_error(print_plan(Type::plan_enable_revision_in(
+                    &load_hives(&hive),
+                    &hive_backup_store(&hive),
+                    &id,
+                    revision,
+                ))),
+                (false, false) => with_hives(&hive, |reg, store| {
+                    Type::enable_revision_in(reg, store, &id, revision)
+                }),
+            },
+            Win10Command::Enable {
+                id,
+                hive,
+                dry_run,
+                revision: None,
+            } => match (hive.is_empty(), dry_run) {
+                (true, true) => exit_on_error(print_plan(Type::Win10.plan_enable(&id, None))),
+                (true, false) => exit_on_error(Type::Win10.enable(&id, None)),
+                (false, true) => exit_on_error(print_plan(Type::Win10.plan_enable_in(
+                    &load_hives(&hive),
+                    &hive_backup_store(&hive),
+                    &id,
+                    None,
+                ))),
+                (false, false) => with_hives(&hive, |reg, store| {
+                    Type::Win10.enable_in(reg, store, &id, None)
+                }),
+            },
+            Win10Command::Disable { id, hive, dry_run } => match (hive.is_empty(), dry_run) {
+                (true, true) => exit_on_error(print_plan(Type::Win10.plan_disable(&id, None))),
+                (true, false) => exit_on_error(Type::Win10.disable(&id, None)),
+                (false, true) => exit_on_error(print_plan(Type::Win10.plan_disable_in(
+                    &load_hives(&hive),
+                    &hive_backup_store(&hive),
+                    &id,
+                    None,
+                ))),
+                (false, false) => with_hives(&hive, |reg, store| {
+                    Type::Win10.disable_in(reg, store, &id, None)
+                }),
+            },
+            Win10Command::Add {
+                scene,
+                name,
+                command,
+                icon,
+                extended,
+                position,
+                hive,
+                dry_run,
+            } => {
+                let verb = NewVerb {
+                    icon,
+                    extended,
+                    position,
+                    ..NewVerb::new(scene, &name, &command)
+                };
+                match (hive.is_empty(), dry_run) {
+                    (true, true) => exit_on_error(print_plan(verb.plan_in(&WinRegBackend))),
+                    (true, false) => exit_on_error(backup_store().and_then(|store| {
+                        let item = Type::add(&store, &verb)?;
+                        println!("Added {}", item.id);
+                        Ok(())
+                    })),
+                    (false, true) => exit_on_error(print_plan(verb.plan_in(&load_hives(&hive)))),
+                    (false, false) => with_hives(&hive, |reg, store| {
+                        Type::add_in(reg, store, &verb).map(|_| ())
+                    }),
+                }
             }
-            Win10Command::Disable { id } => {
-                let _ = Type::Win10.disable(&id, None);
+            Win10Command::Edit {
+                id,
+                hive,
+                dry_run,
+                revert: true,
+                ..
+            } => match (hive.is_empty(), dry_run) {
+                (true, true) => exit_on_error(backup_store().and_then(|store| {
+                    print_plan(Type::plan_revert_in(&WinRegBackend, &store, &id))
+                })),
+                (true, false) => {
+                    exit_on_error(backup_store().and_then(|store| Type::revert(&store, &id)))
+                }
+                (false, true) => exit_on_error(print_plan(Type::plan_revert_in(
+                    &load_hives(&hive),
+                    &hive_backup_store(&hive),
+                    &id,
+                ))),
+                (false, false) => with_hives(&hive, |reg, store| Type::revert_in(reg, store, &id)),
+            },
+            Win10Command::Edit {
+                id,
+                name,
+                icon,
+                command,
+                position,
+                no_position,
+                extended,
+                separator_before,
+                separator_after,
+                never_default,
+                has_lua_shield,
+                revert: false,
+                hive,
+                dry_run,
+            } => {
+                let edit = VerbEdit {
+                    name,
+                    icon,
+                    command,
+                    position: if no_position {
+                        Some(None)
+                    } else {
+                        position.map(Some)
+                    },
+                    extended,
+                    separator_before,
+                    separator_after,
+                    never_default,
+                    has_lua_shield,
+                };
+                if edit.is_empty() {
+                    eprintln!("Nothing to change, give at least one value.");
+                    std::process::exit(1);
+                }
+                match (hive.is_empty(), dry_run) {
+                    (true, true) => {
+                        exit_on_error(print_plan(Type::plan_edit_in(&WinRegBackend, &id, &edit)))
+                    }
+                    (true, false) => exit_on_error(
+                        backup_store().and_then(|store| Type::edit(&store, &id, &edit).map(|_| ())),
+                    ),
+                    (false, true) => exit_on_error(print_plan(Type::plan_edit_in(
+                        &load_hives(&hive),
+                        &id,
+                        &edit,
+                    ))),
+                    (false, false) => with_hives(&hive, |reg, store| {
+                        Type::edit_in(reg, store, &id, &edit).map(|_| ())
+                    }),
+                }
             }
+            Win10Command::Remove { id, hive, dry_run } => match (hive.is_empty(), dry_run) {
+                (true, true) => exit_on_error(backup_store().and_then(|store| {
+                    print_plan(Type::plan_remove_in(&WinRegBackend, &store, &id))
+                })),
+                (true, false) => {
+                    exit_on_error(backup_store().and_then(|store| Type::remove(&store, &id)))
+                }
+                (false, true) => exit_on_error(print_plan(Type::plan_remove_in(
+                    &load_hives(&hive),
+                    &hive_backup_store(&hive),
+                    &id,
+                ))),
+                (false, false) => with_hives(&hive, |reg, store| Type::remove_in(reg, store, &id)),
+            },
         },
         Commands::Win11 { command } => match command {
-            Win11Command::Enable { scope, id } => {
+            Win11Command::Enable {
+                scope,
+                id,
+                hive,
+                dry_run,
+            } => {
+                if !hive.is_empty() {
+                    if dry_run {
+                        let reg = load_hives(&hive);
+                        exit_on_error(print_plan(Type::Win11.plan_enable_in(
+                            &reg,
+                            &hive_backup_store(&hive),
+                            &id,
+                            Some(scope),
+                        )));
+                    } else {
+                        with_hives(&hive, |reg, store| {
+                            Type::Win11.enable_in(reg, store, &id, Some(scope))
+                        });
+                    }
+                    return;
+                }
+                if dry_run {
+                    exit_on_error(print_plan(Type::Win11.plan_enable(&id, Some(scope))));
+                    return;
+                }
                 if scope == Scope::Machine && !is_admin::is_admin() {
                     panic!("You must run this command as an administrator.");
                 }
-                let _ = Type::Win11.enable(&id, Some(scope));
+                exit_on_error(Type::Win11.enable(&id, Some(scope)));
             }
-            Win11Command::Disable { scope, id } => {
+            Win11Command::Disable {
+                scope,
+                id,
+                hive,
+                dry_run,
+            } => {
+                if !hive.is_empty() {
+                    if dry_run {
+                        let reg = load_hives(&hive);
+                        exit_on_error(print_plan(Type::Win11.plan_disable_in(
+                            &reg,
+                            &hive_backup_store(&hive),
+                            &id,
+                            Some(scope),
+                        )));
+                    } else {
+                        with_hives(&hive, |reg, store| {
+                            Type::Win11.disable_in(reg, store, &id, Some(scope))
+                        });
+                    }
+                    return;
+                }
+                if dry_run {
+                    exit_on_error(print_plan(Type::Win11.plan_disable(&id, Some(scope))));
+                    return;
+                }
                 if scope == Scope::Machine && !is_admin::is_admin() {
                     panic!("You must run this command as an administrator.");
                 }
-                let _ = Type::Win11.disable(&id, Some(scope));
+                exit_on_error(Type::Win11.disable(&id, Some(scope)));
             }
-            Win11Command::List { scope } => {
+            Win11Command::List { scope, scene } => {
                 if scope == Scope::Machine && !is_admin::is_admin() {
                     panic!("You must run this command as an administrator.");
                 }
-                let v = Type::Win11.list(Some(scope));
+                let v = Type::Win11.list(Some(scope), &scene);
                 for i in v {
                     let icon = if i.enabled { "✅" } else { "❌" };
                     println!("{icon} {} {}", i.id, i.name,);
@@ -104,5 +1208,223 @@ fn main() {
             }
         },
         Commands::RestartExplorer => restart_explorer(),
+        Commands::Import {
+            file,
+            yes,
+            force,
+            hive,
+            dry_run,
+        } => {
+            if hive.is_empty() {
+                let journal = Journal::open_default();
+                exit_on_error(import(
+                    &WinRegBackend,
+                    &file,
+                    yes,
+                    force,
+                    dry_run,
+                    journal.as_ref(),
+                ));
+            } else if dry_run {
+                exit_on_error(import(&load_hives(&hive), &file, yes, force, true, None));
+            } else {
+                with_hives(&hive, |reg, _| import(reg, &file, yes, force, false, None));
+            }
+        }
+        Commands::Diff { old, new, hive } => {
+            if hive.is_empty() {
+                exit_on_error(diff(&WinRegBackend, &old, &new));
+            } else {
+                exit_on_error(diff(&load_hives(&hive), &old, &new));
+            }
+        }
+        Commands::Snapshot { command } => match command {
+            SnapshotCommand::Create { hive } => {
+                if hive.is_empty() {
+                    exit_on_error(create_snapshot(&WinRegBackend));
+                } else {
+                    exit_on_error(create_snapshot(&load_hives(&hive)));
+                }
+            }
+            SnapshotCommand::List => exit_on_error(snapshot_store().and_then(|store| {
+                for snapshot in store.list()? {
+                    let keys = snapshot.keys.iter().filter(|k| k.item.is_some()).count();
+                    println!("{} {keys} keys", snapshot.id);
+                }
+                Ok(())
+            })),
+            SnapshotCommand::Restore {
+                id,
+                yes,
+                hive,
+                dry_run,
+            } => {
+                if hive.is_empty() {
+                    let journal = Journal::open_default();
+                    exit_on_error(restore_snapshot(
+                        &WinRegBackend,
+                        &id,
+                        yes,
+                        dry_run,
+                        journal.as_ref(),
+                    ));
+                } else if dry_run {
+                    exit_on_error(restore_snapshot(&load_hives(&hive), &id, yes, true, None));
+                } else {
+                    with_hives(&hive, |reg, _| restore_snapshot(reg, &id, yes, false, None));
+                }
+            }
+            SnapshotCommand::Delete { id } => {
+                exit_on_error(snapshot_store().and_then(|store| Ok(store.delete(&id)?)))
+            }
+        },
+        Commands::Undo {
+            count,
+            yes,
+            dry_run,
+        } => exit_on_error(undo(&WinRegBackend, count, yes, dry_run)),
+        Commands::History => exit_on_error(history()),
+        Commands::Backup { command } => match command {
+            BackupCommand::List { hive } => {
+                if hive.is_empty() {
+                    exit_on_error(backup_list(&WinRegBackend));
+                } else {
+                    exit_on_error(backup_list(&load_hives(&hive)));
+                }
+            }
+            BackupCommand::Show { id } => exit_on_error(backup_show(&id)),
+            BackupCommand::Export { file, ids } => exit_on_error(backup_export(&file, &ids)),
+            BackupCommand::Import { file } => exit_on_error(backup_import(&file)),
+            BackupCommand::Prune {
+                days,
+                yes,
+                dry_run,
+                hive,
+            } => {
+                if hive.is_empty() {
+                    exit_on_error(backup_prune(&WinRegBackend, days, yes, dry_run));
+                } else {
+                    exit_on_error(backup_prune(&load_hives(&hive), days, yes, dry_run));
+                }
+            }
+            BackupCommand::Verify => exit_on_error(backup_verify()),
+        },
+        Commands::Check { against, hive } => {
+            let drift = if hive.is_empty() {
+                check(&WinRegBackend, &against)
+            } else {
+                try_load_hives(&hive).and_then(|reg| check(&reg, &against))
+            };
+            match drift {
+                Ok(drift) if drift.is_empty() => println!("In sync."),
+                Ok(drift) => {
+                    for d in drift {
+                        println!("{d}");
+                    }
+                    std::process::exit(CHECK_DRIFTED);
+                }
+                Err(e) => {
+                    eprintln!("{e:#}");
+                    std::process::exit(CHECK_ERROR);
+                }
+            }
+        }
+        Commands::Plan { file, hive } => {
+            if hive.is_empty() {
+                exit_on_error(
+                    backup_store()
+                        .and_then(|store| plan_state(&WinRegBackend, &store, &file))
+                        .map(|_| ()),
+                );
+            } else {
+                let store = hive_backup_store(&hive);
+                exit_on_error(plan_state(&load_hives(&hive), &store, &file).map(|_| ()));
+            }
+        }
+        Commands::Apply {
+            file,
+            yes,
+            hive,
+            dry_run,
+        } => {
+            if hive.is_empty() {
+                let journal = Journal::open_default();
+                exit_on_error(backup_store().and_then(|store| {
+                    apply_state(
+                        &WinRegBackend,
+                        &store,
+                        &file,
+                        yes,
+                        dry_run,
+                        journal.as_ref(),
+                    )
+                }));
+            } else if dry_run {
+                let store = hive_backup_store(&hive);
+                exit_on_error(apply_state(
+                    &load_hives(&hive),
+                    &store,
+                    &file,
+                    yes,
+                    true,
+                    None,
+                ));
+            } else {
+                with_hives(&hive, |reg, store| {
+                    apply_state(reg, store, &file, yes, false, None)
+                });
+            }
+        }
+        Commands::Profile { command } => match command {
+            ProfileCommand::Save { name, hive } => {
+                if hive.is_empty() {
+                    exit_on_error(
+                        backup_store()
+                            .and_then(|store| profile_save(&WinRegBackend, &store, &name)),
+                    );
+                } else {
+                    let store = hive_backup_store(&hive);
+                    exit_on_error(profile_save(&load_hives(&hive), &store, &name));
+                }
+            }
+            ProfileCommand::Apply {
+                name,
+                yes,
+                hive,
+                dry_run,
+            } => {
+                if hive.is_empty() {
+                    let journal = Journal::open_default();
+                    exit_on_error(backup_store().and_then(|store| {
+                        profile_apply(
+                            &WinRegBackend,
+                            &store,
+                            &name,
+                            yes,
+                            dry_run,
+                            journal.as_ref(),
+                        )
+                    }));
+                } else if dry_run {
+                    let store = hive_backup_store(&hive);
+                    exit_on_error(profile_apply(
+                        &load_hives(&hive),
+                        &store,
+                        &name,
+                        yes,
+                        true,
+                        None,
+                    ));
+                } else {
+                    with_hives(&hive, |reg, store| {
+                        profile_apply(reg, store, &name, yes, false, None)
+                    });
+                }
+            }
+            ProfileCommand::List => exit_on_error(profile_list()),
+            ProfileCommand::Delete { name } => {
+                exit_on_error(profile_store().and_then(|store| Ok(store.delete(&name)?)))
+            }
+        },
     }
 }
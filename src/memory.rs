@@ -0,0 +1,245 @@
+use crate::RawValue;
+use crate::RegistryBackend;
+use crate::SceneRoot;
+use crate::backend::filetime_now;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::io;
+
+#[derive(Debug, Clone, Default)]
+struct MemoryKey {
+    name: String,
+    values: Vec<(String, RawValue)>,
+    /// Keyed by the lowercased subkey name.
+    children: BTreeMap<String, MemoryKey>,
+    last_write_time: u64,
+}
+
+impl MemoryKey {
+    fn find(&self, path: &str) -> Option<&MemoryKey> {
+        split_path(path).try_fold(self, |key, name| key.children.get(&name.to_lowercase()))
+    }
+
+    fn find_mut(&mut self, path: &str) -> Option<&mut MemoryKey> {
+        split_path(path).try_fold(self, |key, name| key.children.get_mut(&name.to_lowercase()))
+    }
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('\\').filter(|s| !s.is_empty())
+}
+
+fn not_found(root: SceneRoot, path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{root}\\{path}"))
+}
+
+/// A registry that only lives in memory, for tests and offline tooling.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    roots: RefCell<HashMap<SceneRoot, MemoryKey>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_key<T>(
+        &self,
+        root: SceneRoot,
+        path: &str,
+        f: impl FnOnce(&MemoryKey) -> T,
+    ) -> io::Result<T> {
+        let roots = self.roots.borrow();
+        let key = roots
+            .get(&root)
+            .and_then(|r| r.find(path))
+            .ok_or_else(|| not_found(root, path))?;
+        Ok(f(key))
+    }
+
+    fn with_key_mut<T>(
+        &self,
+        root: SceneRoot,
+        path: &str,
+        f: impl FnOnce(&mut MemoryKey) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut roots = self.roots.borrow_mut();
+        let key = roots
+            .entry(root)
+            .or_default()
+            .find_mut(path)
+            .ok_or_else(|| not_found(root, path))?;
+        let out = f(key)?;
+        key.last_write_time = filetime_now();
+        Ok(out)
+    }
+}
+
+impl RegistryBackend for MemoryBackend {
+    fn enum_keys(&self, root: SceneRoot, path: &str) -> io::Result<Vec<String>> {
+        self.with_key(root, path, |key| {
+            key.children.values().map(|c| c.name.clone()).collect()
+        })
+    }
+
+    fn enum_values(&self, root: SceneRoot, path: &str) -> io::Result<Vec<(String, RawValue)>> {
+        self.with_key(root, path, |key| key.values.clone())
+    }
+
+    fn create_key(&self, root: SceneRoot, path: &str) -> io::Result<()> {
+        let mut roots = self.roots.borrow_mut();
+        let mut key = roots.entry(root).or_default();
+        for name in split_path(path) {
+            key = key
+                .children
+                .entry(name.to_lowercase())
+                .or_insert_with(|| MemoryKey {
+                    name: name.to_string(),
+                    last_write_time: filetime_now(),
+                    ..Default::default()
+                });
+        }
+        Ok(())
+    }
+
+    fn set_value(
+        &self,
+        root: SceneRoot,
+        path: &str,
+        name: &str,
+        value: &RawValue,
+    ) -> io::Result<()> {
+        self.with_key_mut(root, path, |key| {
+            match key
+                .values
+                .iter_mut()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            {
+                Some((_, v)) => *v = value.clone(),
+                None => key.values.push((name.to_string(), value.clone())),
+            }
+            Ok(())
+        })
+    }
+
+    fn delete_value(&self, root: SceneRoot, path: &str, name: &str) -> io::Result<()> {
+        self.with_key_mut(root, path, |key| {
+            let len = key.values.len();
+            key.values.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+            if key.values.len() == len {
+                return Err(not_found(root, &format!("{path}\\{name}")));
+            }
+            Ok(())
+        })
+    }
+
+    fn delete_key(&self, root: SceneRoot, path: &str) -> io::Result<()> {
+        let (parent, name) = path.rsplit_once('\\').unwrap_or(("", path));
+        self.with_key_mut(root, parent, |key| {
+            let lower = name.to_lowercase();
+            match key.children.get(&lower) {
+                None => Err(not_found(root, path)),
+                Some(child) if !child.children.is_empty() => Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{root}\\{path} has subkeys"),
+                )),
+                Some(_) => {
+                    key.children.remove(&lower);
+                    Ok(())
+                }
+            }
+        })
+    }
+
+    fn last_write_time(&self, root: SceneRoot, path: &str) -> io::Result<u64> {
+        self.with_key(root, path, |key| key.last_write_time)
+    }
+}
+
+#[cfg(test)]
+impl MemoryBackend {
+    /// Registers a classic menu verb at `HKEY_CLASSES_ROOT\path`.
+    pub(crate) fn add_verb(&self, path: &str, title: &str, command: &str) {
+        use crate::{RegItem, RegItemValue};
+        let mut item = RegItem::new(SceneRoot::HKCR, path);
+        item.set_value("", RegItemValue::SZ(title.to_string()));
+        let mut child = RegItem::new(SceneRoot::HKCR, &format!(r"{path}\command"));
+        child.set_value("", RegItemValue::SZ(command.to_string()));
+        item.children.push(child);
+        item.write_in(self).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MemoryBackend;
+    use crate::{RegItem, RegItemValue, RegistryBackend, SceneRoot};
+
+    #[test]
+    fn test_keys_are_case_insensitive() {
+        let reg = MemoryBackend::new();
+        reg.create_key(SceneRoot::HKCR, r"Directory\Background\shell\Git")
+            .unwrap();
+        assert!(reg.key_exists(SceneRoot::HKCR, r"directory\background\SHELL\git"));
+        assert!(!reg.key_exists(SceneRoot::HKCU, r"Directory\Background"));
+        assert_eq!(
+            reg.enum_keys(SceneRoot::HKCR, r"Directory\Background\Shell")
+                .unwrap(),
+            vec!["Git".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_delete_key_with_subkeys_fails() {
+        let reg = MemoryBackend::new();
+        reg.create_key(SceneRoot::HKCR, r"*\shell\open\command")
+            .unwrap();
+        assert!(reg.delete_key(SceneRoot::HKCR, r"*\shell\open").is_err());
+        reg.delete_tree(SceneRoot::HKCR, r"*\shell\open").unwrap();
+        assert!(!reg.key_exists(SceneRoot::HKCR, r"*\shell\open"));
+        assert!(reg.key_exists(SceneRoot::HKCR, r"*\shell"));
+    }
+
+    #[test]
+    fn test_reg_item_round_trip() {
+        let reg = MemoryBackend::new();
+        let path = r"Directory\Background\Shell\wt";
+        reg.create_key(SceneRoot::HKCR, &format!(r"{path}\command"))
+            .unwrap();
+        reg.set_value(
+            SceneRoot::HKCR,
+            path,
+            "",
+            &RegItemValue::SZ("Open in Terminal".to_string()).to_raw(),
+        )
+        .unwrap();
+        reg.set_value(
+            SceneRoot::HKCR,
+            &format!(r"{path}\command"),
+            "",
+            &RegItemValue::SZ(r#"wt.exe -d "%V""#.to_string()).to_raw(),
+        )
+        .unwrap();
+
+        let item = RegItem::from_path_in(&reg, SceneRoot::HKCR, path).unwrap();
+        assert_eq!(
+            item.get_value(""),
+            Some(&RegItemValue::SZ("Open in Terminal".to_string()))
+        );
+        assert_eq!(item.children.len(), 1);
+
+        item.delete_in(&reg).unwrap();
+        assert!(!reg.key_exists(SceneRoot::HKCR, path));
+
+        item.write_in(&reg).unwrap();
+        assert_eq!(
+            RegItem::from_path_in(&reg, SceneRoot::HKCR, path).unwrap(),
+            item
+        );
+        let outside = RegItem::new(SceneRoot::HKLM, r"SYSTEM\CurrentControlSet");
+        assert!(outside.write_in(&reg).is_err());
+        assert!(!reg.key_exists(SceneRoot::HKLM, r"SYSTEM\CurrentControlSet"));
+    }
+}
@@ -0,0 +1,337 @@
+use crate::RegFile;
+use crate::RegItem;
+use crate::RegItemValue;
+use crate::RegistryBackend;
+use crate::SceneRoot;
+use crate::backend::strip_path_prefix;
+use crate::is_safe_path;
+use crate::regf::upcase;
+use anyhow::{Context, bail};
+use std::fmt::Display;
+use std::io;
+
+/// A single registry change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegOp {
+    CreateKey {
+        root: SceneRoot,
+        path: String,
+    },
+    SetValue {
+        root: SceneRoot,
+        path: String,
+        name: String,
+        value: RegItemValue,
+    },
+    DeleteValue {
+        root: SceneRoot,
+        path: String,
+        name: String,
+    },
+    /// Removes the key and everything below it.
+    DeleteKey {
+        root: SceneRoot,
+        path: String,
+    },
+}
+
+impl RegOp {
+    pub fn root(&self) -> SceneRoot {
+        match self {
+            RegOp::CreateKey { root, .. }
+            | RegOp::SetValue { root, .. }
+            | RegOp::DeleteValue { root, .. }
+            | RegOp::DeleteKey { root, .. } => *root,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        match self {
+            RegOp::CreateKey { path, .. }
+            | RegOp::SetValue { path, .. }
+            | RegOp::DeleteValue { path, .. }
+            | RegOp::DeleteKey { path, .. } => path,
+        }
+    }
+
+    pub fn is_safe(&self) -> bool {
+        is_safe_path(self.root(), self.path())
+    }
+
+    /// Whether `backend` already looks the way this change would leave it.
+    pub fn is_applied(&self, backend: &dyn RegistryBackend) -> bool {
+        match self {
+            RegOp::CreateKey { root, path } => backend.key_exists(*root, path),
+            RegOp::SetValue {
+                root,
+                path,
+                name,
+                value,
+            } => backend
+                .get_value(*root, path, name)
+                .is_ok_and(|v| v == value.to_raw()),
+            RegOp::DeleteValue { root, path, name } => {
+                backend.get_value(*root, path, name).is_err()
+            }
+            RegOp::DeleteKey { root, path } => !backend.key_exists(*root, path),
+        }
+    }
+
+    pub fn apply(&self, backend: &dyn RegistryBackend) -> io::Result<()> {
+        match self {
+            RegOp::CreateKey { root, path } => backend.create_key(*root, path),
+            RegOp::SetValue {
+                root,
+                path,
+                name,
+                value,
+            } => {
+                backend.create_key(*root, path)?;
+                backend.set_value(*root, path, name, &value.to_raw())
+            }
+            RegOp::DeleteValue { root, path, name } => backend.delete_value(*root, path, name),
+            RegOp::DeleteKey { root, path } => backend.delete_tree(*root, path),
+        }
+    }
+}
+
+/// The full key name as shown in .reg files, like `HKEY_CLASSES_ROOT\*\shell`.
+pub(crate) fn key_name(root: SceneRoot, path: &str) -> String {
+    match path {
+        "" => root.hive_name().to_string(),
+        path => format!("{}\\{path}", root.hive_name()),
+    }
+}
+
+/// A value name as shown in .reg files, `@` for the default value.
+pub(crate) fn value_name(name: &str) -> String {
+    if name.is_empty() {
+        "@".to_string()
+    } else {
+        format!(r#""{name}""#)
+    }
+}
+
+impl Display for RegOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let key = key_name(self.root(), self.path());
+        match self {
+            RegOp::CreateKey { .. } => write!(f, "+ [{key}]"),
+            RegOp::DeleteKey { .. } => write!(f, "- [{key}]"),
+            RegOp::SetValue { name, value, .. } => {
+                write!(f, "~ [{key}] {} = {value}", value_name(name))
+            }
+            RegOp::DeleteValue { name, .. } => write!(f, "- [{key}] {}", value_name(name)),
+        }
+    }
+}
+
+impl RegFile {
+    /// The changes the file describes, in file order.
+    pub fn to_ops(&self) -> Vec<RegOp> {
+        let mut ops = vec![];
+        for section in &self.sections {
+            let (root, path) = (section.root, section.path.clone());
+            if section.delete {
+                ops.push(RegOp::DeleteKey { root, path });
+                continue;
+            }
+            ops.push(RegOp::CreateKey {
+                root,
+                path: path.clone(),
+            });
+            for (name, value) in &section.values {
+                let (path, name) = (path.clone(), name.clone());
+                ops.push(match value {
+                    Some(value) => RegOp::SetValue {
+                        root,
+                        path,
+                        name,
+                        value: value.clone(),
+                    },
+                    None => RegOp::DeleteValue { root, path, name },
+                });
+            }
+        }
+        ops
+    }
+
+    /// The changes importing the file would make to `backend`, leaving out
+    /// the ones already in place.
+    pub fn plan(&self, backend: &dyn RegistryBackend) -> Vec<RegOp> {
+        // changes after a removal can't be checked against the current state
+        let mut removed: Vec<&RegOp> = vec![];
+        let all = self.to_ops();
+        let mut ops = vec![];
+        for op in &all {
+            let after_removal = removed.iter().any(|r| {
+                r.root() == op.root()
+                    && match (r, op) {
+                        (RegOp::DeleteKey { path, .. }, _) => {
+                            strip_path_prefix(op.path(), path).is_some()
+                        }
+                        (
+                            RegOp::DeleteValue { path, name, .. },
+                            RegOp::SetValue {
+                                path: p, name: n, ..
+                            },
+                        ) => path.eq_ignore_ascii_case(p) && name.eq_ignore_ascii_case(n),
+                        _ => false,
+                    }
+            });
+            if after_removal || !op.is_applied(backend) {
+                if matches!(op, RegOp::DeleteKey { .. } | RegOp::DeleteValue { .. }) {
+                    removed.push(op);
+                }
+                ops.push(op.clone());
+            }
+        }
+        ops
+    }
+}
+
+impl RegItem {
+    /// The changes that remove this key one value and key at a time, subkeys
+    /// first, in the order regedit lists them.
+    pub fn delete_ops(&self) -> Vec<RegOp> {
+        let mut ops = vec![];
+        let mut values: Vec<_> = self.values().collect();
+        values.sort_by_cached_key(|(name, _)| upcase(name));
+        for (name, _) in values {
+            ops.push(RegOp::DeleteValue {
+                root: self.root,
+                path: self.path.clone(),
+                name: name.clone(),
+            });
+        }
+        let mut children: Vec<_> = self.children.iter().collect();
+        children.sort_by_cached_key(|c| upcase(c.path.rsplit('\\').next().unwrap_or_default()));
+        for child in children {
+            ops.extend(child.delete_ops());
+        }
+        ops.push(RegOp::DeleteKey {
+            root: self.root,
+            path: self.path.clone(),
+        });
+        ops
+    }
+}
+
+/// Applies `ops` in order. Nothing is changed when one of them falls outside
+/// the context menu locations wcm manages, unless `force` is set.
+pub fn apply_ops(backend: &dyn RegistryBackend, ops: &[RegOp], force: bool) -> anyhow::Result<()> {
+    if !force {
+        let outside: Vec<String> = ops
+            .iter()
+            .filter(|op| !op.is_safe())
+            .map(|op| op.to_string())
+            .collect();
+        if !outside.is_empty() {
+            bail!(
+                "refusing to change keys outside the context menu locations:\n{}",
+                outside.join("\n")
+            );
+        }
+    }
+    for op in ops {
+        op.apply(backend).with_context(|| op.to_string())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RegOp, apply_ops};
+    use crate::{MemoryBackend, RegFile, RegItem, RegItemValue, RegistryBackend, SceneRoot};
+
+    const TWEAK: &str = r#"Windows Registry Editor Version 5.00
+
+[-HKEY_CLASSES_ROOT\Directory\Background\shell\old]
+
+[HKEY_CLASSES_ROOT\Directory\Background\shell\wt]
+@="Open in Terminal"
+"Extended"=-
+
+[HKEY_CLASSES_ROOT\Directory\Background\shell\wt\command]
+@="wt.exe -d \"%V\""
+"#;
+
+    #[test]
+    fn test_plan_and_apply() {
+        let reg = MemoryBackend::new();
+        let old = r"Directory\Background\shell\old\command";
+        let wt = r"Directory\Background\shell\wt";
+        reg.create_key(SceneRoot::HKCR, old).unwrap();
+        reg.create_key(SceneRoot::HKCR, wt).unwrap();
+        let extended = RegItemValue::SZ(String::new()).to_raw();
+        reg.set_value(SceneRoot::HKCR, wt, "Extended", &extended)
+            .unwrap();
+        let title = RegItemValue::SZ("Open in Terminal".to_string()).to_raw();
+        reg.set_value(SceneRoot::HKCR, wt, "", &title).unwrap();
+
+        let file = RegFile::parse(TWEAK).unwrap();
+        let ops = file.plan(&reg);
+        assert_eq!(
+            ops.iter().map(|op| op.to_string()).collect::<Vec<_>>(),
+            [
+                r"- [HKEY_CLASSES_ROOT\Directory\Background\shell\old]",
+                r#"- [HKEY_CLASSES_ROOT\Directory\Background\shell\wt] "Extended""#,
+                r"+ [HKEY_CLASSES_ROOT\Directory\Background\shell\wt\command]",
+                r#"~ [HKEY_CLASSES_ROOT\Directory\Background\shell\wt\command] @ = wt.exe -d "%V""#,
+            ]
+        );
+
+        apply_ops(&reg, &ops, false).unwrap();
+        assert!(!reg.key_exists(SceneRoot::HKCR, r"Directory\Background\shell\old"));
+        assert!(reg.get_value(SceneRoot::HKCR, wt, "Extended").is_err());
+        assert!(file.plan(&reg).is_empty());
+    }
+
+    #[test]
+    fn test_refuse_unknown_keys() {
+        let reg = MemoryBackend::new();
+        let ops = [
+            RegOp::CreateKey {
+                root: SceneRoot::HKCU,
+                path: r"Software\Classes\*\shell\edit".to_string(),
+            },
+            RegOp::CreateKey {
+                root: SceneRoot::HKLM,
+                path: r"SYSTEM\CurrentControlSet".to_string(),
+            },
+        ];
+        assert!(ops[0].is_safe());
+        assert!(!ops[1].is_safe());
+        assert!(apply_ops(&reg, &ops, false).is_err());
+        assert!(!reg.key_exists(SceneRoot::HKCU, r"Software\Classes\*\shell\edit"));
+
+        apply_ops(&reg, &ops, true).unwrap();
+        assert!(reg.key_exists(SceneRoot::HKLM, r"SYSTEM\CurrentControlSet"));
+    }
+
+    #[test]
+    fn test_delete_ops() {
+        let reg = MemoryBackend::new();
+        let wt = r"Directory\Background\shell\wt";
+        reg.create_key(SceneRoot::HKCR, &format!(r"{wt}\command"))
+            .unwrap();
+        let title = RegItemValue::SZ("Open in Terminal".to_string()).to_raw();
+        reg.set_value(SceneRoot::HKCR, wt, "", &title).unwrap();
+        reg.set_value(SceneRoot::HKCR, wt, "Icon", &title).unwrap();
+
+        let ops = RegItem::from_path_in(&reg, SceneRoot::HKCR, wt)
+            .unwrap()
+            .delete_ops();
+        assert_eq!(
+            ops.iter().map(|op| op.to_string()).collect::<Vec<_>>(),
+            [
+                r"- [HKEY_CLASSES_ROOT\Directory\Background\shell\wt] @",
+                r#"- [HKEY_CLASSES_ROOT\Directory\Background\shell\wt] "Icon""#,
+                r"- [HKEY_CLASSES_ROOT\Directory\Background\shell\wt\command]",
+                r"- [HKEY_CLASSES_ROOT\Directory\Background\shell\wt]",
+            ]
+        );
+        apply_ops(&reg, &ops, false).unwrap();
+        assert!(!reg.key_exists(SceneRoot::HKCR, wt));
+    }
+}
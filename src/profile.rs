@@ -0,0 +1,341 @@
+use crate::APP_NAME;
+use crate::Action;
+use crate::BatchItem;
+use crate::MenuItem;
+use crate::RegistryBackend;
+use crate::SceneRoot;
+use crate::Scope;
+use crate::Transaction;
+use crate::Type;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::io;
+use std::path::PathBuf;
+use strum::IntoEnumIterator;
+
+/// Whether one item should be enabled.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ProfileItem {
+    pub ty: Type,
+    /// The `Blocked` list of a Win11 item, `None` for Win10.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<Scope>,
+    /// The registry path of a Win10 item or the CLSID of a Win11 one, the
+    /// same on every machine.
+    pub id: String,
+    /// Only for people reading the profile, items are matched by `id`.
+    #[serde(default)]
+    pub name: String,
+    pub enabled: bool,
+    /// The root of a Win10 item, which tells apart items sharing an id.
+    /// Missing from profiles saved before roots were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<SceneRoot>,
+}
+
+impl ProfileItem {
+    /// `item` as listed by `ty` in `scope`.
+    pub fn new(ty: Type, scope: Option<Scope>, item: &MenuItem) -> Self {
+        let (scope, root) = match ty {
+            Type::Win10 => (None, item.root()),
+            Type::Win11 => (Some(scope.unwrap_or_default()), None),
+        };
+        ProfileItem {
+            ty,
+            scope,
+            id: item.id.clone(),
+            name: item.name.clone(),
+            enabled: item.enabled,
+            root,
+        }
+    }
+
+    /// Whether both stand for the same item. Ids are registry paths or
+    /// CLSIDs, which ignore case. An item without a root matches the id in
+    /// any root.
+    pub fn matches(&self, other: &ProfileItem) -> bool {
+        self.ty == other.ty
+            && self.scope == other.scope
+            && self.id.eq_ignore_ascii_case(&other.id)
+            && (self.root.is_none() || other.root.is_none() || self.root == other.root)
+    }
+
+    /// The transaction entry that enables or disables the item.
+    pub fn batch_item(&self, enable: bool) -> BatchItem {
+        BatchItem {
+            ty: self.ty,
+            action: if enable {
+                Action::Enable
+            } else {
+                Action::Disable
+            },
+            id: self.id.clone(),
+            scope: self.scope,
+            root: self.root,
+        }
+    }
+}
+
+impl Display for ProfileItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.ty {
+            Type::Win10 => write!(f, "win10 {}", self.id)?,
+            Type::Win11 => write!(f, "win11 {} {}", self.scope.unwrap_or_default(), self.id)?,
+        }
+        if !self.name.is_empty() {
+            write!(f, " ({})", self.name)?;
+        }
+        Ok(())
+    }
+}
+
+/// A named set of items and whether each should be enabled, to set up
+/// machines the same way.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Profile {
+    pub name: String,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    pub items: Vec<ProfileItem>,
+}
+
+/// What applying a [`Profile`] does on this machine.
+#[derive(Debug, Clone, Default)]
+pub struct ProfilePlan {
+    /// Enables and disables the items whose state differs.
+    pub transaction: Transaction,
+    /// Items of the profile that aren't installed here.
+    pub missing: Vec<ProfileItem>,
+    /// Items installed here that the profile doesn't mention, left as they
+    /// are.
+    pub unmentioned: Vec<ProfileItem>,
+}
+
+impl Profile {
+    pub fn new(name: &str, created: u64) -> Self {
+        Profile {
+            name: name.to_string(),
+            created,
+            items: vec![],
+        }
+    }
+
+    /// Adds `items` as listed by `ty` in `scope`.
+    pub fn add(&mut self, ty: Type, scope: Option<Scope>, items: &[MenuItem]) {
+        self.items
+            .extend(items.iter().map(|item| ProfileItem::new(ty, scope, item)));
+    }
+
+    /// The state of every item in `backend`. Win10 items that are only in
+    /// `backup` count as installed but disabled.
+    pub fn capture_in(
+        backend: &dyn RegistryBackend,
+        name: &str,
+        backup: &[MenuItem],
+        created: u64,
+    ) -> Profile {
+        let mut profile = Profile::new(name, created);
+        for (ty, scope, items) in list_all_in(backend, backup) {
+            profile.add(ty, scope, &items);
+        }
+        profile
+    }
+
+    /// What it takes to bring `current`, as captured by
+    /// [`Profile::capture_in`], to this profile.
+    pub fn plan(&self, current: &Profile) -> ProfilePlan {
+        let mut plan = ProfilePlan::default();
+        for wanted in &self.items {
+            match current.items.iter().find(|i| i.matches(wanted)) {
+                Some(have) if have.enabled == wanted.enabled => {}
+                Some(have) => {
+                    plan.transaction.add(have.batch_item(wanted.enabled));
+                }
+                None => plan.missing.push(wanted.clone()),
+            }
+        }
+        plan.unmentioned = current
+            .items
+            .iter()
+            .filter(|have| !self.items.iter().any(|i| i.matches(have)))
+            .cloned()
+            .collect();
+        plan
+    }
+}
+
+/// Every Win10 item, where the ones only in `backup` are listed as disabled.
+pub(crate) fn list_win10_in(backend: &dyn RegistryBackend, backup: &[MenuItem]) -> Vec<MenuItem> {
+    let mut win10 = Type::Win10.list_in(backend, None);
+    for item in backup {
+        if !win10
+            .iter()
+            .any(|i| i.id.eq_ignore_ascii_case(&item.id) && i.root() == item.root())
+        {
+            win10.push(MenuItem {
+                enabled: false,
+                ..item.clone()
+            });
+        }
+    }
+    win10
+}
+
+/// Every Win10 item and the Win11 items of both scopes. Win10 items that are
+/// only in `backup` are listed as disabled.
+pub(crate) fn list_all_in(
+    backend: &dyn RegistryBackend,
+    backup: &[MenuItem],
+) -> Vec<(Type, Option<Scope>, Vec<MenuItem>)> {
+    let mut all = vec![(Type::Win10, None, list_win10_in(backend, backup))];
+    for scope in Scope::iter() {
+        all.push((
+            Type::Win11,
+            Some(scope),
+            Type::Win11.list_in(backend, Some(scope)),
+        ));
+    }
+    all
+}
+
+/// A directory of profile files named after the profiles.
+#[derive(Debug, Clone)]
+pub struct ProfileStore {
+    dir: PathBuf,
+}
+
+impl ProfileStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        ProfileStore { dir: dir.into() }
+    }
+
+    /// `profiles` in the wcm config directory.
+    pub fn open_default() -> Option<Self> {
+        let dir = dirs::config_local_dir()?.join(APP_NAME).join("profiles");
+        Some(ProfileStore::new(dir))
+    }
+
+    fn file(&self, name: &str) -> io::Result<PathBuf> {
+        if name.is_empty() || name.contains(['/', '\\', '.', ':']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid profile name {name:?}"),
+            ));
+        }
+        Ok(self.dir.join(format!("{name}.json")))
+    }
+
+    /// Writes `profile`, replacing one with the same name.
+    pub fn save(&self, profile: &Profile) -> io::Result<()> {
+        let file = self.file(&profile.name)?;
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(file, serde_json::to_string_pretty(profile)?)
+    }
+
+    pub fn load(&self, name: &str) -> io::Result<Profile> {
+        let s = std::fs::read_to_string(self.file(name)?)?;
+        Ok(serde_json::from_str(&s)?)
+    }
+
+    /// Every stored profile, by name.
+    pub fn list(&self) -> io::Result<Vec<Profile>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut profiles = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if let Some(name) = path.file_stem().and_then(|s| s.to_str())
+                && path.extension().is_some_and(|e| e == "json")
+            {
+                profiles.push(self.load(name)?);
+            }
+        }
+        profiles.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(profiles)
+    }
+
+    pub fn delete(&self, name: &str) -> io::Result<()> {
+        std::fs::remove_file(self.file(name)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Profile, ProfileItem, ProfileStore};
+    use crate::test_support::{TempDir, profile_item};
+    use crate::{MemoryBackend, RegistryBackend, SceneRoot, Scope, Type};
+
+    #[test]
+    fn test_apply() {
+        let reg = MemoryBackend::new();
+        let (wt, format) = (r"Directory\Background\Shell\wt", r"Drive\Shell\format");
+        reg.add_verb(wt, "Open in Terminal", "wt.exe");
+        reg.add_verb(format, "Format", "format.exe");
+        let backup = Type::Win10.list_in(&reg, None);
+        reg.delete_tree(SceneRoot::HKCR, format).unwrap();
+
+        let current = Profile::capture_in(&reg, "here", &backup, 0);
+        let state = |p: &Profile| {
+            let mut state: Vec<_> = p.items.iter().map(|i| (i.id.clone(), i.enabled)).collect();
+            state.sort();
+            state
+        };
+        assert_eq!(
+            state(&current),
+            [(wt.to_string(), true), (format.to_string(), false)]
+        );
+
+        let mut profile = Profile::new("kiosk", 0);
+        profile.items = vec![
+            profile_item(Type::Win10, None, &wt.to_uppercase(), false),
+            profile_item(Type::Win10, None, r"Folder\Shell\git", true),
+            profile_item(Type::Win11, Some(Scope::User), "{1234}", false),
+        ];
+        let plan = profile.plan(&current);
+        assert_eq!(plan.missing.len(), 2);
+        assert_eq!(plan.unmentioned, [current.items[1].clone()]);
+        plan.transaction
+            .commit(&reg, &backup)
+            .into_result()
+            .unwrap();
+        assert!(!reg.key_exists(SceneRoot::HKCR, wt));
+
+        let current = Profile::capture_in(&reg, "here", &backup, 0);
+        assert!(profile.plan(&current).transaction.items().is_empty());
+
+        // items saved with a root only match the item in that root
+        let have = &current.items[0];
+        assert_eq!(have.root, Some(SceneRoot::HKCR));
+        let elsewhere = ProfileItem {
+            root: Some(SceneRoot::HKCU),
+            ..have.clone()
+        };
+        assert!(!elsewhere.matches(have));
+        assert!(profile_item(Type::Win10, None, &have.id, true).matches(have));
+    }
+
+    #[test]
+    fn test_store() {
+        let dir = TempDir::new("profiles");
+        let store = ProfileStore::new(dir.path());
+        assert!(store.list().unwrap().is_empty());
+        let mut profile = Profile::new("minimal", 100);
+        profile.items = vec![profile_item(
+            Type::Win11,
+            Some(Scope::Machine),
+            "{1234}",
+            false,
+        )];
+        store.save(&profile).unwrap();
+        store.save(&Profile::new("developer", 200)).unwrap();
+        assert_eq!(store.load("minimal").unwrap(), profile);
+        let names: Vec<_> = store.list().unwrap().into_iter().map(|p| p.name).collect();
+        assert_eq!(names, ["developer", "minimal"]);
+        assert!(store.save(&Profile::new("../x", 0)).is_err());
+        store.delete("developer").unwrap();
+        assert_eq!(store.list().unwrap().len(), 1);
+    }
+}
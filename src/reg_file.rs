@@ -0,0 +1,567 @@
+use crate::RawValue;
+use crate::RegItem;
+use crate::RegItemValue;
+use crate::SceneRoot;
+use crate::backend::join_path;
+use crate::regf::upcase;
+use crate::vtype::*;
+use anyhow::{Context, anyhow, bail};
+use std::path::Path;
+
+pub const REG_HEADER: &str = "Windows Registry Editor Version 5.00";
+pub const REGEDIT4_HEADER: &str = "REGEDIT4";
+
+/// Hex data is wrapped once a line reaches this many characters.
+const MAX_HEX_LINE: usize = 77;
+
+/// One `[key]` section of a .reg file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegSection {
+    pub root: SceneRoot,
+    pub path: String,
+    /// `[-key]`, which removes the key and everything below it.
+    pub delete: bool,
+    /// Values in file order. `None` is a `"name"=-` removal.
+    pub values: Vec<(String, Option<RegItemValue>)>,
+}
+
+/// A parsed .reg file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegFile {
+    /// `REGEDIT4` files store `hex(1)`, `hex(2)` and `hex(7)` strings as
+    /// ANSI instead of UTF-16.
+    pub regedit4: bool,
+    pub sections: Vec<RegSection>,
+}
+
+impl RegFile {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<RegFile> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path).with_context(|| path.display().to_string())?;
+        RegFile::from_bytes(&bytes).with_context(|| path.display().to_string())
+    }
+
+    /// Decodes UTF-16LE with a BOM, as written by regedit, or UTF-8.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<RegFile> {
+        let text = if let Some(rest) = bytes.strip_prefix(&[0xFF, 0xFE]) {
+            let words: Vec<u16> = rest
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect();
+            String::from_utf16(&words).context("invalid UTF-16 text")?
+        } else {
+            let rest = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]).unwrap_or(bytes);
+            String::from_utf8(rest.to_vec()).context("invalid UTF-8 text")?
+        };
+        RegFile::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<RegFile> {
+        let mut lines = logical_lines(text).into_iter();
+        let regedit4 = loop {
+            match lines.next() {
+                Some((_, line)) if line.is_empty() => continue,
+                Some((_, line)) if line == REG_HEADER => break false,
+                Some((_, line)) if line == REGEDIT4_HEADER => break true,
+                _ => bail!("missing registry file header"),
+            }
+        };
+
+        let mut file = RegFile {
+            regedit4,
+            sections: vec![],
+        };
+        for (number, line) in lines {
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') {
+                let section = parse_section(&line).with_context(|| format!("line {number}"))?;
+                file.sections.push(section);
+                continue;
+            }
+            let section = file
+                .sections
+                .last_mut()
+                .ok_or_else(|| anyhow!("line {number}: value outside of a key"))?;
+            let value = parse_value(&line, regedit4).with_context(|| format!("line {number}"))?;
+            if section.delete {
+                bail!("line {number}: value under a deleted key");
+            }
+            section.values.push(value);
+        }
+        Ok(file)
+    }
+
+    /// Builds the trees of keys the file creates, one per topmost key, with
+    /// missing intermediate keys filled in. Removals are left out.
+    pub fn to_items(&self) -> Vec<RegItem> {
+        let mut items: Vec<RegItem> = vec![];
+        for section in self.sections.iter().filter(|s| !s.delete) {
+            let item = find_or_insert(&mut items, section.root, &section.path);
+            for (name, value) in &section.values {
+                if let Some(value) = value {
+                    item.set_value(name, value.clone());
+                }
+            }
+        }
+        items
+    }
+}
+
+/// Joins `\` continuations and trims every line, keeping the number of the
+/// line each one starts on.
+fn logical_lines(text: &str) -> Vec<(usize, String)> {
+    let mut out: Vec<(usize, String)> = vec![];
+    let mut continued = false;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let (line, continues) = match line.strip_suffix('\\') {
+            Some(head) if !line.starts_with(';') => (head.trim_end(), true),
+            _ => (line, false),
+        };
+        match out.last_mut() {
+            Some((_, last)) if continued => last.push_str(line),
+            _ => out.push((i + 1, line.to_string())),
+        }
+        continued = continues;
+    }
+    out
+}
+
+fn parse_section(line: &str) -> anyhow::Result<RegSection> {
+    let inner = line
+        .strip_prefix('[')
+        .and_then(|l| l.rfind(']').map(|end| &l[..end]))
+        .ok_or_else(|| anyhow!("unterminated key name"))?;
+    let (delete, inner) = match inner.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, inner),
+    };
+    let (root, path) = inner.split_once('\\').unwrap_or((inner, ""));
+    let root =
+        SceneRoot::from_hive_name(root).ok_or_else(|| anyhow!("unsupported root key {root}"))?;
+    Ok(RegSection {
+        root,
+        path: path.trim_end_matches('\\').to_string(),
+        delete,
+        values: vec![],
+    })
+}
+
+/// Reads a `"..."` string starting at the opening quote and returns it
+/// unescaped along with the rest of the input.
+fn parse_quoted(s: &str) -> anyhow::Result<(String, &str)> {
+    let mut out = String::new();
+    let mut chars = s
+        .strip_prefix('"')
+        .context("expected a quote")?
+        .char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((out, &s[i + 2..])),
+            '\\' => match chars.next() {
+                Some((_, c)) => out.push(c),
+                None => break,
+            },
+            c => out.push(c),
+        }
+    }
+    bail!("unterminated string")
+}
+
+fn parse_value(line: &str, regedit4: bool) -> anyhow::Result<(String, Option<RegItemValue>)> {
+    let (name, rest) = match line.strip_prefix('@') {
+        Some(rest) => (String::new(), rest),
+        None => parse_quoted(line)?,
+    };
+    let data = rest
+        .trim_start()
+        .strip_prefix('=')
+        .ok_or_else(|| anyhow!("expected '=' after value name"))?
+        .trim();
+
+    if data == "-" {
+        return Ok((name, None));
+    }
+    if data.starts_with('"') {
+        let (s, rest) = parse_quoted(data)?;
+        if !rest.trim().is_empty() {
+            bail!("unexpected text after string value");
+        }
+        return Ok((name, Some(RegItemValue::SZ(s))));
+    }
+    if let Some(hex) = data.strip_prefix("dword:") {
+        let v = u32::from_str_radix(hex.trim(), 16).context("invalid dword")?;
+        return Ok((name, Some(RegItemValue::DWORD(v))));
+    }
+    if let Some(hex) = data.strip_prefix("qword:") {
+        let v = u64::from_str_radix(hex.trim(), 16).context("invalid qword")?;
+        return Ok((name, Some(RegItemValue::QWORD(v))));
+    }
+
+    let (vtype, hex) = if let Some(hex) = data.strip_prefix("hex:") {
+        (REG_BINARY, hex)
+    } else if let Some(rest) = data.strip_prefix("hex(") {
+        let (ty, hex) = rest
+            .split_once("):")
+            .ok_or_else(|| anyhow!("invalid hex value type"))?;
+        let ty = u32::from_str_radix(ty, 16).context("invalid hex value type")?;
+        (ty, hex)
+    } else {
+        bail!("unrecognized value data {data}");
+    };
+    let mut bytes = hex
+        .split(',')
+        .map(str::trim)
+        .filter(|b| !b.is_empty())
+        .map(|b| u8::from_str_radix(b, 16).with_context(|| format!("invalid hex byte {b}")))
+        .collect::<anyhow::Result<Vec<u8>>>()?;
+
+    if regedit4 && matches!(vtype, REG_SZ | REG_EXPAND_SZ | REG_MULTI_SZ) {
+        bytes = bytes.iter().flat_map(|&b| [b, 0]).collect();
+    }
+    let value = RegItemValue::from(RawValue { vtype, bytes });
+    Ok((name, Some(value)))
+}
+
+fn is_same_or_below(ancestor: &str, path: &str) -> bool {
+    if ancestor.is_empty() {
+        return true;
+    }
+    match path.get(..ancestor.len()) {
+        Some(head) if head.eq_ignore_ascii_case(ancestor) => {
+            path.len() == ancestor.len() || path[ancestor.len()..].starts_with('\\')
+        }
+        _ => false,
+    }
+}
+
+/// Walks from `item` down to `path`, creating missing keys on the way.
+fn descend<'a>(item: &'a mut RegItem, path: &str) -> &'a mut RegItem {
+    if item.path.len() >= path.len() {
+        return item;
+    }
+    let rest = if item.path.is_empty() {
+        path
+    } else {
+        &path[item.path.len() + 1..]
+    };
+    let name = rest.split('\\').next().unwrap_or_default();
+    let child_path = join_path(&item.path, name);
+    let i = match item
+        .children
+        .iter()
+        .position(|c| c.path.eq_ignore_ascii_case(&child_path))
+    {
+        Some(i) => i,
+        None => {
+            item.children.push(RegItem::new(item.root, &child_path));
+            item.children.len() - 1
+        }
+    };
+    descend(&mut item.children[i], path)
+}
+
+fn find_or_insert<'a>(items: &'a mut Vec<RegItem>, root: SceneRoot, path: &str) -> &'a mut RegItem {
+    if let Some(i) = items
+        .iter()
+        .position(|it| it.root == root && is_same_or_below(&it.path, path))
+    {
+        return descend(&mut items[i], path);
+    }
+
+    let (below, rest): (Vec<_>, Vec<_>) = items
+        .drain(..)
+        .partition(|it| it.root == root && is_same_or_below(path, &it.path));
+    *items = rest;
+    let mut item = RegItem::new(root, path);
+    for child in below {
+        let parent = child.path.rsplit_once('\\').map_or("", |(p, _)| p);
+        descend(&mut item, parent).children.push(child);
+    }
+    items.push(item);
+    items.last_mut().expect("just pushed")
+}
+
+/// Renders `items` and their subkeys as a .reg file the way regedit exports
+/// them: CRLF line ends, subkeys in registry order and hex data wrapped at
+/// the same columns. Values are sorted by name so exports diff cleanly.
+pub fn reg_txt(items: &[RegItem]) -> String {
+    let mut out = format!("{REG_HEADER}\r\n\r\n");
+    for item in items {
+        write_item(&mut out, item);
+    }
+    out
+}
+
+/// Encodes .reg text as UTF-16LE with a BOM, like regedit saves it.
+pub fn encode_reg_file(text: &str) -> Vec<u8> {
+    let mut bytes = vec![0xFF, 0xFE];
+    bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+    bytes
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', r"\\").replace('"', r#"\""#)
+}
+
+fn write_item(out: &mut String, item: &RegItem) {
+    match item.path.as_str() {
+        "" => out.push_str(&format!("[{}]\r\n", item.root.hive_name())),
+        path => out.push_str(&format!("[{}\\{path}]\r\n", item.root.hive_name())),
+    }
+    let mut values: Vec<_> = item.values().collect();
+    values.sort_by_cached_key(|(name, _)| upcase(name));
+    for (name, value) in values {
+        write_value(out, name, value);
+    }
+    out.push_str("\r\n");
+
+    let mut children: Vec<&RegItem> = item.children.iter().collect();
+    children.sort_by_cached_key(|c| upcase(c.path.rsplit('\\').next().unwrap_or_default()));
+    for child in children {
+        write_item(out, child);
+    }
+}
+
+fn write_value(out: &mut String, name: &str, value: &RegItemValue) {
+    let line_len = if name.is_empty() {
+        out.push_str("@=");
+        2
+    } else {
+        let name = escape(name);
+        out.push_str(&format!(r#""{name}"="#));
+        name.encode_utf16().count() + 3
+    };
+    match value {
+        // strings that can't survive the line based format are written as hex
+        RegItemValue::SZ(s) if !s.contains(['\r', '\n', '\0']) => {
+            out.push_str(&format!(r#""{}""#, escape(s)));
+        }
+        RegItemValue::DWORD(v) => out.push_str(&format!("dword:{v:08x}")),
+        value => {
+            let raw = value.to_raw();
+            write_hex(out, line_len, raw.vtype, &raw.bytes);
+        }
+    }
+    out.push_str("\r\n");
+}
+
+fn write_hex(out: &mut String, mut line_len: usize, vtype: u32, bytes: &[u8]) {
+    let prefix = match vtype {
+        REG_BINARY => "hex:".to_string(),
+        ty => format!("hex({ty:x}):"),
+    };
+    line_len += prefix.len();
+    out.push_str(&prefix);
+    for (i, b) in bytes.iter().enumerate() {
+        out.push_str(&format!("{b:02x}"));
+        if i + 1 == bytes.len() {
+            break;
+        }
+        out.push(',');
+        line_len += 3;
+        if line_len >= MAX_HEX_LINE {
+            out.push_str("\\\r\n  ");
+            line_len = 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RegFile;
+    use crate::vtype::*;
+    use crate::{RawValue, RegItem, RegItemValue, SceneRoot};
+
+    #[test]
+    fn test_parse_utf16_reg_file() {
+        let text = concat!(
+            "Windows Registry Editor Version 5.00\r\n",
+            "\r\n",
+            "; Open in Terminal\r\n",
+            "[HKEY_CLASSES_ROOT\\Directory\\Background\\shell\\wt]\r\n",
+            "@=\"Open in \\\"Terminal\\\"\"\r\n",
+            "\"Icon\"=hex(2):25,00,4c,00,4f,00,43,00,41,00,4c,00,41,00,50,00,50,00,44,00,\\\r\n",
+            "  41,00,54,00,41,00,25,00,00,00\r\n",
+            "\"Flags\"=dword:0000001f\r\n",
+            "\"Old\"=-\r\n",
+            "\r\n",
+            "[HKEY_CLASSES_ROOT\\Directory\\Background\\shell\\wt\\command]\r\n",
+            "@=\"C:\\\\wt.exe -d \\\"%V\\\"\"\r\n",
+            "\r\n",
+            "[-HKCU\\Software\\Classes\\Directory\\Background\\shell\\wt]\r\n",
+        );
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        let file = RegFile::from_bytes(&bytes).unwrap();
+
+        assert!(!file.regedit4);
+        assert_eq!(file.sections.len(), 3);
+        let section = &file.sections[0];
+        assert_eq!(section.root, SceneRoot::HKCR);
+        assert_eq!(section.path, r"Directory\Background\shell\wt");
+        assert_eq!(
+            section.values[0],
+            (
+                String::new(),
+                Some(RegItemValue::SZ(r#"Open in "Terminal""#.to_string()))
+            )
+        );
+        assert_eq!(
+            section.values[1].1.as_ref().unwrap().to_string(),
+            "%LOCALAPPDATA%"
+        );
+        assert_eq!(section.values[2].1, Some(RegItemValue::DWORD(0x1f)));
+        assert_eq!(section.values[3], ("Old".to_string(), None));
+        assert_eq!(
+            file.sections[1].values[0].1,
+            Some(RegItemValue::SZ(r#"C:\wt.exe -d "%V""#.to_string()))
+        );
+        assert!(file.sections[2].delete);
+        assert_eq!(file.sections[2].root, SceneRoot::HKCU);
+
+        let items = file.to_items();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].children.len(), 1);
+        assert_eq!(
+            items[0].get_child("command").unwrap().get_value(""),
+            Some(&RegItemValue::SZ(r#"C:\wt.exe -d "%V""#.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_regedit4() {
+        let text =
+            "REGEDIT4\n\n[HKEY_LOCAL_MACHINE\\SOFTWARE\\Test]\n\"Path\"=hex(2):25,54,4d,50,25,00\n";
+        let file = RegFile::parse(text).unwrap();
+        assert!(file.regedit4);
+        assert_eq!(
+            file.sections[0].values[0].1.as_ref().unwrap().to_string(),
+            "%TMP%"
+        );
+    }
+
+    #[test]
+    fn test_reject_malformed() {
+        assert!(RegFile::parse("[HKEY_CLASSES_ROOT\\x]\n").is_err());
+        let header = "Windows Registry Editor Version 5.00\n";
+        for body in [
+            "\"a\"=\"b\"\n",
+            "[HKEY_USERS\\x]\n",
+            "[HKCR\\x]\n\"a\"=dword:zz\n",
+            "[HKCR\\x]\n\"a\"=hex(zz):01,02\n",
+            "[HKCR\\x]\n\"a=\"b\"\n",
+        ] {
+            assert!(
+                RegFile::parse(&format!("{header}{body}")).is_err(),
+                "{body}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_export_format() {
+        let mut item = RegItem::new(SceneRoot::HKCU, r"Software\Classes\*\shell\edit");
+        item.set_value("MultiSelectModel", RegItemValue::SZ("Player".to_string()));
+        item.set_value("", RegItemValue::SZ(r#"Edit "here""#.to_string()));
+        item.set_value("Position", RegItemValue::DWORD(2));
+        item.set_value("Data", RegItemValue::BINARY((0..30).collect()));
+        item.set_value("Verbs", RegItemValue::MultiSz("a\nb".to_string()));
+        item.set_value("Size", RegItemValue::QWORD(1));
+        item.children.push(RegItem::new(
+            SceneRoot::HKCU,
+            r"Software\Classes\*\shell\edit\command",
+        ));
+        item.children.push(RegItem::new(
+            SceneRoot::HKCU,
+            r"Software\Classes\*\shell\edit\Cmd",
+        ));
+
+        let expected = [
+            "Windows Registry Editor Version 5.00",
+            "",
+            r"[HKEY_CURRENT_USER\Software\Classes\*\shell\edit]",
+            r#"@="Edit \"here\"""#,
+            r#""Data"=hex:00,01,02,03,04,05,06,07,08,09,0a,0b,0c,0d,0e,0f,10,11,12,13,14,15,\"#,
+            "  16,17,18,19,1a,1b,1c,1d",
+            r#""MultiSelectModel"="Player""#,
+            r#""Position"=dword:00000002"#,
+            r#""Size"=hex(b):01,00,00,00,00,00,00,00"#,
+            r#""Verbs"=hex(7):61,00,00,00,62,00,00,00,00,00"#,
+            "",
+            r"[HKEY_CURRENT_USER\Software\Classes\*\shell\edit\Cmd]",
+            "",
+            r"[HKEY_CURRENT_USER\Software\Classes\*\shell\edit\command]",
+            "",
+            "",
+        ]
+        .join("\r\n");
+        assert_eq!(item.to_reg_txt(), expected);
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let mut item = RegItem::new(SceneRoot::HKLM, r"SOFTWARE\Policies\Microsoft\Edge");
+        item.set_value("", RegItemValue::SZ("line\r\nbreak".to_string()));
+        item.set_value("Icon", RegItemValue::ExpandSz(b"%\0W\0%\0\0\0".to_vec()));
+        item.set_value(
+            "Big",
+            RegItemValue::BINARY((0..=255).cycle().take(1000).collect()),
+        );
+        item.set_value("Empty", RegItemValue::None(vec![]));
+        item.set_value("Count", RegItemValue::QWORD(u64::MAX));
+        let mut command = RegItem::new(SceneRoot::HKLM, r"SOFTWARE\Policies\Microsoft\Edge\x");
+        command.set_value("", RegItemValue::SZ(r"C:\edit.exe %1".to_string()));
+        item.children.push(command);
+
+        let bytes = item.to_reg_file();
+        assert_eq!(bytes[..2], [0xFF, 0xFE]);
+        let file = RegFile::from_bytes(&bytes).unwrap();
+        assert_eq!(file.to_items(), [item.clone()]);
+        assert_eq!(file.to_items()[0].to_reg_file(), bytes);
+    }
+
+    #[test]
+    fn test_every_value_type_round_trips() {
+        let raw = [
+            (REG_DWORD, vec![1, 2, 3]),
+            (REG_QWORD, vec![1]),
+            (REG_SZ, vec![0x41, 0, 0x42]),
+            (REG_DWORD_BIG_ENDIAN, vec![0, 0, 1, 0]),
+            (
+                REG_LINK,
+                "\\Registry\\Machine"
+                    .encode_utf16()
+                    .flat_map(u16::to_le_bytes)
+                    .collect(),
+            ),
+            (REG_RESOURCE_LIST, vec![1, 0, 0, 0]),
+            (REG_FULL_RESOURCE_DESCRIPTOR, vec![2; 40]),
+            (REG_RESOURCE_REQUIREMENTS_LIST, vec![]),
+            (0x2000_0000, vec![0xAB]),
+        ];
+        let mut item = RegItem::new(SceneRoot::HKCR, r"*\shell\odd");
+        for (i, (vtype, bytes)) in raw.iter().enumerate() {
+            let value = RegItemValue::from(RawValue {
+                vtype: *vtype,
+                bytes: bytes.clone(),
+            });
+            assert_eq!(&value.to_raw().bytes, bytes);
+            let _ = value.to_string();
+            item.set_value(&format!("v{i}"), value);
+        }
+        assert_eq!(
+            item.get_value("v3"),
+            Some(&RegItemValue::DwordBigEndian(256))
+        );
+        assert_eq!(
+            item.get_value("v4"),
+            Some(&RegItemValue::Link(r"\Registry\Machine".to_string()))
+        );
+
+        let file = RegFile::parse(&item.to_reg_txt()).unwrap();
+        assert_eq!(file.to_items(), [item]);
+    }
+}
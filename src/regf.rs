@@ -0,0 +1,1104 @@
+use crate::RawValue;
+use crate::backend::filetime_now;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+const BASE_BLOCK_SIZE: usize = 4096;
+const BIG_DATA_SEGMENT_SIZE: usize = 16344;
+const NO_CELL: u32 = 0xFFFF_FFFF;
+const KEY_COMP_NAME: u16 = 0x0020;
+const VALUE_COMP_NAME: u16 = 0x0001;
+const DATA_INLINE: u32 = 0x8000_0000;
+
+pub(crate) fn corrupt(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, path.to_string())
+}
+
+pub(crate) fn names_equal(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b) || a.to_uppercase() == b.to_uppercase()
+}
+
+fn decode_name(bytes: &[u8], compressed: bool) -> String {
+    if compressed {
+        bytes.iter().map(|&b| b as char).collect()
+    } else {
+        let words: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        String::from_utf16_lossy(&words)
+    }
+}
+
+/// XOR of the first 127 dwords of the base block.
+pub(crate) fn checksum(data: &[u8]) -> u32 {
+    let sum = data[..0x1FC].chunks_exact(4).fold(0u32, |acc, c| {
+        acc ^ u32::from_le_bytes([c[0], c[1], c[2], c[3]])
+    });
+    match sum {
+        0xFFFF_FFFF => 0xFFFF_FFFE,
+        0 => 1,
+        sum => sum,
+    }
+}
+
+/// A parsed `nk` cell.
+#[derive(Debug, Clone)]
+pub(crate) struct KeyNode {
+    pub offset: u32,
+    pub name: String,
+    pub last_write_time: u64,
+    pub subkey_count: u32,
+    pub subkey_list: u32,
+    pub value_count: u32,
+    pub value_list: u32,
+    pub security: u32,
+    pub class_name: u32,
+}
+
+/// An offline registry hive file in the REGF format (`NTUSER.DAT`,
+/// `UsrClass.dat`, `SOFTWARE`, ...), kept in memory as raw bytes.
+///
+/// Cell offsets are relative to the first hive bin, which directly follows
+/// the 4096 byte base block.
+#[derive(Debug, Clone)]
+pub struct Hive {
+    pub(crate) data: Vec<u8>,
+    /// Free cells as `(offset, size)`, scanned on the first allocation.
+    free: Option<Vec<(u32, u32)>>,
+    modified: bool,
+}
+
+impl Hive {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Hive> {
+        Hive::from_bytes(std::fs::read(path)?)
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> io::Result<Hive> {
+        if data.len() < BASE_BLOCK_SIZE || &data[..4] != b"regf" {
+            return Err(corrupt("not a registry hive file"));
+        }
+        let hive = Hive {
+            data,
+            free: None,
+            modified: false,
+        };
+        if hive.u32_at(0x14)? != 1 {
+            return Err(corrupt("unsupported hive major version"));
+        }
+        if hive.checksum() != hive.u32_at(0x1FC)? {
+            return Err(corrupt("hive base block checksum mismatch"));
+        }
+        hive.key(hive.root_cell()?)?;
+        Ok(hive)
+    }
+
+    /// The file name stored in the base block, usually the tail of the path
+    /// the hive was loaded from on its original machine.
+    pub fn file_name(&self) -> String {
+        let raw = &self.data[0x30..0x70];
+        decode_name(raw, false).trim_end_matches('\0').to_string()
+    }
+
+    /// A hive whose sequence numbers differ was not cleanly flushed and has
+    /// pending changes in its transaction logs.
+    pub fn is_dirty(&self) -> bool {
+        self.data[4..8] != self.data[8..12]
+    }
+
+    pub(crate) fn minor_version(&self) -> u32 {
+        u32::from_le_bytes([
+            self.data[0x18],
+            self.data[0x19],
+            self.data[0x1A],
+            self.data[0x1B],
+        ])
+    }
+
+    pub(crate) fn checksum(&self) -> u32 {
+        checksum(&self.data)
+    }
+
+    pub(crate) fn root_cell(&self) -> io::Result<u32> {
+        self.u32_at(0x24)
+    }
+
+    fn bytes_at(&self, pos: usize, len: usize) -> io::Result<&[u8]> {
+        pos.checked_add(len)
+            .and_then(|end| self.data.get(pos..end))
+            .ok_or_else(|| corrupt(format!("read past end of hive at {pos:#x}")))
+    }
+
+    pub(crate) fn u32_at(&self, pos: usize) -> io::Result<u32> {
+        let b = self.bytes_at(pos, 4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// File position of the size field of the cell at `offset`.
+    pub(crate) fn cell_pos(offset: u32) -> usize {
+        BASE_BLOCK_SIZE + offset as usize
+    }
+
+    /// Data of the allocated cell at `offset`, without its size field.
+    pub(crate) fn cell(&self, offset: u32) -> io::Result<&[u8]> {
+        if offset == NO_CELL {
+            return Err(corrupt("reference to a missing cell"));
+        }
+        let pos = Self::cell_pos(offset);
+        let size = self.u32_at(pos)? as i32;
+        if size >= 0 {
+            return Err(corrupt(format!("cell {offset:#x} is not allocated")));
+        }
+        let size = size.unsigned_abs() as usize;
+        if size < 4 {
+            return Err(corrupt(format!("cell {offset:#x} is too small")));
+        }
+        self.bytes_at(pos + 4, size - 4)
+    }
+
+    pub(crate) fn key(&self, offset: u32) -> io::Result<KeyNode> {
+        let cell = self.cell(offset)?;
+        if cell.len() < 76 || &cell[..2] != b"nk" {
+            return Err(corrupt(format!("cell {offset:#x} is not a key node")));
+        }
+        let u32_at = |pos: usize| {
+            u32::from_le_bytes([cell[pos], cell[pos + 1], cell[pos + 2], cell[pos + 3]])
+        };
+        let flags = u16::from_le_bytes([cell[2], cell[3]]);
+        let name_len = u16::from_le_bytes([cell[72], cell[73]]) as usize;
+        let name = cell
+            .get(76..76 + name_len)
+            .ok_or_else(|| corrupt(format!("key node {offset:#x} name is truncated")))?;
+        let mut last_write_time = [0u8; 8];
+        last_write_time.copy_from_slice(&cell[4..12]);
+        Ok(KeyNode {
+            offset,
+            name: decode_name(name, flags & KEY_COMP_NAME != 0),
+            last_write_time: u64::from_le_bytes(last_write_time),
+            subkey_count: u32_at(20),
+            subkey_list: u32_at(28),
+            value_count: u32_at(36),
+            value_list: u32_at(40),
+            security: u32_at(44),
+            class_name: u32_at(48),
+        })
+    }
+
+    /// Offsets of the key nodes referenced by a subkey index cell.
+    pub(crate) fn subkey_offsets(&self, key: &KeyNode) -> io::Result<Vec<u32>> {
+        let mut out = vec![];
+        if key.subkey_count > 0 && key.subkey_list != NO_CELL {
+            self.index_offsets(key.subkey_list, true, &mut out)?;
+        }
+        Ok(out)
+    }
+
+    fn index_offsets(&self, list: u32, allow_ri: bool, out: &mut Vec<u32>) -> io::Result<()> {
+        let cell = self.cell(list)?;
+        if cell.len() < 4 {
+            return Err(corrupt(format!("index cell {list:#x} is too small")));
+        }
+        let count = u16::from_le_bytes([cell[2], cell[3]]) as usize;
+        let stride = match &cell[..2] {
+            b"lf" | b"lh" => 8,
+            b"li" | b"ri" => 4,
+            _ => return Err(corrupt(format!("cell {list:#x} is not a subkey index"))),
+        };
+        let entries = cell
+            .get(4..4 + count * stride)
+            .ok_or_else(|| corrupt(format!("index cell {list:#x} is truncated")))?;
+        for entry in entries.chunks_exact(stride) {
+            let offset = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+            if &cell[..2] == b"ri" {
+                if !allow_ri {
+                    return Err(corrupt(format!("nested index root at {list:#x}")));
+                }
+                self.index_offsets(offset, false, out)?;
+            } else {
+                out.push(offset);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn subkeys(&self, key: &KeyNode) -> io::Result<Vec<KeyNode>> {
+        self.subkey_offsets(key)?
+            .into_iter()
+            .map(|offset| self.key(offset))
+            .collect()
+    }
+
+    pub(crate) fn find_subkey(&self, key: &KeyNode, name: &str) -> io::Result<Option<KeyNode>> {
+        Ok(self
+            .subkeys(key)?
+            .into_iter()
+            .find(|k| names_equal(&k.name, name)))
+    }
+
+    pub(crate) fn find_key(&self, path: &str) -> io::Result<Option<KeyNode>> {
+        let mut key = self.key(self.root_cell()?)?;
+        for name in path.split('\\').filter(|s| !s.is_empty()) {
+            match self.find_subkey(&key, name)? {
+                Some(k) => key = k,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(key))
+    }
+
+    /// How many leading components of `path` exist.
+    pub(crate) fn existing_depth(&self, path: &str) -> io::Result<usize> {
+        let mut key = self.key(self.root_cell()?)?;
+        let mut depth = 0;
+        for name in path.split('\\').filter(|s| !s.is_empty()) {
+            match self.find_subkey(&key, name)? {
+                Some(k) => key = k,
+                None => break,
+            }
+            depth += 1;
+        }
+        Ok(depth)
+    }
+
+    fn open_key(&self, path: &str) -> io::Result<KeyNode> {
+        self.find_key(path)?.ok_or_else(|| not_found(path))
+    }
+
+    /// Offsets of the `vk` cells of a key, in stored order.
+    pub(crate) fn value_offsets(&self, key: &KeyNode) -> io::Result<Vec<u32>> {
+        if key.value_count == 0 || key.value_list == NO_CELL {
+            return Ok(vec![]);
+        }
+        let cell = self.cell(key.value_list)?;
+        let list = cell
+            .get(..key.value_count as usize * 4)
+            .ok_or_else(|| corrupt(format!("value list {:#x} is truncated", key.value_list)))?;
+        Ok(list
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect())
+    }
+
+    pub(crate) fn value_name(&self, offset: u32) -> io::Result<String> {
+        let cell = self.cell(offset)?;
+        if cell.len() < 20 || &cell[..2] != b"vk" {
+            return Err(corrupt(format!("cell {offset:#x} is not a value")));
+        }
+        let name_len = u16::from_le_bytes([cell[2], cell[3]]) as usize;
+        let flags = u16::from_le_bytes([cell[16], cell[17]]);
+        let name = cell
+            .get(20..20 + name_len)
+            .ok_or_else(|| corrupt(format!("value {offset:#x} name is truncated")))?;
+        Ok(decode_name(name, flags & VALUE_COMP_NAME != 0))
+    }
+
+    pub(crate) fn value(&self, offset: u32) -> io::Result<(String, RawValue)> {
+        let name = self.value_name(offset)?;
+        let cell = self.cell(offset)?;
+        let u32_at = |pos: usize| {
+            u32::from_le_bytes([cell[pos], cell[pos + 1], cell[pos + 2], cell[pos + 3]])
+        };
+        let size = u32_at(4);
+        let data_offset = u32_at(8);
+        let vtype = u32_at(12);
+
+        let bytes = if size & DATA_INLINE != 0 {
+            let len = ((size & !DATA_INLINE) as usize).min(4);
+            data_offset.to_le_bytes()[..len].to_vec()
+        } else if size == 0 {
+            vec![]
+        } else {
+            self.value_data(data_offset, size as usize)?
+        };
+        Ok((name, RawValue { vtype, bytes }))
+    }
+
+    fn value_data(&self, offset: u32, len: usize) -> io::Result<Vec<u8>> {
+        let cell = self.cell(offset)?;
+        if len > BIG_DATA_SEGMENT_SIZE && self.minor_version() >= 4 && cell.starts_with(b"db") {
+            if cell.len() < 8 {
+                return Err(corrupt(format!("big data cell {offset:#x} is too small")));
+            }
+            let count = u16::from_le_bytes([cell[2], cell[3]]) as usize;
+            let list = u32::from_le_bytes([cell[4], cell[5], cell[6], cell[7]]);
+            let segments = self
+                .cell(list)?
+                .get(..count * 4)
+                .ok_or_else(|| corrupt(format!("segment list {list:#x} is truncated")))?;
+            let mut out = Vec::with_capacity(len);
+            for c in segments.chunks_exact(4) {
+                let segment = self.cell(u32::from_le_bytes([c[0], c[1], c[2], c[3]]))?;
+                let take = (len - out.len())
+                    .min(BIG_DATA_SEGMENT_SIZE)
+                    .min(segment.len());
+                out.extend_from_slice(&segment[..take]);
+            }
+            if out.len() != len {
+                return Err(corrupt(format!("big data {offset:#x} is truncated")));
+            }
+            return Ok(out);
+        }
+        cell.get(..len)
+            .map(|b| b.to_vec())
+            .ok_or_else(|| corrupt(format!("value data {offset:#x} is truncated")))
+    }
+
+    pub fn subkey_names(&self, path: &str) -> io::Result<Vec<String>> {
+        let key = self.open_key(path)?;
+        Ok(self.subkeys(&key)?.into_iter().map(|k| k.name).collect())
+    }
+
+    pub fn values(&self, path: &str) -> io::Result<Vec<(String, RawValue)>> {
+        let key = self.open_key(path)?;
+        self.value_offsets(&key)?
+            .into_iter()
+            .map(|offset| self.value(offset))
+            .collect()
+    }
+
+    pub fn last_write_time(&self, path: &str) -> io::Result<u64> {
+        Ok(self.open_key(path)?.last_write_time)
+    }
+}
+
+/// Most entries a single `lh` list holds before the index is split under an
+/// `ri` root.
+const MAX_LEAF_ENTRIES: usize = 512;
+const KEY_NODE_SIZE: usize = 76;
+const MAX_KEY_NAME_LEN: usize = 255;
+
+/// Encodes a key or value name, as Latin-1 when possible.
+fn encode_name(name: &str) -> (Vec<u8>, bool) {
+    if name.chars().all(|c| (c as u32) < 0x100) {
+        (name.chars().map(|c| c as u8).collect(), true)
+    } else {
+        let bytes = name.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        (bytes, false)
+    }
+}
+
+/// A name upcased as the registry compares it when sorting subkeys.
+pub(crate) fn upcase(name: &str) -> Vec<u16> {
+    name.to_uppercase().encode_utf16().collect()
+}
+
+/// The hash stored next to each entry of an `lh` list.
+fn name_hash(name: &str) -> u32 {
+    upcase(name)
+        .into_iter()
+        .fold(0u32, |h, c| h.wrapping_mul(37).wrapping_add(c as u32))
+}
+
+fn split_parent(path: &str) -> (&str, &str) {
+    let path = path.trim_matches('\\');
+    path.rsplit_once('\\').unwrap_or(("", path))
+}
+
+impl Hive {
+    /// Whether the hive was changed since it was loaded or last saved.
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    /// Creates `path` and any missing parent keys.
+    pub fn create_key(&mut self, path: &str) -> io::Result<()> {
+        self.writable()?;
+        let mut key = self.key(self.root_cell()?)?;
+        for name in path.split('\\').filter(|s| !s.is_empty()) {
+            key = match self.find_subkey(&key, name)? {
+                Some(k) => k,
+                None => {
+                    let child = self.new_key(&key, name)?;
+                    let mut subkeys = self.subkey_offsets(&key)?;
+                    subkeys.push(child);
+                    self.write_subkey_index(&key, &subkeys)?;
+
+                    let pos = Self::data_pos(key.offset) + 52;
+                    let name_len = name.encode_utf16().count() as u16 * 2;
+                    if name_len > u16::from_le_bytes([self.data[pos], self.data[pos + 1]]) {
+                        self.set_bytes(pos, &name_len.to_le_bytes());
+                    }
+                    self.touch(key.offset);
+                    self.key(child)?
+                }
+            };
+        }
+        Ok(())
+    }
+
+    /// Deletes `path`, which must not have subkeys.
+    pub fn delete_key(&mut self, path: &str) -> io::Result<()> {
+        let (parent_path, name) = split_parent(path);
+        if name.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot delete the root key of a hive",
+            ));
+        }
+        self.writable()?;
+        let parent = self.open_key(parent_path)?;
+        let key = self
+            .find_subkey(&parent, name)?
+            .ok_or_else(|| not_found(path))?;
+        if key.subkey_count > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{path} has subkeys"),
+            ));
+        }
+
+        let subkeys: Vec<u32> = self
+            .subkey_offsets(&parent)?
+            .into_iter()
+            .filter(|&o| o != key.offset)
+            .collect();
+        self.write_subkey_index(&parent, &subkeys)?;
+        self.touch(parent.offset);
+
+        for vk in self.value_offsets(&key)? {
+            self.free_value(vk)?;
+        }
+        if key.value_count > 0 {
+            self.free_cell(key.value_list)?;
+        }
+        self.free_cell(key.class_name)?;
+        self.release_security(key.security)?;
+        self.free_cell(key.offset)
+    }
+
+    pub fn set_value(&mut self, path: &str, name: &str, value: &RawValue) -> io::Result<()> {
+        self.writable()?;
+        let key = self.open_key(path)?;
+        let existing = self.find_value(&key, name)?;
+        let (size, data) = self.write_data(&value.bytes)?;
+        match existing {
+            Some(vk) => {
+                let pos = Self::data_pos(vk);
+                self.free_data(self.u32_at(pos + 4)?, self.u32_at(pos + 8)?)?;
+                self.set_u32(pos + 4, size);
+                self.set_u32(pos + 8, data);
+                self.set_u32(pos + 12, value.vtype);
+            }
+            None => {
+                let (name_bytes, compressed) = encode_name(name);
+                let flags = if compressed { VALUE_COMP_NAME } else { 0 };
+                let mut vk = b"vk".to_vec();
+                vk.extend((name_bytes.len() as u16).to_le_bytes());
+                vk.extend(size.to_le_bytes());
+                vk.extend(data.to_le_bytes());
+                vk.extend(value.vtype.to_le_bytes());
+                vk.extend(flags.to_le_bytes());
+                vk.extend(0u16.to_le_bytes());
+                vk.extend(name_bytes);
+                let vk = self.alloc_with(&vk)?;
+
+                let mut values = self.value_offsets(&key)?;
+                values.push(vk);
+                self.write_value_list(&key, &values)?;
+            }
+        }
+
+        let pos = Self::data_pos(key.offset);
+        self.raise(pos + 60, name.encode_utf16().count() as u32 * 2)?;
+        self.raise(pos + 64, value.bytes.len() as u32)?;
+        self.touch(key.offset);
+        Ok(())
+    }
+
+    pub fn delete_value(&mut self, path: &str, name: &str) -> io::Result<()> {
+        self.writable()?;
+        let key = self.open_key(path)?;
+        let vk = self
+            .find_value(&key, name)?
+            .ok_or_else(|| not_found(&format!("{path}\\{name}")))?;
+        let values: Vec<u32> = self
+            .value_offsets(&key)?
+            .into_iter()
+            .filter(|&o| o != vk)
+            .collect();
+        self.write_value_list(&key, &values)?;
+        self.free_value(vk)?;
+        self.touch(key.offset);
+        Ok(())
+    }
+
+    /// Writes the hive back to `path` with fresh sequence numbers and base
+    /// block checksum. The file is replaced in one rename, so a crash leaves
+    /// the old or the new hive but never half of one.
+    pub fn save(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(self.seal())?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(tmp, path)?;
+        self.modified = false;
+        Ok(())
+    }
+
+    /// Finishes the base block the way a clean flush does and returns the
+    /// complete file contents.
+    pub fn seal(&mut self) -> &[u8] {
+        let sequence = u32::from_le_bytes([self.data[4], self.data[5], self.data[6], self.data[7]])
+            .wrapping_add(1);
+        self.set_u32(4, sequence);
+        self.set_u32(8, sequence);
+        self.set_bytes(0x0C, &filetime_now().to_le_bytes());
+        let end = BASE_BLOCK_SIZE + self.u32_at(0x28).unwrap_or_default() as usize;
+        self.data.truncate(end.max(BASE_BLOCK_SIZE));
+        let checksum = self.checksum();
+        self.set_u32(0x1FC, checksum);
+        &self.data
+    }
+
+    fn writable(&mut self) -> io::Result<()> {
+        if self.is_dirty() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "hive has pending transaction log changes, mount and unload it on Windows first",
+            ));
+        }
+        self.modified = true;
+        Ok(())
+    }
+
+    /// File position of the data of the cell at `offset`.
+    fn data_pos(offset: u32) -> usize {
+        Self::cell_pos(offset) + 4
+    }
+
+    fn set_bytes(&mut self, pos: usize, bytes: &[u8]) {
+        self.data[pos..pos + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn set_u32(&mut self, pos: usize, value: u32) {
+        self.set_bytes(pos, &value.to_le_bytes());
+    }
+
+    /// Raises the dword at `pos` to at least `value`.
+    fn raise(&mut self, pos: usize, value: u32) -> io::Result<()> {
+        if self.u32_at(pos)? < value {
+            self.set_u32(pos, value);
+        }
+        Ok(())
+    }
+
+    fn touch(&mut self, key: u32) {
+        self.set_bytes(Self::data_pos(key) + 4, &filetime_now().to_le_bytes());
+    }
+
+    fn scan_free_cells(&self) -> io::Result<Vec<(u32, u32)>> {
+        let end = self.u32_at(0x28)? as usize;
+        let mut free = vec![];
+        let mut bin = 0;
+        while bin < end {
+            let pos = BASE_BLOCK_SIZE + bin;
+            if self.bytes_at(pos, 4)? != b"hbin" {
+                return Err(corrupt(format!("missing hive bin at {bin:#x}")));
+            }
+            let size = self.u32_at(pos + 8)? as usize;
+            if size < 4096 || !size.is_multiple_of(4096) {
+                return Err(corrupt(format!("hive bin {bin:#x} has a bad size")));
+            }
+            let mut cell = bin + 32;
+            while cell < bin + size {
+                let cell_size = self.u32_at(BASE_BLOCK_SIZE + cell)? as i32;
+                let len = cell_size.unsigned_abs() as usize;
+                if len < 8 || cell + len > bin + size {
+                    return Err(corrupt(format!("cell {cell:#x} has a bad size")));
+                }
+                if cell_size > 0 {
+                    free.push((cell as u32, len as u32));
+                }
+                cell += len;
+            }
+            bin += size;
+        }
+        Ok(free)
+    }
+
+    fn free_list(&mut self) -> io::Result<&mut Vec<(u32, u32)>> {
+        if self.free.is_none() {
+            self.free = Some(self.scan_free_cells()?);
+        }
+        Ok(self.free.get_or_insert_default())
+    }
+
+    /// Allocates a zeroed cell with room for `len` bytes of data.
+    fn alloc(&mut self, len: usize) -> io::Result<u32> {
+        let need = ((len + 4).div_ceil(8) * 8) as u32;
+        let free = self.free_list()?;
+        let (offset, size) = match free.iter().position(|&(_, size)| size >= need) {
+            Some(i) => free.swap_remove(i),
+            None => self.add_bin(need)?,
+        };
+        let size = if size - need >= 8 {
+            self.release(offset + need, size - need)?;
+            need
+        } else {
+            size
+        };
+        let pos = Self::cell_pos(offset);
+        self.set_u32(pos, (size as i32).wrapping_neg() as u32);
+        self.data[pos + 4..pos + size as usize].fill(0);
+        Ok(offset)
+    }
+
+    fn alloc_with(&mut self, data: &[u8]) -> io::Result<u32> {
+        let offset = self.alloc(data.len())?;
+        self.set_bytes(Self::data_pos(offset), data);
+        Ok(offset)
+    }
+
+    /// Appends a hive bin with room for a cell of `need` bytes and returns
+    /// the free space it holds.
+    fn add_bin(&mut self, need: u32) -> io::Result<(u32, u32)> {
+        let offset = self.u32_at(0x28)?;
+        let size = (need as usize + 32).div_ceil(4096) * 4096;
+        let pos = BASE_BLOCK_SIZE + offset as usize;
+        self.data.truncate(pos);
+        self.data.resize(pos + size, 0);
+        self.set_bytes(pos, b"hbin");
+        self.set_u32(pos + 4, offset);
+        self.set_u32(pos + 8, size as u32);
+        self.set_bytes(pos + 0x14, &filetime_now().to_le_bytes());
+        self.set_u32(0x28, offset + size as u32);
+        Ok((offset + 32, size as u32 - 32))
+    }
+
+    /// Marks `offset..offset + size` as a free cell.
+    fn release(&mut self, offset: u32, size: u32) -> io::Result<()> {
+        let pos = Self::cell_pos(offset);
+        self.set_u32(pos, size);
+        self.data[pos + 4..pos + size as usize].fill(0);
+        self.free_list()?.push((offset, size));
+        Ok(())
+    }
+
+    /// Frees an allocated cell, merging it with free neighbours in its bin.
+    fn free_cell(&mut self, offset: u32) -> io::Result<()> {
+        if offset == NO_CELL {
+            return Ok(());
+        }
+        let mut size = self.cell(offset)?.len() as u32 + 4;
+        let mut start = offset;
+        let free = self.free_list()?;
+        if let Some(i) = free.iter().position(|&(o, _)| o == offset + size) {
+            size += free.swap_remove(i).1;
+        }
+        if let Some(i) = free.iter().position(|&(o, s)| o + s == offset) {
+            let (o, s) = free.swap_remove(i);
+            start = o;
+            size += s;
+        }
+        self.release(start, size)
+    }
+
+    fn new_key(&mut self, parent: &KeyNode, name: &str) -> io::Result<u32> {
+        if name.chars().count() > MAX_KEY_NAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("key name {name} is too long"),
+            ));
+        }
+        let (name_bytes, compressed) = encode_name(name);
+        let flags = if compressed { KEY_COMP_NAME } else { 0 };
+        let mut nk = vec![0u8; KEY_NODE_SIZE];
+        nk[..2].copy_from_slice(b"nk");
+        nk[2..4].copy_from_slice(&flags.to_le_bytes());
+        nk[4..12].copy_from_slice(&filetime_now().to_le_bytes());
+        nk[16..20].copy_from_slice(&parent.offset.to_le_bytes());
+        for pos in [28, 32, 40, 48] {
+            nk[pos..pos + 4].copy_from_slice(&NO_CELL.to_le_bytes());
+        }
+        nk[44..48].copy_from_slice(&parent.security.to_le_bytes());
+        nk[72..74].copy_from_slice(&(name_bytes.len() as u16).to_le_bytes());
+        nk.extend(name_bytes);
+        self.retain_security(parent.security)?;
+        self.alloc_with(&nk)
+    }
+
+    fn security_links(&self, sk: u32) -> io::Result<(u32, u32, u32)> {
+        let cell = self.cell(sk)?;
+        if cell.len() < 16 || &cell[..2] != b"sk" {
+            return Err(corrupt(format!("cell {sk:#x} is not a security cell")));
+        }
+        let u32_at = |pos: usize| {
+            u32::from_le_bytes([cell[pos], cell[pos + 1], cell[pos + 2], cell[pos + 3]])
+        };
+        Ok((u32_at(4), u32_at(8), u32_at(12)))
+    }
+
+    fn retain_security(&mut self, sk: u32) -> io::Result<()> {
+        if sk == NO_CELL {
+            return Ok(());
+        }
+        let (.., refs) = self.security_links(sk)?;
+        self.set_u32(Self::data_pos(sk) + 12, refs + 1);
+        Ok(())
+    }
+
+    fn release_security(&mut self, sk: u32) -> io::Result<()> {
+        if sk == NO_CELL {
+            return Ok(());
+        }
+        let (flink, blink, refs) = self.security_links(sk)?;
+        if refs > 1 {
+            self.set_u32(Self::data_pos(sk) + 12, refs - 1);
+            return Ok(());
+        }
+        if flink != sk {
+            self.set_u32(Self::data_pos(blink) + 4, flink);
+            self.set_u32(Self::data_pos(flink) + 8, blink);
+        }
+        self.free_cell(sk)
+    }
+
+    /// Replaces the subkey index of `key` with a sorted one over `subkeys`.
+    fn write_subkey_index(&mut self, key: &KeyNode, subkeys: &[u32]) -> io::Result<()> {
+        let mut entries = subkeys
+            .iter()
+            .map(|&o| {
+                let name = self.key(o)?.name;
+                Ok((upcase(&name), name_hash(&name), o))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+
+        let mut leaves = vec![];
+        for chunk in entries.chunks(MAX_LEAF_ENTRIES) {
+            let mut lh = b"lh".to_vec();
+            lh.extend((chunk.len() as u16).to_le_bytes());
+            for (_, hash, offset) in chunk {
+                lh.extend(offset.to_le_bytes());
+                lh.extend(hash.to_le_bytes());
+            }
+            leaves.push(self.alloc_with(&lh)?);
+        }
+        let list = match leaves.len() {
+            0 => NO_CELL,
+            1 => leaves[0],
+            n => {
+                let mut ri = b"ri".to_vec();
+                ri.extend((n as u16).to_le_bytes());
+                leaves.iter().for_each(|o| ri.extend(o.to_le_bytes()));
+                self.alloc_with(&ri)?
+            }
+        };
+
+        if key.subkey_count > 0 {
+            self.free_index(key.subkey_list)?;
+        }
+        let pos = Self::data_pos(key.offset);
+        self.set_u32(pos + 20, entries.len() as u32);
+        self.set_u32(pos + 28, list);
+        Ok(())
+    }
+
+    fn free_index(&mut self, list: u32) -> io::Result<()> {
+        if list == NO_CELL {
+            return Ok(());
+        }
+        let cell = self.cell(list)?;
+        if cell.starts_with(b"ri") {
+            let count = u16::from_le_bytes([cell[2], cell[3]]) as usize;
+            let leaves: Vec<u32> = cell
+                .get(4..4 + count * 4)
+                .ok_or_else(|| corrupt(format!("index cell {list:#x} is truncated")))?
+                .chunks_exact(4)
+                .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                .collect();
+            for leaf in leaves {
+                self.free_cell(leaf)?;
+            }
+        }
+        self.free_cell(list)
+    }
+
+    fn find_value(&self, key: &KeyNode, name: &str) -> io::Result<Option<u32>> {
+        for offset in self.value_offsets(key)? {
+            if names_equal(&self.value_name(offset)?, name) {
+                return Ok(Some(offset));
+            }
+        }
+        Ok(None)
+    }
+
+    fn write_value_list(&mut self, key: &KeyNode, values: &[u32]) -> io::Result<()> {
+        let list = if values.is_empty() {
+            NO_CELL
+        } else {
+            let bytes: Vec<u8> = values.iter().flat_map(|o| o.to_le_bytes()).collect();
+            self.alloc_with(&bytes)?
+        };
+        if key.value_count > 0 {
+            self.free_cell(key.value_list)?;
+        }
+        let pos = Self::data_pos(key.offset);
+        self.set_u32(pos + 36, values.len() as u32);
+        self.set_u32(pos + 40, list);
+        Ok(())
+    }
+
+    /// Stores value data and returns the size and offset fields of its `vk`.
+    fn write_data(&mut self, bytes: &[u8]) -> io::Result<(u32, u32)> {
+        let len = bytes.len() as u32;
+        if bytes.len() <= 4 {
+            let mut inline = [0u8; 4];
+            inline[..bytes.len()].copy_from_slice(bytes);
+            return Ok((len | DATA_INLINE, u32::from_le_bytes(inline)));
+        }
+        if bytes.len() > BIG_DATA_SEGMENT_SIZE && self.minor_version() >= 4 {
+            let mut segments = vec![];
+            for chunk in bytes.chunks(BIG_DATA_SEGMENT_SIZE) {
+                segments.extend(self.alloc_with(chunk)?.to_le_bytes());
+            }
+            let count = (segments.len() / 4) as u16;
+            let list = self.alloc_with(&segments)?;
+            let mut db = b"db".to_vec();
+            db.extend(count.to_le_bytes());
+            db.extend(list.to_le_bytes());
+            db.extend(0u32.to_le_bytes());
+            return Ok((len, self.alloc_with(&db)?));
+        }
+        Ok((len, self.alloc_with(bytes)?))
+    }
+
+    fn free_data(&mut self, size: u32, offset: u32) -> io::Result<()> {
+        if size & DATA_INLINE != 0 || size == 0 {
+            return Ok(());
+        }
+        let cell = self.cell(offset)?;
+        if size as usize > BIG_DATA_SEGMENT_SIZE
+            && self.minor_version() >= 4
+            && cell.starts_with(b"db")
+            && cell.len() >= 8
+        {
+            let count = u16::from_le_bytes([cell[2], cell[3]]) as usize;
+            let list = u32::from_le_bytes([cell[4], cell[5], cell[6], cell[7]]);
+            let segments: Vec<u32> = self
+                .cell(list)?
+                .get(..count * 4)
+                .ok_or_else(|| corrupt(format!("segment list {list:#x} is truncated")))?
+                .chunks_exact(4)
+                .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                .collect();
+            for segment in segments {
+                self.free_cell(segment)?;
+            }
+            self.free_cell(list)?;
+        }
+        self.free_cell(offset)
+    }
+
+    fn free_value(&mut self, vk: u32) -> io::Result<()> {
+        let pos = Self::data_pos(vk);
+        self.free_data(self.u32_at(pos + 4)?, self.u32_at(pos + 8)?)?;
+        self.free_cell(vk)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::Hive;
+    use crate::RawValue;
+    use crate::vtype::*;
+
+    fn cell(data: &[u8]) -> Vec<u8> {
+        let size = (data.len() + 4).div_ceil(8) * 8;
+        let mut out = (-(size as i32)).to_le_bytes().to_vec();
+        out.extend_from_slice(data);
+        out.resize(size, 0);
+        out
+    }
+
+    fn nk(name: &str, subkeys: (u32, u32), values: (u32, u32)) -> Vec<u8> {
+        let mut d = b"nk".to_vec();
+        d.extend(0x0020u16.to_le_bytes());
+        d.extend([0u8; 8]);
+        d.extend(0u32.to_le_bytes());
+        d.extend(0u32.to_le_bytes());
+        d.extend(subkeys.0.to_le_bytes());
+        d.extend(0u32.to_le_bytes());
+        d.extend(subkeys.1.to_le_bytes());
+        d.extend(u32::MAX.to_le_bytes());
+        d.extend(values.0.to_le_bytes());
+        d.extend(values.1.to_le_bytes());
+        d.extend([0xFFu8; 8]);
+        d.extend([0u8; 20]);
+        d.extend((name.len() as u16).to_le_bytes());
+        d.extend(0u16.to_le_bytes());
+        d.extend(name.as_bytes());
+        cell(&d)
+    }
+
+    /// A hand-assembled hive with `Shell\Open` holding `@="Open"` and
+    /// `Flags=dword:2`.
+    pub(crate) fn sample_hive() -> Vec<u8> {
+        let mut bin = vec![];
+        let mut push = |c: Vec<u8>| {
+            let offset = 32 + bin.len() as u32;
+            bin.extend(c);
+            offset
+        };
+        let open_sz: Vec<u8> = "Open\0".encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let data = push(cell(&open_sz));
+        let mut vk_default = b"vk".to_vec();
+        vk_default.extend(0u16.to_le_bytes());
+        vk_default.extend((open_sz.len() as u32).to_le_bytes());
+        vk_default.extend(data.to_le_bytes());
+        vk_default.extend(REG_SZ.to_le_bytes());
+        vk_default.extend([1, 0, 0, 0]);
+        let vk_default = push(cell(&vk_default));
+        let mut vk_flags = b"vk".to_vec();
+        vk_flags.extend(5u16.to_le_bytes());
+        vk_flags.extend((0x8000_0004u32).to_le_bytes());
+        vk_flags.extend(2u32.to_le_bytes());
+        vk_flags.extend(REG_DWORD.to_le_bytes());
+        vk_flags.extend([1, 0, 0, 0]);
+        vk_flags.extend(b"Flags");
+        let vk_flags = push(cell(&vk_flags));
+        let mut values = vk_default.to_le_bytes().to_vec();
+        values.extend(vk_flags.to_le_bytes());
+        let values = push(cell(&values));
+        let open = push(nk("Open", (0, u32::MAX), (2, values)));
+        let mut lf = b"lf".to_vec();
+        lf.extend(1u16.to_le_bytes());
+        lf.extend(open.to_le_bytes());
+        lf.extend(b"Open");
+        let lf = push(cell(&lf));
+        let shell = push(nk("Shell", (1, lf), (0, u32::MAX)));
+        let mut li = b"li".to_vec();
+        li.extend(1u16.to_le_bytes());
+        li.extend(shell.to_le_bytes());
+        let li = push(cell(&li));
+        let root = push(nk("ROOT", (1, li), (0, u32::MAX)));
+
+        let bin_size = (32 + bin.len()).div_ceil(4096) * 4096;
+        let free = bin_size - 32 - bin.len();
+        bin.extend((free as i32).to_le_bytes());
+        bin.resize(bin_size - 32, 0);
+
+        let mut data = vec![0u8; 4096];
+        data[..4].copy_from_slice(b"regf");
+        data[4..8].copy_from_slice(&1u32.to_le_bytes());
+        data[8..12].copy_from_slice(&1u32.to_le_bytes());
+        data[0x14..0x18].copy_from_slice(&1u32.to_le_bytes());
+        data[0x18..0x1C].copy_from_slice(&5u32.to_le_bytes());
+        data[0x20..0x24].copy_from_slice(&1u32.to_le_bytes());
+        data[0x24..0x28].copy_from_slice(&root.to_le_bytes());
+        data[0x28..0x2C].copy_from_slice(&(bin_size as u32).to_le_bytes());
+        data[0x2C..0x30].copy_from_slice(&1u32.to_le_bytes());
+        let mut hbin = b"hbin".to_vec();
+        hbin.extend(0u32.to_le_bytes());
+        hbin.extend((bin_size as u32).to_le_bytes());
+        hbin.resize(32, 0);
+        data.extend(hbin);
+        data.extend(bin);
+        let checksum = super::checksum(&data);
+        data[0x1FC..0x200].copy_from_slice(&checksum.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_read_sample_hive() {
+        let hive = Hive::from_bytes(sample_hive()).unwrap();
+        assert!(!hive.is_dirty());
+        assert_eq!(hive.subkey_names("").unwrap(), ["Shell"]);
+        assert_eq!(hive.subkey_names("shell").unwrap(), ["Open"]);
+
+        let values = hive.values(r"SHELL\open").unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[0].0, "");
+        assert_eq!(values[0].1.vtype, REG_SZ);
+        assert_eq!(values[1].0, "Flags");
+        assert_eq!(values[1].1.bytes, 2u32.to_le_bytes());
+
+        assert!(hive.values(r"Shell\Missing").is_err());
+    }
+
+    #[test]
+    fn test_reject_bad_checksum() {
+        let mut data = sample_hive();
+        data[0x1FC] ^= 1;
+        assert!(Hive::from_bytes(data).is_err());
+    }
+
+    #[test]
+    fn test_write_keys_and_values() {
+        let mut hive = Hive::from_bytes(sample_hive()).unwrap();
+        hive.create_key(r"Shell\Edit\command").unwrap();
+        let edit = RawValue {
+            vtype: REG_SZ,
+            bytes: "Edit\0".encode_utf16().flat_map(u16::to_le_bytes).collect(),
+        };
+        hive.set_value(r"Shell\Edit", "", &edit).unwrap();
+        let blob = RawValue {
+            vtype: REG_BINARY,
+            bytes: (0..40000u32).map(|i| i as u8).collect(),
+        };
+        hive.set_value(r"Shell\Open", "Blob", &blob).unwrap();
+        let flags = RawValue {
+            vtype: REG_DWORD,
+            bytes: 7u32.to_le_bytes().to_vec(),
+        };
+        hive.set_value(r"Shell\Open", "flags", &flags).unwrap();
+        assert!(hive.is_modified());
+
+        let mut hive = Hive::from_bytes(hive.seal().to_vec()).unwrap();
+        assert!(!hive.is_dirty());
+        assert_eq!(hive.subkey_names("Shell").unwrap(), ["Edit", "Open"]);
+        assert_eq!(hive.values(r"Shell\Edit").unwrap(), [(String::new(), edit)]);
+        let values = hive.values(r"Shell\Open").unwrap();
+        assert_eq!(values[1], ("Flags".to_string(), flags));
+        assert_eq!(values[2], ("Blob".to_string(), blob));
+
+        assert!(hive.delete_key(r"Shell\Edit").is_err());
+        hive.delete_key(r"Shell\Edit\Command").unwrap();
+        hive.delete_key(r"Shell\Edit").unwrap();
+        hive.delete_value(r"Shell\Open", "BLOB").unwrap();
+        assert!(hive.delete_value(r"Shell\Open", "Blob").is_err());
+
+        let hive = Hive::from_bytes(hive.seal().to_vec()).unwrap();
+        assert_eq!(hive.subkey_names("Shell").unwrap(), ["Open"]);
+        assert_eq!(hive.values(r"Shell\Open").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_large_subkey_index() {
+        let mut hive = Hive::from_bytes(sample_hive()).unwrap();
+        for i in 0..600 {
+            hive.create_key(&format!(r"Shell\Verb{i:03}")).unwrap();
+        }
+        let names = hive.subkey_names("Shell").unwrap();
+        assert_eq!(names.len(), 601);
+        assert_eq!(names[0], "Open");
+        assert_eq!(names[600], "Verb599");
+
+        for i in 0..600 {
+            hive.delete_key(&format!(r"Shell\Verb{i:03}")).unwrap();
+        }
+        assert_eq!(hive.subkey_names("Shell").unwrap(), ["Open"]);
+        let mut free = hive.free.clone().unwrap();
+        free.sort();
+        assert_eq!(hive.scan_free_cells().unwrap(), free);
+    }
+
+    #[test]
+    fn test_refuse_dirty_hive() {
+        let mut data = sample_hive();
+        data[4] = 2;
+        let checksum = super::checksum(&data);
+        data[0x1FC..0x200].copy_from_slice(&checksum.to_le_bytes());
+        let mut hive = Hive::from_bytes(data).unwrap();
+        assert!(hive.create_key("Shell").is_err());
+    }
+}
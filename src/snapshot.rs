@@ -0,0 +1,315 @@
+use crate::APP_NAME;
+use crate::CLSID_PATH;
+use crate::RegItem;
+use crate::RegOp;
+use crate::RegistryBackend;
+use crate::SceneRoot;
+use crate::SceneType;
+use crate::apply_ops;
+use crate::backend::strip_path_prefix;
+use crate::{diff_items, ops_from_changes};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use strum::IntoEnumIterator;
+
+/// One key a snapshot covers, `item` is `None` when it didn't exist.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SnapshotKey {
+    pub root: SceneRoot,
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub item: Option<RegItem>,
+}
+
+/// The context menu state of a machine: every scene root, the blocked
+/// shell extensions of both scopes and the classic menu switch.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Snapshot {
+    /// UTC creation time as `YYYYMMDD-HHMMSS`, also the file name.
+    pub id: String,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    pub keys: Vec<SnapshotKey>,
+}
+
+/// The keys a snapshot covers.
+fn tracked_keys() -> Vec<(SceneRoot, &'static str)> {
+    let mut keys: Vec<(SceneRoot, &str)> = vec![];
+    for scene_type in SceneType::iter() {
+        keys.extend(scene_type.registry_path().iter().copied());
+    }
+    keys.extend([
+        (SceneRoot::HKCU, crate::win11::REG_KEY),
+        (SceneRoot::HKLM, crate::win11::REG_KEY),
+        (SceneRoot::HKCU, CLSID_PATH),
+    ]);
+    let mut unique: Vec<(SceneRoot, &str)> = vec![];
+    for (root, path) in keys {
+        if !unique
+            .iter()
+            .any(|(r, p)| *r == root && p.eq_ignore_ascii_case(path))
+        {
+            unique.push((root, path));
+        }
+    }
+    unique
+}
+
+pub(crate) fn read_key(
+    backend: &dyn RegistryBackend,
+    root: SceneRoot,
+    path: &str,
+) -> io::Result<Option<RegItem>> {
+    match RegItem::from_path_in(backend, root, path) {
+        Ok(item) => Ok(Some(item)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl SnapshotKey {
+    /// Reads the key and its subkeys from `backend`.
+    pub fn capture(backend: &dyn RegistryBackend, root: SceneRoot, path: &str) -> io::Result<Self> {
+        Ok(SnapshotKey {
+            root,
+            path: path.to_string(),
+            item: read_key(backend, root, path)?,
+        })
+    }
+
+    /// The changes that return the key in `backend` to this state.
+    pub fn plan_restore(&self, backend: &dyn RegistryBackend) -> io::Result<Vec<RegOp>> {
+        let current = read_key(backend, self.root, &self.path)?;
+        let changes = diff_items(current.as_slice(), self.item.as_slice());
+        Ok(ops_from_changes(&changes))
+    }
+}
+
+impl Snapshot {
+    /// Reads the current state from `backend`.
+    pub fn capture(backend: &dyn RegistryBackend) -> io::Result<Snapshot> {
+        let mut keys = vec![];
+        for (root, path) in tracked_keys() {
+            keys.push(SnapshotKey::capture(backend, root, path)?);
+        }
+        let created = unix_now();
+        Ok(Snapshot {
+            id: timestamp(created),
+            created,
+            keys,
+        })
+    }
+
+    /// The changes that return `backend` to this snapshot.
+    pub fn plan_restore(&self, backend: &dyn RegistryBackend) -> io::Result<Vec<RegOp>> {
+        let mut ops = vec![];
+        for key in &self.keys {
+            ops.extend(key.plan_restore(backend)?);
+        }
+        Ok(ops)
+    }
+
+    /// Whether the key at `path` is one of the snapshot's keys or below one.
+    pub fn covers(&self, root: SceneRoot, path: &str) -> bool {
+        self.keys
+            .iter()
+            .any(|k| k.root == root && strip_path_prefix(path, &k.path).is_some())
+    }
+
+    pub fn restore(&self, backend: &dyn RegistryBackend) -> anyhow::Result<()> {
+        // the snapshot only holds keys wcm tracks, some outside the scene roots
+        apply_ops(backend, &self.plan_restore(backend)?, true)
+    }
+}
+
+/// Seconds since the Unix epoch.
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Formats seconds since the Unix epoch as a UTC `YYYYMMDD-HHMMSS`.
+pub fn timestamp(secs: u64) -> String {
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+/// A directory of snapshot files named after their ids.
+#[derive(Debug, Clone)]
+pub struct SnapshotStore {
+    dir: PathBuf,
+}
+
+impl SnapshotStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        SnapshotStore { dir: dir.into() }
+    }
+
+    /// `snapshots` in the wcm config directory.
+    pub fn open_default() -> Option<Self> {
+        let dir = dirs::config_local_dir()?.join(APP_NAME).join("snapshots");
+        Some(SnapshotStore::new(dir))
+    }
+
+    fn file(&self, id: &str) -> io::Result<PathBuf> {
+        if id.is_empty() || id.contains(['/', '\\', '.']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid snapshot id {id:?}"),
+            ));
+        }
+        Ok(self.dir.join(format!("{id}.json")))
+    }
+
+    /// Writes a new snapshot file, adding a suffix to the id of `snapshot`
+    /// if one with the same id exists.
+    pub fn save(&self, snapshot: &mut Snapshot) -> io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let base = snapshot.id.clone();
+        let mut n = 0;
+        let mut file = loop {
+            let open = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(self.file(&snapshot.id)?);
+            match open {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    n += 1;
+                    snapshot.id = format!("{base}-{n}");
+                }
+                open => break open?,
+            }
+        };
+        let json = serde_json::to_string_pretty(&*snapshot)?;
+        file.write_all(json.as_bytes())
+    }
+
+    pub fn load(&self, id: &str) -> io::Result<Snapshot> {
+        let s = std::fs::read_to_string(self.file(id)?)?;
+        Ok(serde_json::from_str(&s)?)
+    }
+
+    /// Every stored snapshot, oldest first.
+    pub fn list(&self) -> io::Result<Vec<Snapshot>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut snapshots = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if let Some(id) = path.file_stem().and_then(|s| s.to_str())
+                && path.extension().is_some_and(|e| e == "json")
+            {
+                snapshots.push(self.load(id)?);
+            }
+        }
+        snapshots.sort_by_key(|s| (s.created, s.id.clone()));
+        Ok(snapshots)
+    }
+
+    pub fn delete(&self, id: &str) -> io::Result<()> {
+        std::fs::remove_file(self.file(id)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Snapshot, SnapshotStore, timestamp};
+    use crate::test_support::TempDir;
+    use crate::{
+        CLSID_PATH, MemoryBackend, RegItemValue, RegistryBackend, SceneRoot,
+        set_context_menu_style_in,
+    };
+
+    const BLOCKED: &str = r"Software\Microsoft\Windows\CurrentVersion\Shell Extensions\Blocked";
+
+    #[test]
+    fn test_capture_and_restore() {
+        let reg = MemoryBackend::new();
+        let wt = r"Directory\Background\Shell\wt";
+        reg.create_key(SceneRoot::HKCR, &format!(r"{wt}\command"))
+            .unwrap();
+        let title = RegItemValue::SZ("Open in Terminal".to_string()).to_raw();
+        reg.set_value(SceneRoot::HKCR, wt, "", &title).unwrap();
+        reg.create_key(SceneRoot::HKCU, BLOCKED).unwrap();
+        let empty = RegItemValue::SZ(String::new()).to_raw();
+        reg.set_value(SceneRoot::HKCU, BLOCKED, "{1234}", &empty)
+            .unwrap();
+        let snapshot = Snapshot::capture(&reg).unwrap();
+        assert!(snapshot.plan_restore(&reg).unwrap().is_empty());
+
+        reg.delete_tree(SceneRoot::HKCR, wt).unwrap();
+        reg.create_key(SceneRoot::HKCR, r"Drive\Shell\format")
+            .unwrap();
+        reg.delete_value(SceneRoot::HKCU, BLOCKED, "{1234}")
+            .unwrap();
+        reg.create_key(SceneRoot::HKLM, BLOCKED).unwrap();
+        reg.set_value(SceneRoot::HKLM, BLOCKED, "{5678}", &empty)
+            .unwrap();
+        set_context_menu_style_in(&reg, false).unwrap();
+
+        let ops = snapshot.plan_restore(&reg).unwrap();
+        assert_eq!(
+            ops.iter().map(|op| op.to_string()).collect::<Vec<_>>(),
+            [
+                r"+ [HKEY_CLASSES_ROOT\Directory\Background\Shell\wt]",
+                r"~ [HKEY_CLASSES_ROOT\Directory\Background\Shell\wt] @ = Open in Terminal",
+                r"+ [HKEY_CLASSES_ROOT\Directory\Background\Shell\wt\command]",
+                r"- [HKEY_CLASSES_ROOT\Drive\Shell]",
+                r#"~ [HKEY_CURRENT_USER\Software\Microsoft\Windows\CurrentVersion\Shell Extensions\Blocked] "{1234}" = "#,
+                r"- [HKEY_LOCAL_MACHINE\Software\Microsoft\Windows\CurrentVersion\Shell Extensions\Blocked]",
+                &format!(r"- [HKEY_CURRENT_USER\{CLSID_PATH}]"),
+            ]
+        );
+
+        snapshot.restore(&reg).unwrap();
+        assert!(snapshot.plan_restore(&reg).unwrap().is_empty());
+        assert_eq!(Snapshot::capture(&reg).unwrap().keys, snapshot.keys);
+    }
+
+    #[test]
+    fn test_store() {
+        let dir = TempDir::new("snapshots");
+        let store = SnapshotStore::new(dir.path());
+        assert!(store.list().unwrap().is_empty());
+
+        let mut first = Snapshot::capture(&MemoryBackend::new()).unwrap();
+        let mut second = first.clone();
+        store.save(&mut first).unwrap();
+        store.save(&mut second).unwrap();
+        assert_eq!(second.id, format!("{}-1", first.id));
+        assert_eq!(store.list().unwrap(), [first.clone(), second.clone()]);
+        assert_eq!(store.load(&second.id).unwrap(), second);
+
+        store.delete(&first.id).unwrap();
+        assert_eq!(store.list().unwrap(), [second]);
+        assert!(store.load("../backup").is_err());
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(0), "19700101-000000");
+        assert_eq!(timestamp(1_709_210_096), "20240229-123456");
+    }
+}
@@ -0,0 +1,322 @@
+use crate::Backup;
+use crate::BackupStore;
+use crate::MenuItem;
+use crate::ProfileItem;
+use crate::RegOp;
+use crate::RegistryBackend;
+use crate::Scope;
+use crate::Transaction;
+use crate::Type;
+use crate::plan_context_menu_style_in;
+use crate::profile::list_all_in;
+use crate::set_context_menu_style_in;
+use crate::transaction::{capture, rollback};
+use anyhow::{Context, bail};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Which items a [`Rule`] covers. Every matcher given has to match.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub enabled: bool,
+    /// Only items of this type.
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub ty: Option<Type>,
+    /// The `Blocked` list Win11 items are matched in, `User` when not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<Scope>,
+    /// The id `wcm win10 list` or `wcm win11 list` shows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The shell extension behind the item, with or without braces.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clsid: Option<String>,
+    /// The name shown in the menu, where `*` and `?` stand for any text and
+    /// any character.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publisher: Option<String>,
+    /// The package family name of a packaged extension.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub family: Option<String>,
+}
+
+impl Rule {
+    fn has_matcher(&self) -> bool {
+        self.id.is_some()
+            || self.clsid.is_some()
+            || self.name.is_some()
+            || self.publisher.is_some()
+            || self.family.is_some()
+    }
+
+    fn matches(&self, ty: Type, scope: Option<Scope>, item: &MenuItem) -> anyhow::Result<bool> {
+        if self.ty.is_some_and(|t| t != ty)
+            || (ty == Type::Win11 && scope != Some(self.scope.unwrap_or_default()))
+        {
+            return Ok(false);
+        }
+        let info = item.info.clone().unwrap_or_default();
+        let trim = |clsid: &str| clsid.trim_matches(['{', '}']).to_ascii_lowercase();
+        if let Some(id) = &self.id
+            && !id.eq_ignore_ascii_case(&item.id)
+        {
+            return Ok(false);
+        }
+        if let Some(clsid) = &self.clsid {
+            let mut clsids: Vec<String> = info.types.iter().map(|t| t.clsid.clone()).collect();
+            clsids.extend(info.reg.as_ref().and_then(|reg| reg.get_guid()));
+            if ty == Type::Win11 {
+                clsids.push(item.id.clone());
+            }
+            if !clsids.iter().any(|c| trim(c) == trim(clsid)) {
+                return Ok(false);
+            }
+        }
+        if let Some(name) = &self.name {
+            let pattern = regex::escape(name).replace(r"\*", ".*").replace(r"\?", ".");
+            let re = Regex::new(&format!("(?i)^{pattern}$"))?;
+            if !re.is_match(&item.name) {
+                return Ok(false);
+            }
+        }
+        if let Some(publisher) = &self.publisher
+            && !publisher.eq_ignore_ascii_case(&info.publisher_display_name)
+        {
+            return Ok(false);
+        }
+        if let Some(family) = &self.family
+            && !family.eq_ignore_ascii_case(&info.family_name)
+        {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+/// The context menu a machine should have, kept in a TOML or JSON file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DesiredState {
+    /// `Win10` for the classic menu, `Win11` for the new one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub menu_style: Option<Type>,
+    #[serde(default, rename = "rule", skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
+}
+
+/// What it takes to bring a machine to a [`DesiredState`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatePlan {
+    /// Items to enable or disable, each with the state it should have.
+    pub items: Vec<ProfileItem>,
+    /// The menu style to switch to, when it isn't the current one.
+    pub menu_style: Option<Type>,
+    /// Positions of the rules that matched no item, starting at 1.
+    pub unmatched: Vec<usize>,
+}
+
+impl StatePlan {
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty() && self.menu_style.is_none()
+    }
+
+    /// The enables and disables of `items`.
+    pub fn transaction(&self) -> Transaction {
+        let mut tx = Transaction::new();
+        for item in &self.items {
+            tx.add(item.batch_item(item.enabled));
+        }
+        tx
+    }
+
+    /// The registry changes of the plan. Win10 items are looked up in
+    /// `backup`, with the current copy of the ones to disable.
+    pub fn ops(
+        &self,
+        backend: &dyn RegistryBackend,
+        backup: &Backup,
+    ) -> anyhow::Result<Vec<RegOp>> {
+        let mut ops = self.transaction().plan_backed_up(backend, backup)?.concat();
+        if let Some(style) = self.menu_style {
+            ops.extend(plan_context_menu_style_in(backend, style == Type::Win11));
+        }
+        Ok(ops)
+    }
+
+    /// Makes the changes of the plan in `backend`, backing up the Win10
+    /// items it disables in `store` first. When the menu style can't be
+    /// switched, the enables and disables are rolled back too.
+    pub fn commit(&self, backend: &dyn RegistryBackend, store: &BackupStore) -> anyhow::Result<()> {
+        let mut captured = vec![];
+        for op in self.ops(backend, &store.load()?)? {
+            capture(backend, &mut captured, op.root(), op.path())?;
+        }
+        self.transaction()
+            .commit_backed_up(backend, store)?
+            .into_result()?;
+        let Some(style) = self.menu_style else {
+            return Ok(());
+        };
+        if let Err(e) = set_context_menu_style_in(backend, style == Type::Win11) {
+            let errors = rollback(backend, &captured);
+            if !errors.is_empty() {
+                bail!("{e}, and rolling back failed: {}", errors.join(", "));
+            }
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+impl DesiredState {
+    pub fn from_toml(s: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(s)?)
+    }
+
+    pub fn from_json(s: &str) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(s)?)
+    }
+
+    /// Reads a `.json` file as JSON and anything else as TOML.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let s = std::fs::read_to_string(path).with_context(|| path.display().to_string())?;
+        let state = if path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+        {
+            DesiredState::from_json(&s)
+        } else {
+            DesiredState::from_toml(&s)
+        };
+        state.with_context(|| path.display().to_string())
+    }
+
+    /// Compares the state with `backend`, where Win10 items only in
+    /// `backup` count as disabled. Rules without a matcher and rules that
+    /// want the same item enabled and disabled are errors.
+    pub fn plan_in(
+        &self,
+        backend: &dyn RegistryBackend,
+        backup: &[MenuItem],
+    ) -> anyhow::Result<StatePlan> {
+        for (n, rule) in self.rules.iter().enumerate() {
+            if !rule.has_matcher() {
+                bail!(
+                    "rule {}: matches every item, give an id, clsid, name, publisher or family",
+                    n + 1
+                );
+            }
+        }
+        let mut plan = StatePlan::default();
+        let mut matched = vec![false; self.rules.len()];
+        for (ty, scope, items) in list_all_in(backend, backup) {
+            for item in &items {
+                // the first matching rule decides, later ones have to agree
+                let mut wanted: Option<(usize, bool)> = None;
+                for (n, rule) in self.rules.iter().enumerate() {
+                    if !rule.matches(ty, scope, item)? {
+                        continue;
+                    }
+                    matched[n] = true;
+                    match wanted {
+                        Some((first, enabled)) if enabled != rule.enabled => {
+                            let item = ProfileItem::new(ty, scope, item);
+                            bail!("rules {} and {} conflict on {item}", first + 1, n + 1);
+                        }
+                        Some(_) => {}
+                        None => wanted = Some((n, rule.enabled)),
+                    }
+                }
+                if let Some((_, enabled)) = wanted
+                    && enabled != item.enabled
+                {
+                    plan.items.push(ProfileItem {
+                        enabled,
+                        ..ProfileItem::new(ty, scope, item)
+                    });
+                }
+            }
+        }
+        plan.unmatched = (1..=self.rules.len()).filter(|n| !matched[n - 1]).collect();
+        if let Some(style) = self.menu_style
+            && !plan_context_menu_style_in(backend, style == Type::Win11).is_empty()
+        {
+            plan.menu_style = Some(style);
+        }
+        Ok(plan)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DesiredState;
+    use crate::test_support::TempDir;
+    use crate::{BackupStore, MemoryBackend, RegistryBackend, SceneRoot, Type};
+
+    #[test]
+    fn test_plan() {
+        let reg = MemoryBackend::new();
+        let (wt, format) = (r"Directory\Background\Shell\wt", r"Drive\Shell\format");
+        reg.add_verb(wt, "Open in Terminal", "wt.exe");
+        reg.add_verb(format, "Format", "format.exe");
+        let backup = Type::Win10.list_in(&reg, None);
+        reg.delete_tree(SceneRoot::HKCR, format).unwrap();
+
+        let state = DesiredState::from_toml(
+            r#"
+            menu_style = "Win10"
+
+            [[rule]]
+            name = "open in *"
+            enabled = false
+
+            [[rule]]
+            id = 'Drive\Shell\format'
+            enabled = true
+
+            [[rule]]
+            family = "Microsoft.WindowsTerminal_8wekyb3d8bbwe"
+            enabled = false
+            "#,
+        )
+        .unwrap();
+        let plan = state.plan_in(&reg, &backup).unwrap();
+        let items: Vec<_> = plan
+            .items
+            .iter()
+            .map(|i| (i.id.as_str(), i.enabled))
+            .collect();
+        assert_eq!(items, [(wt, false), (format, true)]);
+        assert_eq!(plan.menu_style, Some(Type::Win10));
+        assert_eq!(plan.unmatched, [3]);
+
+        let dir = TempDir::new("state");
+        let store = BackupStore::new(dir.join("backup.json"));
+        store
+            .update(|b| b.merge(&backup, crate::snapshot::unix_now()))
+            .unwrap();
+        plan.commit(&reg, &store).unwrap();
+        assert!(crate::get_context_menu_style_in(&reg));
+        assert!(state.plan_in(&reg, &backup).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_invalid() {
+        let reg = MemoryBackend::new();
+        reg.add_verb(r"Drive\Shell\format", "Format", "format.exe");
+        let e = DesiredState::from_toml("[[rule]]\nenabled = true\nlabel = \"x\"\n").unwrap_err();
+        assert!(e.to_string().contains("label"), "{e}");
+        let state = DesiredState::from_json(r#"{"rule": [{"enabled": true}]}"#).unwrap();
+        assert!(state.plan_in(&reg, &[]).is_err());
+        let state = DesiredState::from_json(
+            r#"{"rule": [{"name": "F*", "enabled": true}, {"name": "*t", "enabled": false}]}"#,
+        )
+        .unwrap();
+        let e = state.plan_in(&reg, &[]).unwrap_err();
+        assert!(e.to_string().contains("rules 1 and 2 conflict"), "{e}");
+    }
+}
@@ -0,0 +1,64 @@
+use crate::MenuItem;
+use crate::MenuItemInfo;
+use crate::ProfileItem;
+use crate::RegItem;
+use crate::SceneRoot;
+use crate::Scope;
+use crate::Type;
+use std::path::{Path, PathBuf};
+
+/// A directory of the system temp directory, removed again on drop so
+/// failing tests don't leave it behind.
+pub(crate) struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// `wcm-<name>-<pid>`, emptied first in case an earlier run was killed.
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("wcm-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.path.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// An enabled item named after `id` with an empty key at `root\id`.
+pub(crate) fn menu_item(root: SceneRoot, id: &str) -> MenuItem {
+    MenuItem {
+        id: id.to_string(),
+        name: id.to_string(),
+        enabled: true,
+        info: Some(MenuItemInfo {
+            reg: Some(RegItem::new(root, id)),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// A profile entry without a name or root.
+pub(crate) fn profile_item(ty: Type, scope: Option<Scope>, id: &str, enabled: bool) -> ProfileItem {
+    ProfileItem {
+        ty,
+        scope,
+        id: id.to_string(),
+        name: String::new(),
+        enabled,
+        root: None,
+    }
+}
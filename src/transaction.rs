@@ -0,0 +1,534 @@
+use crate::Backup;
+use crate::BackupStore;
+use crate::MenuItem;
+use crate::RegItem;
+use crate::RegItemValue;
+use crate::RegOp;
+use crate::RegistryBackend;
+use crate::SceneRoot;
+use crate::Scope;
+use crate::SnapshotKey;
+use crate::Type;
+use crate::backend::strip_path_prefix;
+use crate::snapshot::unix_now;
+use crate::split_root;
+use crate::win11::{Blocks, REG_KEY};
+use crate::{diff_items, ops_from_changes};
+use anyhow::bail;
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Enable,
+    Disable,
+}
+
+/// One entry of a [`Transaction`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchItem {
+    pub ty: Type,
+    pub action: Action,
+    pub id: String,
+    /// Which `Blocked` list a Win11 item goes to, ignored for Win10.
+    pub scope: Option<Scope>,
+    /// The root of a Win10 item. Any root will do when `None`, as long as
+    /// only one holds the id.
+    pub root: Option<SceneRoot>,
+}
+
+/// What happened to one entry of a committed transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Applied,
+    /// The item was already enabled or disabled.
+    Unchanged,
+    Failed(String),
+    /// Applied, then undone because another entry failed.
+    RolledBack,
+    /// Not attempted because an earlier entry failed.
+    Skipped,
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Applied => write!(f, "applied"),
+            Outcome::Unchanged => write!(f, "unchanged"),
+            Outcome::Failed(e) => write!(f, "failed: {e}"),
+            Outcome::RolledBack => write!(f, "rolled back"),
+            Outcome::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransactionReport {
+    pub outcomes: Vec<(BatchItem, Outcome)>,
+    /// Keys that couldn't be put back after a failure.
+    pub rollback_errors: Vec<String>,
+}
+
+impl TransactionReport {
+    pub fn is_ok(&self) -> bool {
+        self.outcomes
+            .iter()
+            .all(|(_, o)| matches!(o, Outcome::Applied | Outcome::Unchanged))
+    }
+
+    /// `Ok` when every entry was applied or unchanged, otherwise the errors.
+    pub fn into_result(self) -> anyhow::Result<()> {
+        let mut errors: Vec<String> = self
+            .outcomes
+            .into_iter()
+            .filter_map(|(_, outcome)| match outcome {
+                Outcome::Failed(e) => Some(e),
+                _ => None,
+            })
+            .collect();
+        errors.extend(
+            self.rollback_errors
+                .into_iter()
+                .map(|e| format!("rollback: {e}")),
+        );
+        if !errors.is_empty() {
+            bail!("{}", errors.join("\n"));
+        }
+        Ok(())
+    }
+
+    /// Reports the entry after `done` as failed, undoing the ones before it
+    /// and skipping the rest.
+    fn fail(items: &[BatchItem], mut done: Vec<Outcome>, error: String) -> Self {
+        for outcome in done.iter_mut() {
+            if *outcome == Outcome::Applied {
+                *outcome = Outcome::RolledBack;
+            }
+        }
+        done.push(Outcome::Failed(error));
+        done.resize(items.len(), Outcome::Skipped);
+        TransactionReport {
+            outcomes: items.iter().cloned().zip(done).collect(),
+            rollback_errors: vec![],
+        }
+    }
+}
+
+/// A batch of enable and disable operations that is applied completely or
+/// not at all.
+///
+/// Every key an entry touches is captured before anything changes, and the
+/// first failure puts all of them back.
+#[derive(Debug, Clone, Default)]
+pub struct Transaction {
+    items: Vec<BatchItem>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enables `id`. A Win10 id can start with its root, such as `HKLM\`,
+    /// to pick one of the items sharing it.
+    pub fn enable(&mut self, ty: Type, id: &str, scope: Option<Scope>) -> &mut Self {
+        self.push(ty, Action::Enable, id, scope)
+    }
+
+    /// Disables `id`, which can start with a root as in
+    /// [`Transaction::enable`].
+    pub fn disable(&mut self, ty: Type, id: &str, scope: Option<Scope>) -> &mut Self {
+        self.push(ty, Action::Disable, id, scope)
+    }
+
+    fn push(&mut self, ty: Type, action: Action, id: &str, scope: Option<Scope>) -> &mut Self {
+        let (root, id) = match ty {
+            Type::Win10 => split_root(id),
+            Type::Win11 => (None, id),
+        };
+        self.add(BatchItem {
+            ty,
+            action,
+            id: id.to_string(),
+            scope,
+            root,
+        })
+    }
+
+    pub fn add(&mut self, item: BatchItem) -> &mut Self {
+        self.items.push(item);
+        self
+    }
+
+    pub fn items(&self) -> &[BatchItem] {
+        &self.items
+    }
+
+    /// The registry changes of every entry. Win10 items are looked up in
+    /// `backup`.
+    pub fn plan(
+        &self,
+        backend: &dyn RegistryBackend,
+        backup: &[MenuItem],
+    ) -> anyhow::Result<Vec<Vec<RegOp>>> {
+        self.items
+            .iter()
+            .map(|item| plan_item(backend, backup, item))
+            .collect()
+    }
+
+    /// Backs up the current copy of every Win10 item the transaction
+    /// disables in `backup`, so it holds exactly what gets deleted, subkeys
+    /// added since the last listing included.
+    pub fn back_up(
+        &self,
+        backend: &dyn RegistryBackend,
+        backup: &mut Backup,
+        now: u64,
+    ) -> anyhow::Result<()> {
+        let disables: Vec<&BatchItem> = self
+            .items
+            .iter()
+            .filter(|i| i.ty == Type::Win10 && i.action == Action::Disable)
+            .collect();
+        if disables.is_empty() {
+            return Ok(());
+        }
+        let current: Vec<MenuItem> = crate::win10::load_all_in(backend)?
+            .into_iter()
+            .filter(|item| {
+                disables.iter().any(|i| {
+                    i.id.eq_ignore_ascii_case(&item.id)
+                        && i.root.is_none_or(|root| item.root() == Some(root))
+                })
+            })
+            .collect();
+        backup.merge(&current, now);
+        Ok(())
+    }
+
+    /// The registry changes of every entry after [`Transaction::back_up`],
+    /// without changing `backup`.
+    pub fn plan_backed_up(
+        &self,
+        backend: &dyn RegistryBackend,
+        backup: &Backup,
+    ) -> anyhow::Result<Vec<Vec<RegOp>>> {
+        let mut backup = backup.clone();
+        self.back_up(backend, &mut backup, unix_now())?;
+        self.plan(backend, &backup.items())
+    }
+
+    /// Backs up the Win10 items the transaction disables in `store`, see
+    /// [`Transaction::back_up`], and commits it. `store` stays locked until
+    /// the commit is done, so no other wcm process backs up an older copy
+    /// in between.
+    pub fn commit_backed_up(
+        &self,
+        backend: &dyn RegistryBackend,
+        store: &BackupStore,
+    ) -> anyhow::Result<TransactionReport> {
+        store.update_then(
+            |backup| self.back_up(backend, backup, unix_now()),
+            |backup, backed_up| {
+                backed_up?;
+                Ok(self.commit(backend, &backup.items()))
+            },
+        )?
+    }
+
+    pub fn commit(&self, backend: &dyn RegistryBackend, backup: &[MenuItem]) -> TransactionReport {
+        let mut plans = vec![];
+        let mut captured: Vec<SnapshotKey> = vec![];
+        for (i, item) in self.items.iter().enumerate() {
+            let plan = plan_item(backend, backup, item).and_then(|ops| {
+                for op in &ops {
+                    capture(backend, &mut captured, op.root(), op.path())?;
+                }
+                Ok(ops)
+            });
+            match plan {
+                Ok(ops) => plans.push(ops),
+                Err(e) => {
+                    let done = vec![Outcome::Skipped; i];
+                    return TransactionReport::fail(&self.items, done, format!("{e:#}"));
+                }
+            }
+        }
+
+        let mut done = vec![];
+        for ops in &plans {
+            for op in ops {
+                if let Err(e) = op.apply(backend) {
+                    let mut report =
+                        TransactionReport::fail(&self.items, done, format!("{op}: {e}"));
+                    report.rollback_errors = rollback(backend, &captured);
+                    return report;
+                }
+            }
+            done.push(if ops.is_empty() {
+                Outcome::Unchanged
+            } else {
+                Outcome::Applied
+            });
+        }
+        TransactionReport {
+            outcomes: self.items.iter().cloned().zip(done).collect(),
+            rollback_errors: vec![],
+        }
+    }
+}
+
+fn plan_item(
+    backend: &dyn RegistryBackend,
+    backup: &[MenuItem],
+    item: &BatchItem,
+) -> anyhow::Result<Vec<RegOp>> {
+    let id = &item.id;
+    match item.ty {
+        Type::Win10 => {
+            let found: Vec<&RegItem> = backup
+                .iter()
+                .filter(|i| i.id.eq_ignore_ascii_case(id))
+                .filter(|i| item.root.is_none_or(|root| i.root() == Some(root)))
+                .filter_map(|i| i.info.as_ref()?.reg.as_ref())
+                .collect();
+            let reg = match found[..] {
+                [] => bail!("{id}: not found in the backup"),
+                [reg] => reg,
+                _ => {
+                    let roots: Vec<String> =
+                        found.iter().map(|r| format!(r"{}\", r.root)).collect();
+                    bail!(
+                        "{id}: in more than one root, start it with {}",
+                        roots.join(" or ")
+                    )
+                }
+            };
+            if !reg.is_safe() {
+                bail!("{id}: outside the context menu locations");
+            }
+            let current = RegItem::from_path_in(backend, reg.root, &reg.path).ok();
+            let ops = match item.action {
+                // everything below the key goes, including subkeys added since
+                Action::Disable => current.map(|c| c.delete_ops()).unwrap_or_default(),
+                Action::Enable => {
+                    // like `RegItem::write_in`, values added since are kept
+                    let changes = diff_items(current.as_slice(), std::slice::from_ref(reg));
+                    ops_from_changes(&changes)
+                        .into_iter()
+                        .filter(|op| {
+                            !matches!(op, RegOp::DeleteKey { .. } | RegOp::DeleteValue { .. })
+                        })
+                        .collect()
+                }
+            };
+            Ok(ops)
+        }
+        Type::Win11 => {
+            let scope = item.scope.unwrap_or_default();
+            let blocked = Blocks::new(backend, scope).contains(id);
+            let (root, path, name) = (
+                scope.to_root(),
+                REG_KEY.to_string(),
+                Blocks::to_reg_name(id),
+            );
+            let ops = match item.action {
+                Action::Disable if !blocked => vec![RegOp::SetValue {
+                    root,
+                    path,
+                    name,
+                    value: RegItemValue::SZ(String::new()),
+                }],
+                Action::Enable if blocked => vec![RegOp::DeleteValue { root, path, name }],
+                _ => vec![],
+            };
+            Ok(ops)
+        }
+    }
+}
+
+/// Records the state of `path` unless an already captured key covers it.
+/// Starts at the topmost missing parent, so that parents created along the
+/// way are removed again on rollback.
+pub(crate) fn capture(
+    backend: &dyn RegistryBackend,
+    captured: &mut Vec<SnapshotKey>,
+    root: SceneRoot,
+    path: &str,
+) -> std::io::Result<()> {
+    let mut path = path;
+    while let Some((parent, _)) = path.rsplit_once('\\')
+        && !backend.key_exists(root, parent)
+    {
+        path = parent;
+    }
+    let covers = |key: &SnapshotKey, path: &str| {
+        key.root == root && strip_path_prefix(path, &key.path).is_some()
+    };
+    if captured.iter().any(|key| covers(key, path)) {
+        return Ok(());
+    }
+    captured.retain(|key| !(key.root == root && strip_path_prefix(&key.path, path).is_some()));
+    captured.push(SnapshotKey::capture(backend, root, path)?);
+    Ok(())
+}
+
+/// Puts every captured key back, carrying on past errors.
+pub(crate) fn rollback(backend: &dyn RegistryBackend, captured: &[SnapshotKey]) -> Vec<String> {
+    let mut errors = vec![];
+    for key in captured.iter().rev() {
+        let ops = match key.plan_restore(backend) {
+            Ok(ops) => ops,
+            Err(e) => {
+                errors.push(format!("{}: {e}", key.path));
+                continue;
+            }
+        };
+        for op in ops {
+            if let Err(e) = op.apply(backend) {
+                errors.push(format!("{op}: {e}"));
+            }
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Outcome, Transaction};
+    use crate::{
+        MemoryBackend, MenuItem, MenuItemInfo, RawValue, RegItem, RegistryBackend, SceneRoot,
+        Scope, Type,
+    };
+    use std::io;
+
+    /// Fails to delete one key, after its subkeys are already gone.
+    struct FailingDelete<'a> {
+        inner: &'a MemoryBackend,
+        path: &'a str,
+    }
+
+    impl RegistryBackend for FailingDelete<'_> {
+        fn enum_keys(&self, root: SceneRoot, path: &str) -> io::Result<Vec<String>> {
+            self.inner.enum_keys(root, path)
+        }
+        fn enum_values(&self, root: SceneRoot, path: &str) -> io::Result<Vec<(String, RawValue)>> {
+            self.inner.enum_values(root, path)
+        }
+        fn create_key(&self, root: SceneRoot, path: &str) -> io::Result<()> {
+            self.inner.create_key(root, path)
+        }
+        fn set_value(
+            &self,
+            root: SceneRoot,
+            path: &str,
+            name: &str,
+            value: &RawValue,
+        ) -> io::Result<()> {
+            self.inner.set_value(root, path, name, value)
+        }
+        fn delete_value(&self, root: SceneRoot, path: &str, name: &str) -> io::Result<()> {
+            self.inner.delete_value(root, path, name)
+        }
+        fn delete_key(&self, root: SceneRoot, path: &str) -> io::Result<()> {
+            if path.eq_ignore_ascii_case(self.path) {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "denied"));
+            }
+            self.inner.delete_key(root, path)
+        }
+        fn last_write_time(&self, root: SceneRoot, path: &str) -> io::Result<u64> {
+            self.inner.last_write_time(root, path)
+        }
+    }
+
+    fn verb(reg: &MemoryBackend, path: &str, command: &str) -> MenuItem {
+        reg.add_verb(path, path, command);
+        MenuItem {
+            id: path.to_string(),
+            name: path.to_string(),
+            enabled: true,
+            info: Some(MenuItemInfo {
+                reg: RegItem::from_path_in(reg, SceneRoot::HKCR, path).ok(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_commit() {
+        let reg = MemoryBackend::new();
+        let backup = [
+            verb(&reg, r"Directory\Shell\wt", "wt.exe"),
+            verb(&reg, r"Drive\Shell\format", "format.exe"),
+        ];
+        let mut tx = Transaction::new();
+        tx.disable(Type::Win10, r"Directory\Shell\wt", None)
+            .disable(Type::Win11, "1234", Some(Scope::User))
+            .enable(Type::Win10, r"Drive\Shell\format", None);
+        let report = tx.commit(&reg, &backup);
+
+        let outcomes: Vec<_> = report.outcomes.iter().map(|(_, o)| o.clone()).collect();
+        assert_eq!(
+            outcomes,
+            [Outcome::Applied, Outcome::Applied, Outcome::Unchanged]
+        );
+        assert!(report.is_ok());
+        assert!(!reg.key_exists(SceneRoot::HKCR, r"Directory\Shell\wt"));
+        assert!(crate::win11::Blocks::new(&reg, Scope::User).contains("1234"));
+
+        // registry paths ignore case
+        let mut tx = Transaction::new();
+        tx.enable(Type::Win10, r"DIRECTORY\shell\WT", None);
+        assert!(tx.commit(&reg, &backup).is_ok());
+        assert_eq!(
+            RegItem::from_path_in(&reg, SceneRoot::HKCR, r"Directory\Shell\wt").ok(),
+            backup[0].info.as_ref().unwrap().reg
+        );
+    }
+
+    #[test]
+    fn test_rollback() {
+        let reg = MemoryBackend::new();
+        let backup = [
+            verb(&reg, r"Directory\Shell\wt", "wt.exe"),
+            verb(&reg, r"Drive\Shell\format", "format.exe"),
+        ];
+        let before = RegItem::from_path_in(&reg, SceneRoot::HKCR, "").unwrap();
+        let failing = FailingDelete {
+            inner: &reg,
+            path: r"Drive\Shell\format",
+        };
+
+        let mut tx = Transaction::new();
+        tx.disable(Type::Win10, r"Directory\Shell\wt", None)
+            .disable(Type::Win11, "1234", Some(Scope::User))
+            .disable(Type::Win10, r"Drive\Shell\format", None)
+            .disable(Type::Win11, "5678", Some(Scope::User));
+        let report = tx.commit(&failing, &backup);
+
+        let outcomes: Vec<_> = report.outcomes.iter().map(|(_, o)| o.to_string()).collect();
+        assert_eq!(
+            outcomes,
+            [
+                "rolled back",
+                "rolled back",
+                r"failed: - [HKEY_CLASSES_ROOT\Drive\Shell\format]: denied",
+                "skipped",
+            ]
+        );
+        assert!(report.rollback_errors.is_empty());
+        assert_eq!(
+            RegItem::from_path_in(&reg, SceneRoot::HKCR, "").unwrap(),
+            before
+        );
+        assert!(reg.enum_keys(SceneRoot::HKCU, "").unwrap().is_empty());
+
+        let mut tx = Transaction::new();
+        tx.disable(Type::Win11, "1234", None)
+            .enable(Type::Win10, "unknown", None);
+        let report = tx.commit(&reg, &backup);
+        assert_eq!(report.outcomes[0].1, Outcome::Skipped);
+        assert!(!crate::win11::Blocks::new(&reg, Scope::User).contains("1234"));
+    }
+}
@@ -0,0 +1,347 @@
+use crate::RegItem;
+use crate::RegItemValue;
+use crate::RegOp;
+use crate::RegistryBackend;
+use crate::Scene;
+use crate::SceneRoot;
+use crate::encode_utf16;
+use crate::{diff_items, ops_from_changes};
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+
+/// Where a verb goes in the menu, the `Position` value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, EnumString, Display)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[strum(ascii_case_insensitive)]
+pub enum Position {
+    Top,
+    Bottom,
+}
+
+/// A classic menu verb to create below the `Shell` key of a scene.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewVerb {
+    pub scene: Scene,
+    /// The text shown in the menu, also the name of the key.
+    pub name: String,
+    /// The command line, `%1` or `%V` stand for the clicked file or folder.
+    pub command: String,
+    /// An icon file, or a DLL or EXE and an icon index like `shell32.dll,3`.
+    pub icon: Option<String>,
+    /// Only shown with Shift held down.
+    pub extended: bool,
+    pub position: Option<Position>,
+}
+
+impl NewVerb {
+    pub fn new(scene: Scene, name: &str, command: &str) -> Self {
+        NewVerb {
+            scene,
+            name: name.to_string(),
+            command: command.to_string(),
+            icon: None,
+            extended: false,
+            position: None,
+        }
+    }
+
+    /// The key of the verb in `HKEY_CLASSES_ROOT`, which is also its id.
+    pub fn path(&self) -> anyhow::Result<String> {
+        let Some(shell) = self.scene.shell_key() else {
+            bail!("verbs can't be added to {}", self.scene);
+        };
+        let key = self.name.replace('\\', "").trim().to_string();
+        if key.is_empty() {
+            bail!("the verb needs a name");
+        }
+        Ok(format!(r"{shell}\{key}"))
+    }
+
+    /// The keys of the verb.
+    pub fn to_reg_item(&self) -> anyhow::Result<RegItem> {
+        if self.command.trim().is_empty() {
+            bail!("the verb needs a command");
+        }
+        let path = self.path()?;
+        let mut item = RegItem::new(SceneRoot::HKCR, &path);
+        item.set_value("MUIVerb", RegItemValue::SZ(self.name.clone()));
+        if let Some(icon) = &self.icon {
+            item.set_value("Icon", RegItemValue::SZ(icon.clone()));
+        }
+        if self.extended {
+            item.set_value("Extended", RegItemValue::SZ(String::new()));
+        }
+        if let Some(position) = self.position {
+            item.set_value("Position", RegItemValue::SZ(position.to_string()));
+        }
+        let mut command = RegItem::new(SceneRoot::HKCR, &format!(r"{path}\command"));
+        command.set_value("", RegItemValue::SZ(self.command.clone()));
+        item.children.push(command);
+        Ok(item)
+    }
+
+    /// The registry changes that create the verb, an error when its key
+    /// already exists.
+    pub fn plan_in(&self, backend: &dyn RegistryBackend) -> anyhow::Result<Vec<RegOp>> {
+        let item = self.to_reg_item()?;
+        if backend.key_exists(item.root, &item.path) {
+            bail!("{}: already exists", item.path);
+        }
+        let changes = diff_items(&[], std::slice::from_ref(&item));
+        Ok(ops_from_changes(&changes))
+    }
+}
+
+/// The values of a classic menu verb that change how it shows up.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VerbInfo {
+    /// `MUIVerb`, the text shown in place of the default value.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    /// The default value of the `command` subkey.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<Position>,
+    #[serde(default)]
+    pub extended: bool,
+    #[serde(default)]
+    pub separator_before: bool,
+    #[serde(default)]
+    pub separator_after: bool,
+    #[serde(default)]
+    pub never_default: bool,
+    #[serde(default)]
+    pub has_lua_shield: bool,
+}
+
+/// The flags of [`VerbInfo`], by value name. They are on when the value
+/// exists, whatever it holds.
+const FLAGS: [&str; 5] = [
+    "Extended",
+    "SeparatorBefore",
+    "SeparatorAfter",
+    "NeverDefault",
+    "HasLUAShield",
+];
+
+impl VerbInfo {
+    /// Reads the values of the verb key `reg`.
+    pub fn from_reg(reg: &RegItem) -> Self {
+        let text = |reg: &RegItem, name: &str| reg.get_value(name).map(|v| v.to_string());
+        let flag = |name: &str| reg.get_value(name).is_some();
+        VerbInfo {
+            name: text(reg, "MUIVerb"),
+            icon: text(reg, "Icon"),
+            command: reg.get_child("command").and_then(|c| text(c, "")),
+            position: text(reg, "Position").and_then(|p| p.parse().ok()),
+            extended: flag(FLAGS[0]),
+            separator_before: flag(FLAGS[1]),
+            separator_after: flag(FLAGS[2]),
+            never_default: flag(FLAGS[3]),
+            has_lua_shield: flag(FLAGS[4]),
+        }
+    }
+}
+
+/// Changes to the values of a classic menu verb. `None` leaves a value as
+/// it is.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerbEdit {
+    /// The new `MUIVerb`.
+    pub name: Option<String>,
+    /// An empty icon removes it.
+    pub icon: Option<String>,
+    pub command: Option<String>,
+    /// `Some(None)` removes the position.
+    pub position: Option<Option<Position>>,
+    pub extended: Option<bool>,
+    pub separator_before: Option<bool>,
+    pub separator_after: Option<bool>,
+    pub never_default: Option<bool>,
+    pub has_lua_shield: Option<bool>,
+}
+
+/// Sets or removes the text value `name`, keeping the case of its name and
+/// whether it's a `REG_EXPAND_SZ`.
+fn put_text(reg: &mut RegItem, name: &str, text: Option<&str>) {
+    let old = reg.remove_value(name);
+    let Some(text) = text else {
+        return;
+    };
+    let value = match &old {
+        Some((_, RegItemValue::ExpandSz(_))) => RegItemValue::ExpandSz(encode_utf16(text)),
+        _ => RegItemValue::SZ(text.to_string()),
+    };
+    let name = old
+        .map(|(name, _)| name)
+        .unwrap_or_else(|| name.to_string());
+    reg.set_value(&name, value);
+}
+
+impl VerbEdit {
+    pub fn is_empty(&self) -> bool {
+        *self == VerbEdit::default()
+    }
+
+    /// The verb key `reg` with the changes made.
+    pub fn apply_to(&self, reg: &RegItem) -> anyhow::Result<RegItem> {
+        let mut reg = reg.clone();
+        if let Some(name) = &self.name {
+            if name.trim().is_empty() {
+                bail!("the verb needs a name");
+            }
+            put_text(&mut reg, "MUIVerb", Some(name));
+        }
+        if let Some(icon) = &self.icon {
+            put_text(
+                &mut reg,
+                "Icon",
+                (!icon.is_empty()).then_some(icon.as_str()),
+            );
+        }
+        if let Some(position) = self.position {
+            let position = position.map(|p| p.to_string());
+            put_text(&mut reg, "Position", position.as_deref());
+        }
+        let flags = [
+            self.extended,
+            self.separator_before,
+            self.separator_after,
+            self.never_default,
+            self.has_lua_shield,
+        ];
+        for (name, on) in FLAGS.iter().zip(flags) {
+            match on {
+                Some(true) if reg.get_value(name).is_none() => {
+                    reg.set_value(name, RegItemValue::SZ(String::new()));
+                }
+                Some(false) => {
+                    reg.remove_value(name);
+                }
+                _ => {}
+            }
+        }
+        if let Some(command) = &self.command {
+            if command.trim().is_empty() {
+                bail!("the verb needs a command");
+            }
+            let (root, path) = (reg.root, format!(r"{}\command", reg.path));
+            let child = match reg.children.iter().position(|c| {
+                c.path
+                    .rsplit('\\')
+                    .next()
+                    .is_some_and(|n| n.eq_ignore_ascii_case("command"))
+            }) {
+                Some(i) => &mut reg.children[i],
+                None => {
+                    reg.children.push(RegItem::new(root, &path));
+                    reg.children.last_mut().unwrap()
+                }
+            };
+            put_text(child, "", Some(command));
+        }
+        Ok(reg)
+    }
+
+    /// The registry changes that turn the verb key `reg` into
+    /// [`VerbEdit::apply_to`].
+    pub fn plan(&self, reg: &RegItem) -> anyhow::Result<Vec<RegOp>> {
+        let edited = self.apply_to(reg)?;
+        let changes = diff_items(std::slice::from_ref(reg), std::slice::from_ref(&edited));
+        Ok(ops_from_changes(&changes))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{NewVerb, Position, VerbEdit, VerbInfo};
+    use crate::{
+        MemoryBackend, RegItem, RegItemValue, RegistryBackend, Scene, SceneRoot, apply_ops,
+        encode_utf16,
+    };
+
+    #[test]
+    fn test_new_verb() {
+        let reg = MemoryBackend::new();
+        let mut verb = NewVerb::new(Scene::Background, "Open terminal here", r#"wt.exe -d "%V""#);
+        verb.extended = true;
+        verb.position = Some(Position::Top);
+        let path = r"Directory\Background\Shell\Open terminal here";
+        assert_eq!(verb.path().unwrap(), path);
+
+        let ops = verb.plan_in(&reg).unwrap();
+        assert!(ops.iter().all(|op| op.is_safe()));
+        apply_ops(&reg, &ops, false).unwrap();
+        let value = |path: &str, name: &str| {
+            let item = crate::RegItem::from_path_in(&reg, SceneRoot::HKCR, path).unwrap();
+            item.get_value(name).map(|v| v.to_string())
+        };
+        assert_eq!(value(path, "Position").as_deref(), Some("Top"));
+        assert_eq!(value(path, "Extended").as_deref(), Some(""));
+        assert_eq!(value(path, "Icon"), None);
+        assert_eq!(
+            value(&format!(r"{path}\command"), "").as_deref(),
+            Some(r#"wt.exe -d "%V""#)
+        );
+        assert!(reg.key_exists(SceneRoot::HKCR, path));
+        assert!(verb.plan_in(&reg).is_err());
+
+        let verb = NewVerb::new(Scene::SystemFileAssociations, "x", "x.exe");
+        assert!(verb.plan_in(&reg).is_err());
+        let verb = NewVerb::new(Scene::Drive, "x", " ");
+        assert!(verb.plan_in(&reg).is_err());
+    }
+
+    #[test]
+    fn test_edit_verb() {
+        let mut reg = RegItem::new(SceneRoot::HKCR, r"*\shell\notepad");
+        reg.set_value("MuiVerb", RegItemValue::SZ("Notepad".to_string()));
+        reg.set_value("extended", RegItemValue::SZ(String::new()));
+        let mut command = RegItem::new(SceneRoot::HKCR, r"*\shell\notepad\Command");
+        let notepad = r#"%SystemRoot%\notepad.exe "%1""#;
+        command.set_value("", RegItemValue::ExpandSz(encode_utf16(notepad)));
+        reg.children.push(command);
+        let info = VerbInfo::from_reg(&reg);
+        assert_eq!(info.name.as_deref(), Some("Notepad"));
+        assert_eq!(info.command.as_deref(), Some(notepad));
+        assert!(info.extended && !info.has_lua_shield);
+
+        let edit = VerbEdit {
+            name: Some("Edit".to_string()),
+            icon: Some("notepad.exe".to_string()),
+            command: Some(r#"%SystemRoot%\notepad.exe /A "%1""#.to_string()),
+            position: Some(Some(Position::Top)),
+            extended: Some(false),
+            has_lua_shield: Some(true),
+            ..Default::default()
+        };
+        let edited = edit.apply_to(&reg).unwrap();
+        let mut names: Vec<_> = edited.values().map(|(n, _)| n.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["HasLUAShield", "Icon", "MuiVerb", "Position"]);
+        let command = edited.get_child("command").unwrap().get_value("");
+        assert!(matches!(command, Some(RegItemValue::ExpandSz(_))));
+        let info = VerbInfo::from_reg(&edited);
+        assert_eq!(info.position, Some(Position::Top));
+        assert!(info.has_lua_shield && !info.extended);
+        assert_eq!(edit.plan(&reg).unwrap().len(), 6);
+
+        let undo = VerbEdit {
+            icon: Some(String::new()),
+            position: Some(None),
+            ..Default::default()
+        };
+        let info = VerbInfo::from_reg(&undo.apply_to(&edited).unwrap());
+        assert_eq!((info.icon, info.position), (None, None));
+        assert!(VerbEdit::default().plan(&reg).unwrap().is_empty());
+        let edit = VerbEdit {
+            command: Some(String::new()),
+            ..Default::default()
+        };
+        assert!(edit.apply_to(&reg).is_err());
+    }
+}
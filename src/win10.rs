@@ -1,103 +1,124 @@
-use crate::APP_NAME;
-use crate::BACKUP_NAME;
+use crate::Backup;
+use crate::BackupStore;
 use crate::GuidManager;
 use crate::MenuItem;
 use crate::MenuItemInfo;
+use crate::MenuItemKind;
+use crate::NewVerb;
 use crate::RegItem;
 use crate::RegItemValue;
+use crate::RegOp;
+use crate::RegistryBackend;
+use crate::Scene;
 use crate::SceneRoot;
 use crate::SceneType;
-use cached::SizedCache;
-use cached::proc_macro::cached;
-use std::collections::HashSet;
+use crate::Transaction;
+use crate::Type;
+use crate::VerbEdit;
+use crate::VerbInfo;
+#[cfg(all(windows, feature = "live"))]
+use crate::WinRegBackend;
+use crate::apply_ops;
+#[cfg(all(windows, feature = "live"))]
+use crate::snapshot::unix_now;
+use crate::split_root;
+use crate::{diff_items, ops_from_changes};
+use anyhow::bail;
+#[cfg(all(windows, feature = "live"))]
+use cached::{SizedCache, proc_macro::cached};
 use strum::IntoEnumIterator;
+#[cfg(all(windows, feature = "live"))]
 use windows::Win32::System::SystemInformation::GetWindowsDirectoryW;
-use winreg::RegKey;
-use winreg::enums::HKEY_CLASSES_ROOT;
 
-fn get_backup() -> Vec<MenuItem> {
-    let Some(d) = dirs::config_local_dir() else {
-        return vec![];
-    };
-
-    let app_dir = d.join(APP_NAME);
-    if !std::fs::exists(&app_dir).unwrap_or(false) {
-        let _ = std::fs::create_dir_all(&app_dir);
+/// The backup of every item seen so far. Having no config directory means
+/// having no backup, but a backup that can't be read is an error.
+#[cfg(all(windows, feature = "live"))]
+pub(crate) fn get_backup() -> anyhow::Result<Backup> {
+    match BackupStore::open_default() {
+        Some(store) => store.load(),
+        None => Ok(Backup::new(unix_now())),
     }
+}
 
-    let backup_path = app_dir.join(BACKUP_NAME);
+/// The backup of the live registry. Disabling an item without one would
+/// leave nothing to enable it from.
+#[cfg(all(windows, feature = "live"))]
+pub(crate) fn default_store() -> anyhow::Result<BackupStore> {
+    BackupStore::open_default().ok_or_else(|| anyhow::anyhow!("no config directory"))
+}
 
-    let Ok(s) = std::fs::read_to_string(backup_path) else {
-        return vec![];
+/// A backup holding only revision `revision` of `id` from `backup`, see
+/// [`crate::BackupEntry::revision`].
+pub(crate) fn backup_revision(
+    backup: &Backup,
+    id: &str,
+    revision: usize,
+) -> anyhow::Result<Vec<MenuItem>> {
+    let entry = match split_root(id) {
+        (Some(root), path) => backup.find_in(root, path),
+        (None, path) => backup.find(path),
     };
-
-    serde_json::from_str(&s).unwrap_or_default()
+    let Some(entry) = entry else {
+        bail!("{id}: not found in the backup");
+    };
+    let Some(item) = entry.revision(revision) else {
+        let count = entry.revisions.len() + 1;
+        bail!("{id}: no revision {revision}, the backup holds {count}");
+    };
+    Ok(vec![item.clone()])
 }
 
-fn set_backup(items: &Vec<MenuItem>) {
-    let mut old = get_backup();
-    let old_keys: HashSet<String> = old.iter().map(|i| i.id.clone()).collect();
-
-    let Some(d) = dirs::config_local_dir() else {
-        return;
+/// Adds `items` to the backup and returns all backed up items.
+#[cfg(all(windows, feature = "live"))]
+fn set_backup(items: &[MenuItem]) -> anyhow::Result<Vec<MenuItem>> {
+    let Some(store) = BackupStore::open_default() else {
+        return Ok(items.to_vec());
     };
-
-    let app_dir = d.join(APP_NAME);
-    if !std::fs::exists(&app_dir).unwrap_or(false) {
-        let _ = std::fs::create_dir_all(&app_dir);
-    }
-
-    let backup_path = app_dir.join(BACKUP_NAME);
-
-    for i in items {
-        if !old_keys.contains(&i.id) {
-            old.push(i.clone());
-        }
-    }
-    if let Ok(s) = serde_json::to_string_pretty(&old) {
-        let _ = std::fs::write(backup_path, &s);
-    }
+    store.update(|backup| {
+        backup.merge(items, unix_now());
+        backup.items()
+    })
 }
 
-fn load_all() -> anyhow::Result<Vec<MenuItem>, anyhow::Error> {
+pub(crate) fn load_all_in(backend: &dyn RegistryBackend) -> anyhow::Result<Vec<MenuItem>> {
     let mut v = vec![];
     let guid = GuidManager::new();
     for scene in SceneType::iter() {
         match scene {
             SceneType::Shell => {
                 for (root, i) in scene.registry_path() {
-                    let items = load_shell(*root, i, &guid).unwrap_or_default();
+                    let items = load_shell(backend, *root, i, &guid).unwrap_or_default();
                     v.extend(items);
                 }
             }
             SceneType::ShellEx => {
                 for (root, i) in scene.registry_path() {
-                    let items = load_shellex(*root, i, &guid).unwrap_or_default();
+                    let items = load_shellex(backend, *root, i, &guid).unwrap_or_default();
                     v.extend(items);
                 }
             }
             SceneType::Edge => {
                 for (root, i) in scene.registry_path() {
-                    let items = load_edge(*root, i).unwrap_or_default();
+                    let items = load_edge(backend, *root, i).unwrap_or_default();
                     v.extend(items);
                 }
             }
             SceneType::FileExts => {
                 for (root, i) in scene.registry_path() {
-                    let items = load_file_exts(*root, i).unwrap_or_default();
+                    let items = load_file_exts(backend, *root, i).unwrap_or_default();
                     v.extend(items);
                 }
             }
         }
     }
-    if let Ok(items) = load_hkcr_exts() {
+    if let Ok(items) = load_hkcr_exts(backend) {
         v.extend(items);
     }
 
-    set_backup(&v);
     Ok(v)
 }
 
+#[cfg(all(windows, feature = "live"))]
 #[cached]
 fn get_system_directory() -> String {
     use std::ffi::OsString;
@@ -111,6 +132,7 @@ fn get_system_directory() -> String {
         path.to_string_lossy().to_string() + "/"
     }
 }
+#[cfg(all(windows, feature = "live"))]
 #[cached]
 fn get_windows_directory() -> String {
     use std::ffi::OsString;
@@ -123,6 +145,7 @@ fn get_windows_directory() -> String {
         path.to_string_lossy().to_string()
     }
 }
+#[cfg(all(windows, feature = "live"))]
 #[cached(
     ty = "SizedCache<String, Option<String>>",
     create = "{ SizedCache::with_size(100) }",
@@ -132,6 +155,16 @@ fn get_dll_txt(s: &str) -> Option<String> {
     let (dll, id) = parse_reg_path(s)?;
     exeico::get_dll_txt(dll, id).ok()
 }
+// strings and icons are loaded from DLL resources, which needs Windows
+#[cfg(not(all(windows, feature = "live")))]
+fn get_dll_txt(_: &str) -> Option<String> {
+    None
+}
+#[cfg(not(all(windows, feature = "live")))]
+fn get_ico_from_str(_: &str) -> Option<Vec<u8>> {
+    None
+}
+#[cfg(all(windows, feature = "live"))]
 #[cached(
     ty = "SizedCache<String, Option<Vec<u8>>>",
     create = "{ SizedCache::with_size(100) }",
@@ -148,6 +181,7 @@ fn get_ico_from_str(s: &str) -> Option<Vec<u8>> {
     exeico::get_dll_icos(s).ok()?.first().cloned()
 }
 
+#[cfg(all(windows, feature = "live"))]
 fn get_ico_from_reg(reg: &RegItem) -> Option<Vec<u8>> {
     if let Some(RegItemValue::SZ(icon)) = reg.get_value("Icon") {
         return get_ico_from_str(icon);
@@ -179,6 +213,11 @@ fn get_ico_from_reg(reg: &RegItem) -> Option<Vec<u8>> {
     }
     None
 }
+#[cfg(not(all(windows, feature = "live")))]
+fn get_ico_from_reg(_: &RegItem) -> Option<Vec<u8>> {
+    None
+}
+#[cfg(all(windows, feature = "live"))]
 #[cached(
     ty = "SizedCache<String, String>",
     create = "{ SizedCache::with_size(100) }",
@@ -197,6 +236,7 @@ fn parse_path(path: &str) -> String {
     }
 }
 
+#[cfg(all(windows, feature = "live"))]
 fn parse_reg_path(s: &str) -> Option<(String, i32)> {
     let s = s.to_lowercase();
     let (path, id) = s.split_once(",")?;
@@ -205,7 +245,7 @@ fn parse_reg_path(s: &str) -> Option<(String, i32)> {
     Some((dll, id))
 }
 
-fn get_shell_name(reg: &RegItem) -> String {
+fn get_shell_name(backend: &dyn RegistryBackend, reg: &RegItem) -> String {
     let path_name = reg
         .path
         .split('\\')
@@ -232,21 +272,29 @@ fn get_shell_name(reg: &RegItem) -> String {
     }
 
     if let Some(clsid) = reg.get_guid()
-        && let Some(cls_name) = get_cls_name(&clsid)
+        && let Some(cls_name) = get_cls_name(backend, &clsid)
     {
         return cls_name;
     }
     path_name
 }
 
-fn from_shell(reg: &RegItem, guid: &GuidManager) -> anyhow::Result<MenuItem> {
+fn from_shell(
+    backend: &dyn RegistryBackend,
+    reg: &RegItem,
+    guid: &GuidManager,
+) -> anyhow::Result<MenuItem> {
     if let Some(guid_key) = reg.get_guid()
-        && let Some(item) = from_guid(guid_key.as_str(), reg, guid)
+        && let Some(mut item) = from_guid(backend, guid_key.as_str(), reg, guid)
     {
+        if let Some(info) = &mut item.info {
+            info.verb = Some(VerbInfo::from_reg(reg));
+        }
         Ok(item)
     } else {
         let info = MenuItemInfo {
             icon: get_ico_from_reg(reg),
+            icon_hash: None,
             publisher_display_name: String::new(),
             description: String::new(),
             types: vec![],
@@ -255,10 +303,11 @@ fn from_shell(reg: &RegItem, guid: &GuidManager) -> anyhow::Result<MenuItem> {
             full_name: String::new(),
             reg: Some(reg.clone()),
             reg_txt: Some(reg.to_reg_txt()),
+            verb: Some(VerbInfo::from_reg(reg)),
         };
-        let mut name = get_shell_name(reg);
+        let mut name = get_shell_name(backend, reg);
         if is_clsid(&name)
-            && let Some(cls_name) = get_cls_name(&name)
+            && let Some(cls_name) = get_cls_name(backend, &name)
         {
             name = cls_name;
         }
@@ -267,23 +316,19 @@ fn from_shell(reg: &RegItem, guid: &GuidManager) -> anyhow::Result<MenuItem> {
             name,
             enabled: true,
             info: Some(info),
+            ..Default::default()
         };
 
         Ok(menu)
     }
 }
-#[cached(
-    ty = "SizedCache<String, Option<String>>",
-    create = "{ SizedCache::with_size(100) }",
-    convert = r#"{ format!("{}", name) }"#
-)]
-fn get_cls_name(name: &str) -> Option<String> {
+fn get_cls_name(backend: &dyn RegistryBackend, name: &str) -> Option<String> {
     let name = if is_clsid(name) {
         name.to_string()
     } else {
         format!("{{{name}}}")
     };
-    let reg = RegItem::from_path(SceneRoot::HKCR, &format!(r"CLSID\{name}")).ok()?;
+    let reg = RegItem::from_path_in(backend, SceneRoot::HKCR, &format!(r"CLSID\{name}")).ok()?;
     let RegItemValue::SZ(cls_name) = reg
         .get_value("LocalizedString")
         .or(reg.get_value(""))
@@ -302,10 +347,16 @@ fn is_clsid(name: &str) -> bool {
     name.starts_with('{') && name.ends_with('}')
 }
 
-fn from_guid(key: &str, reg: &RegItem, guid: &GuidManager) -> Option<MenuItem> {
+fn from_guid(
+    backend: &dyn RegistryBackend,
+    key: &str,
+    reg: &RegItem,
+    guid: &GuidManager,
+) -> Option<MenuItem> {
     if let Some(item) = guid.get_item(key.to_lowercase().as_str()) {
         let info = MenuItemInfo {
             icon: item.icon.clone().and_then(|s| get_ico_from_str(&s)),
+            icon_hash: None,
             publisher_display_name: String::new(),
             description: String::new(),
             types: vec![],
@@ -314,15 +365,16 @@ fn from_guid(key: &str, reg: &RegItem, guid: &GuidManager) -> Option<MenuItem> {
             full_name: String::new(),
             reg: Some(reg.clone()),
             reg_txt: Some(reg.to_reg_txt()),
+            verb: None,
         };
         let mut name = item
             .res_text
             .clone()
             .and_then(|s| get_dll_txt(&s))
-            .unwrap_or(item.text.clone().unwrap_or(get_shell_name(reg)));
+            .unwrap_or(item.text.clone().unwrap_or(get_shell_name(backend, reg)));
 
         if is_clsid(&name)
-            && let Some(cls_name) = get_cls_name(&name)
+            && let Some(cls_name) = get_cls_name(backend, &name)
         {
             name = cls_name;
         }
@@ -332,19 +384,25 @@ fn from_guid(key: &str, reg: &RegItem, guid: &GuidManager) -> Option<MenuItem> {
             name,
             enabled: true,
             info: Some(info),
+            ..Default::default()
         };
         return Some(menu);
     }
     None
 }
-fn from_shell_ex(reg: &RegItem, guid: &GuidManager) -> anyhow::Result<MenuItem> {
+fn from_shell_ex(
+    backend: &dyn RegistryBackend,
+    reg: &RegItem,
+    guid: &GuidManager,
+) -> anyhow::Result<MenuItem> {
     if let Some(guid_key) = reg.get_guid()
-        && let Some(item) = from_guid(guid_key.as_str(), reg, guid)
+        && let Some(item) = from_guid(backend, guid_key.as_str(), reg, guid)
     {
         Ok(item)
     } else {
         let info = MenuItemInfo {
             icon: get_ico_from_reg(reg),
+            icon_hash: None,
             publisher_display_name: String::new(),
             description: String::new(),
             types: vec![],
@@ -353,10 +411,11 @@ fn from_shell_ex(reg: &RegItem, guid: &GuidManager) -> anyhow::Result<MenuItem>
             full_name: String::new(),
             reg: Some(reg.clone()),
             reg_txt: Some(reg.to_reg_txt()),
+            verb: None,
         };
-        let mut name = get_shell_name(reg);
+        let mut name = get_shell_name(backend, reg);
         if is_clsid(&name)
-            && let Some(cls_name) = get_cls_name(&name)
+            && let Some(cls_name) = get_cls_name(backend, &name)
         {
             name = cls_name;
         }
@@ -365,24 +424,48 @@ fn from_shell_ex(reg: &RegItem, guid: &GuidManager) -> anyhow::Result<MenuItem>
             name,
             enabled: true,
             info: Some(info),
+            ..Default::default()
         };
         Ok(menu)
     }
 }
 
-fn load_shell(root: SceneRoot, path: &str, guid: &GuidManager) -> anyhow::Result<Vec<MenuItem>> {
-    let root = RegItem::from_path(root, path)?;
+fn load_shell(
+    backend: &dyn RegistryBackend,
+    root: SceneRoot,
+    path: &str,
+    guid: &GuidManager,
+) -> anyhow::Result<Vec<MenuItem>> {
+    let root = RegItem::from_path_in(backend, root, path)?;
     let mut v = vec![];
     for i in root.children {
-        if let Ok(menu) = from_shell(&i, guid) {
-            v.push(menu);
+        if let Ok(menu) = from_shell(backend, &i, guid) {
+            v.push(locate(menu, MenuItemKind::Verb, root.root));
         }
     }
     Ok(v)
 }
 
-fn load_edge(root: SceneRoot, path: &str) -> anyhow::Result<Vec<MenuItem>> {
-    let root = RegItem::from_path(root, path)?;
+/// Fills in what `item` is and where it was found.
+fn locate(item: MenuItem, kind: MenuItemKind, source: SceneRoot) -> MenuItem {
+    let scene = match kind {
+        MenuItemKind::Association | MenuItemKind::Policy => None,
+        _ => Scene::from_item_path(&item.id),
+    };
+    MenuItem {
+        kind: Some(kind),
+        scene,
+        source: Some(source),
+        ..item
+    }
+}
+
+fn load_edge(
+    backend: &dyn RegistryBackend,
+    root: SceneRoot,
+    path: &str,
+) -> anyhow::Result<Vec<MenuItem>> {
+    let root = RegItem::from_path_in(backend, root, path)?;
     let mut v = vec![];
     let info = MenuItemInfo {
         reg: Some(root.clone()),
@@ -391,27 +474,33 @@ fn load_edge(root: SceneRoot, path: &str) -> anyhow::Result<Vec<MenuItem>> {
     };
     let menu = MenuItem {
         id: path.to_string(),
-        name: get_shell_name(&root),
+        name: get_shell_name(backend, &root),
         enabled: true,
         info: Some(info),
+        ..Default::default()
     };
-    v.push(menu);
+    v.push(locate(menu, MenuItemKind::Policy, root.root));
     Ok(v)
 }
 
-fn load_shellex(root: SceneRoot, path: &str, guid: &GuidManager) -> anyhow::Result<Vec<MenuItem>> {
-    let root = RegItem::from_path(root, path)?;
+fn load_shellex(
+    backend: &dyn RegistryBackend,
+    root: SceneRoot,
+    path: &str,
+    guid: &GuidManager,
+) -> anyhow::Result<Vec<MenuItem>> {
+    let root = RegItem::from_path_in(backend, root, path)?;
     let mut v = vec![];
-    for ex in [
-        "ContextMenuHandlers",
-        "DragDropHandlers",
-        "CopyHookHandlers",
-        "PropertySheetHandlers",
+    for (ex, kind) in [
+        ("ContextMenuHandlers", MenuItemKind::ContextMenuHandler),
+        ("DragDropHandlers", MenuItemKind::DragDropHandler),
+        ("CopyHookHandlers", MenuItemKind::CopyHook),
+        ("PropertySheetHandlers", MenuItemKind::PropertySheet),
     ] {
         for i in root.get_child(ex).iter() {
             for reg in &i.children {
-                if let Ok(menu) = from_shell_ex(reg, guid) {
-                    v.push(menu);
+                if let Ok(menu) = from_shell_ex(backend, reg, guid) {
+                    v.push(locate(menu, kind, root.root));
                 }
             }
         }
@@ -420,8 +509,12 @@ fn load_shellex(root: SceneRoot, path: &str, guid: &GuidManager) -> anyhow::Resu
     Ok(v)
 }
 
-fn get_ext_info(progid: &str, reg: &RegItem) -> Option<MenuItemInfo> {
-    let progid = RegItem::from_path(SceneRoot::HKCR, progid).ok()?;
+fn get_ext_info(
+    backend: &dyn RegistryBackend,
+    progid: &str,
+    reg: &RegItem,
+) -> Option<MenuItemInfo> {
+    let progid = RegItem::from_path_in(backend, SceneRoot::HKCR, progid).ok()?;
     let icon = get_ico_from_reg(&progid);
 
     let mut name = progid
@@ -449,6 +542,7 @@ fn get_ext_info(progid: &str, reg: &RegItem) -> Option<MenuItemInfo> {
 
     Some(MenuItemInfo {
         icon,
+        icon_hash: None,
         publisher_display_name: String::new(),
         description: String::new(),
         types: vec![],
@@ -457,10 +551,11 @@ fn get_ext_info(progid: &str, reg: &RegItem) -> Option<MenuItemInfo> {
         full_name: name,
         reg: Some(reg.clone()),
         reg_txt: Some(reg.to_reg_txt()),
+        verb: None,
     })
 }
 
-fn from_ext(reg: &RegItem) -> anyhow::Result<MenuItem> {
+fn from_ext(backend: &dyn RegistryBackend, reg: &RegItem) -> anyhow::Result<MenuItem> {
     let user_choice = reg
         .children
         .iter()
@@ -474,7 +569,7 @@ fn from_ext(reg: &RegItem) -> anyhow::Result<MenuItem> {
         return Err(anyhow::anyhow!("not found Progid"));
     };
 
-    let info = get_ext_info(&progid.to_string(), reg).or(Some(MenuItemInfo {
+    let info = get_ext_info(backend, &progid.to_string(), reg).or(Some(MenuItemInfo {
         reg: Some(reg.clone()),
         reg_txt: Some(reg.to_reg_txt()),
         full_name: progid.to_string(),
@@ -488,18 +583,19 @@ fn from_ext(reg: &RegItem) -> anyhow::Result<MenuItem> {
             .unwrap_or(progid.to_string()),
         enabled: true,
         info,
+        ..Default::default()
     };
 
     Ok(item)
 }
 
-fn from_hkcr_ext(name: &str) -> anyhow::Result<MenuItem> {
-    let ext_reg = RegItem::from_path(SceneRoot::HKCR, name)?;
+fn from_hkcr_ext(backend: &dyn RegistryBackend, name: &str) -> anyhow::Result<MenuItem> {
+    let ext_reg = RegItem::from_path_in(backend, SceneRoot::HKCR, name)?;
     let Some(RegItemValue::SZ(app_id)) = ext_reg.get_value("").or(ext_reg.get_value("@")) else {
         return Err(anyhow::anyhow!("not found app_id"));
     };
 
-    let info = get_ext_info(app_id, &ext_reg);
+    let info = get_ext_info(backend, app_id, &ext_reg);
     let item = MenuItem {
         id: ext_reg.path.clone(),
         name: info
@@ -508,84 +604,223 @@ fn from_hkcr_ext(name: &str) -> anyhow::Result<MenuItem> {
             .unwrap_or(app_id.to_string()),
         enabled: true,
         info,
+        ..Default::default()
     };
 
     Ok(item)
 }
 
-fn load_file_exts(root: SceneRoot, path: &str) -> anyhow::Result<Vec<MenuItem>> {
-    let root = RegItem::from_path(root, path)?;
+fn load_file_exts(
+    backend: &dyn RegistryBackend,
+    root: SceneRoot,
+    path: &str,
+) -> anyhow::Result<Vec<MenuItem>> {
+    let root = RegItem::from_path_in(backend, root, path)?;
     let mut v = vec![];
     for i in root.children {
-        if let Ok(menu) = from_ext(&i) {
-            v.push(menu);
+        if let Ok(menu) = from_ext(backend, &i) {
+            v.push(locate(menu, MenuItemKind::Association, root.root));
         }
     }
 
     Ok(v)
 }
 
-fn load_hkcr_exts() -> anyhow::Result<Vec<MenuItem>> {
+fn load_hkcr_exts(backend: &dyn RegistryBackend) -> anyhow::Result<Vec<MenuItem>> {
     let mut v = vec![];
-    let root = RegKey::predef(HKEY_CLASSES_ROOT);
-    for name in root.enum_keys().flat_map(|x| x.ok()) {
+    for name in backend.enum_keys(SceneRoot::HKCR, "")? {
         if !name.starts_with(".") {
             continue;
         }
-        if let Ok(menu) = from_hkcr_ext(&name) {
-            v.push(menu);
+        if let Ok(menu) = from_hkcr_ext(backend, &name) {
+            v.push(locate(menu, MenuItemKind::Association, SceneRoot::HKCR));
         }
     }
 
     Ok(v)
 }
 
+#[cfg(all(windows, feature = "live"))]
 pub fn list() -> Vec<MenuItem> {
-    let v = load_all().unwrap_or_default();
-    let mut backup = get_backup();
+    let v = load_all_in(&WinRegBackend).unwrap_or_default();
+    // without a readable backup only the items still present are listed,
+    // enabling or disabling reports the error
+    match set_backup(&v) {
+        Ok(backup) => merge_backup(v, backup),
+        Err(_) => v,
+    }
+}
 
+/// Marks every backup entry as enabled or disabled depending on whether it is
+/// still present in the freshly loaded `items`, which replace the backed up
+/// copies of the ones that are.
+#[cfg(any(test, all(windows, feature = "live")))]
+pub(crate) fn merge_backup(items: Vec<MenuItem>, mut backup: Vec<MenuItem>) -> Vec<MenuItem> {
     for i in backup.iter_mut() {
         i.enabled = false;
     }
 
-    for item in v {
-        if let Some(i) = backup.iter_mut().find(|i| i.id == item.id) {
-            i.enabled = true;
+    for item in items {
+        if let Some(i) = backup
+            .iter_mut()
+            .find(|i| i.id == item.id && i.root() == item.root())
+        {
+            *i = item;
         }
     }
 
     backup
 }
 
+#[cfg(all(windows, feature = "live"))]
 pub fn disable(id: &str) -> Result<(), anyhow::Error> {
-    let backup = get_backup();
-    if let Some(item) = backup.iter().find(|i| i.id == id)
-        && let Some(info) = &item.info
-        && let Some(reg) = &info.reg
-    {
-        let _ = reg.delete();
-    }
-    Ok(())
+    disable_in(&WinRegBackend, &default_store()?, id)
 }
 
+/// Disables `id` in `backend`, backing up its current copy in `store`
+/// first.
+pub(crate) fn disable_in(
+    backend: &dyn RegistryBackend,
+    store: &BackupStore,
+    id: &str,
+) -> Result<(), anyhow::Error> {
+    Transaction::new()
+        .disable(Type::Win10, id, None)
+        .commit_backed_up(backend, store)?
+        .into_result()
+}
+
+#[cfg(all(windows, feature = "live"))]
 pub fn enable(id: &str) -> Result<(), anyhow::Error> {
-    let backup = get_backup();
-    if let Some(item) = backup.iter().find(|i| i.id == id)
-        && let Some(info) = &item.info
-        && let Some(reg) = &info.reg
-    {
-        reg.write();
+    enable_in(&WinRegBackend, &get_backup()?.items(), id)
+}
+
+pub(crate) fn enable_in(
+    backend: &dyn RegistryBackend,
+    backup: &[MenuItem],
+    id: &str,
+) -> Result<(), anyhow::Error> {
+    Transaction::new()
+        .enable(Type::Win10, id, None)
+        .commit(backend, backup)
+        .into_result()
+}
+
+/// Creates `verb` in `backend` and returns it as listed.
+pub(crate) fn add_in(backend: &dyn RegistryBackend, verb: &NewVerb) -> anyhow::Result<MenuItem> {
+    apply_ops(backend, &verb.plan_in(backend)?, false)?;
+    let reg = RegItem::from_path_in(backend, SceneRoot::HKCR, &verb.path()?)?;
+    let item = from_shell(backend, &reg, &GuidManager::new())?;
+    Ok(locate(item, MenuItemKind::Verb, SceneRoot::HKCR))
+}
+
+/// The registry changes that delete `id`, which `backup` has to list as
+/// created by wcm. Nothing is left to delete when the key is already gone.
+pub(crate) fn plan_remove_in(
+    backend: &dyn RegistryBackend,
+    backup: &Backup,
+    id: &str,
+) -> anyhow::Result<Vec<RegOp>> {
+    match backup.find_in(SceneRoot::HKCR, id) {
+        Some(entry) if entry.added => {}
+        Some(_) => bail!("{id}: not created by wcm, disable it instead"),
+        None => bail!("{id}: not found in the backup"),
+    }
+    if !backend.key_exists(SceneRoot::HKCR, id) {
+        return Ok(vec![]);
     }
-    Ok(())
+    Ok(RegItem::from_path_in(backend, SceneRoot::HKCR, id)?.delete_ops())
+}
+
+pub(crate) fn remove_in(
+    backend: &dyn RegistryBackend,
+    backup: &Backup,
+    id: &str,
+) -> anyhow::Result<()> {
+    apply_ops(backend, &plan_remove_in(backend, backup, id)?, false)
+}
+
+/// The verb `id` as registered in `backend`.
+fn find_verb(backend: &dyn RegistryBackend, id: &str) -> anyhow::Result<(MenuItem, RegItem)> {
+    let Some(item) = load_all_in(backend)?
+        .into_iter()
+        .find(|i| i.id.eq_ignore_ascii_case(id))
+    else {
+        bail!("{id}: not found");
+    };
+    let reg = match item.info.as_ref().and_then(|i| i.reg.clone()) {
+        Some(reg) if item.kind == Some(MenuItemKind::Verb) => reg,
+        _ => bail!("{id}: not a verb"),
+    };
+    Ok((item, reg))
+}
+
+/// The registry changes that make `edit` to the verb `id`.
+pub(crate) fn plan_edit_in(
+    backend: &dyn RegistryBackend,
+    id: &str,
+    edit: &VerbEdit,
+) -> anyhow::Result<Vec<RegOp>> {
+    edit.plan(&find_verb(backend, id)?.1)
+}
+
+/// Makes `edit` to the verb `id` and returns it as it was before and after.
+pub(crate) fn edit_in(
+    backend: &dyn RegistryBackend,
+    id: &str,
+    edit: &VerbEdit,
+) -> anyhow::Result<(MenuItem, MenuItem)> {
+    let (before, reg) = find_verb(backend, id)?;
+    apply_ops(backend, &edit.plan(&reg)?, false)?;
+    let (after, _) = find_verb(backend, id)?;
+    Ok((before, after))
+}
+
+/// The registry changes that put the verb `id` back the way it was before
+/// wcm first edited it, as `backup` recorded it.
+pub(crate) fn plan_revert_in(
+    backend: &dyn RegistryBackend,
+    backup: &Backup,
+    id: &str,
+) -> anyhow::Result<Vec<RegOp>> {
+    let (item, current) = find_verb(backend, id)?;
+    let Some(old) = backup
+        .find_in(current.root, &item.id)
+        .and_then(|e| e.edited.as_ref())
+    else {
+        bail!("{id}: not edited by wcm, nothing to revert");
+    };
+    let changes = diff_items(&[current], std::slice::from_ref(old));
+    Ok(ops_from_changes(&changes))
+}
+
+/// Reverts the edits to the verb `id` and returns it as it is now.
+pub(crate) fn revert_in(
+    backend: &dyn RegistryBackend,
+    backup: &Backup,
+    id: &str,
+) -> anyhow::Result<MenuItem> {
+    apply_ops(backend, &plan_revert_in(backend, backup, id)?, false)?;
+    Ok(find_verb(backend, id)?.0)
 }
 
 #[cfg(test)]
 mod test {
+    use crate::test_support::TempDir;
     use crate::{
-        SceneRoot,
+        Backup, BackupStore, MemoryBackend, MenuItem, MenuItemKind, NewVerb, Position,
+        RegItemValue, RegistryBackend, Scene, SceneRoot, SceneType, Transaction, Type, VerbEdit,
+        win10::{
+            add_in, disable_in, edit_in, enable_in, load_all_in, merge_backup, remove_in, revert_in,
+        },
+    };
+    #[cfg(all(windows, feature = "live"))]
+    use crate::{
+        WinRegBackend,
         win10::{load_file_exts, load_hkcr_exts},
     };
 
+    #[cfg(all(windows, feature = "live"))]
     #[test]
     fn test_get_dll_txt() {
         for i in [
@@ -604,6 +839,7 @@ mod test {
         }
     }
 
+    #[cfg(all(windows, feature = "live"))]
     #[test]
     fn test_get_dll_ico() {
         for i in [
@@ -617,9 +853,11 @@ mod test {
         }
     }
 
+    #[cfg(all(windows, feature = "live"))]
     #[test]
     fn test_ext() {
         let exts = load_file_exts(
+            &WinRegBackend,
             SceneRoot::HKCU,
             r"Software\Microsoft\Windows\CurrentVersion\Explorer\FileExts",
         )
@@ -628,9 +866,266 @@ mod test {
         println!("{:#?}", exts.len());
     }
 
+    #[cfg(all(windows, feature = "live"))]
     #[test]
     fn test_hkcr_ext() {
-        let exts = load_hkcr_exts().unwrap();
+        let exts = load_hkcr_exts(&WinRegBackend).unwrap();
         println!("{:#?}", exts.len());
     }
+
+    #[test]
+    fn test_list_disable_enable_in_memory() {
+        let reg = MemoryBackend::new();
+        reg.add_verb(
+            r"Directory\Background\Shell\wt",
+            "Open in Terminal",
+            "wt.exe -d \"%V\"",
+        );
+        reg.add_verb(r"Drive\Shell\format", "Format", "format.exe");
+
+        let backup = load_all_in(&reg).unwrap();
+        let mut names: Vec<_> = backup.iter().map(|i| i.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["Format", "Open in Terminal"]);
+
+        let id = r"Directory\Background\Shell\wt";
+        Transaction::new()
+            .disable(Type::Win10, id, None)
+            .commit(&reg, &backup)
+            .into_result()
+            .unwrap();
+        assert!(!reg.key_exists(SceneRoot::HKCR, id));
+
+        let list = merge_backup(load_all_in(&reg).unwrap(), backup.clone());
+        let item = list.iter().find(|i| i.id == id).unwrap();
+        assert!(!item.enabled);
+
+        enable_in(&reg, &backup, id).unwrap();
+        let list = merge_backup(load_all_in(&reg).unwrap(), backup);
+        assert!(list.iter().all(|i| i.enabled));
+    }
+
+    #[test]
+    fn test_disable_enable_with_store() {
+        let reg = MemoryBackend::new();
+        let id = r"Drive\Shell\format";
+        reg.add_verb(id, "Format", "format.exe");
+        let dir = TempDir::new("disable");
+        let store = BackupStore::new(dir.join("backup.json"));
+
+        let ops = Type::Win10.plan_disable_in(&reg, &store, id, None).unwrap();
+        assert!(!ops.is_empty());
+        assert!(store.load().unwrap().entries.is_empty());
+        Type::Win10.disable_in(&reg, &store, id, None).unwrap();
+        assert!(!reg.key_exists(SceneRoot::HKCR, id));
+        let list = Type::list_backed_up_in(&reg, &store).unwrap();
+        assert!(list.iter().any(|i| i.id == id && !i.enabled));
+
+        Type::Win10.enable_in(&reg, &store, id, None).unwrap();
+        assert!(reg.key_exists(SceneRoot::HKCR, &format!(r"{id}\command")));
+
+        // the copy disabled is the current one, not the one backed up before
+        reg.set_value(
+            SceneRoot::HKCR,
+            id,
+            "Icon",
+            &RegItemValue::SZ("format.ico".into()).to_raw(),
+        )
+        .unwrap();
+        Type::Win10.disable_in(&reg, &store, id, None).unwrap();
+        Type::Win10.enable_in(&reg, &store, id, None).unwrap();
+        assert!(reg.get_value(SceneRoot::HKCR, id, "Icon").is_ok());
+    }
+
+    #[test]
+    fn test_same_id_in_two_roots() {
+        let reg = MemoryBackend::new();
+        let edge = r"SOFTWARE\Policies\Microsoft\Edge";
+        let off = RegItemValue::DWORD(0).to_raw();
+        for root in [SceneRoot::HKCU, SceneRoot::HKLM] {
+            reg.create_key(root, edge).unwrap();
+            reg.set_value(root, edge, "ContextMenuSearchEnabled", &off)
+                .unwrap();
+        }
+        assert_eq!(
+            load_all_in(&reg)
+                .unwrap()
+                .iter()
+                .filter(|i| i.id == edge)
+                .count(),
+            2
+        );
+        let dir = TempDir::new("roots");
+        let store = BackupStore::new(dir.join("backup.json"));
+
+        assert!(disable_in(&reg, &store, edge).is_err());
+        disable_in(&reg, &store, &format!(r"HKLM\{edge}")).unwrap();
+        assert!(reg.key_exists(SceneRoot::HKCU, edge));
+        assert!(!reg.key_exists(SceneRoot::HKLM, edge));
+        let backup = store.load().unwrap().items();
+
+        let list = merge_backup(load_all_in(&reg).unwrap(), backup.clone());
+        let edges: Vec<_> = list
+            .iter()
+            .filter(|i| i.id == edge)
+            .map(|i| (i.root(), i.enabled))
+            .collect();
+        assert_eq!(
+            edges,
+            [
+                (Some(SceneRoot::HKCU), true),
+                (Some(SceneRoot::HKLM), false)
+            ]
+        );
+        enable_in(&reg, &backup, &format!(r"hklm\{edge}")).unwrap();
+        assert!(reg.key_exists(SceneRoot::HKLM, edge));
+    }
+
+    #[test]
+    fn test_every_scene_path_has_a_scene() {
+        for scene_type in [SceneType::Shell, SceneType::ShellEx] {
+            for (_, path) in scene_type.registry_path() {
+                let item = format!(r"{path}\item");
+                assert!(Scene::from_item_path(&item).is_some(), "{path}");
+            }
+        }
+        let item = MenuItem {
+            id: r"Drive\Shell\format".to_string(),
+            ..Default::default()
+        };
+        assert!(item.on_scene(&[]));
+        assert!(item.on_scene(&[Scene::Background, Scene::Drive]));
+        assert!(!item.on_scene(&[Scene::Background]));
+    }
+
+    #[test]
+    fn test_kind_scene_source() {
+        let reg = MemoryBackend::new();
+        reg.add_verb(r"Directory\Background\Shell\wt", "Terminal", "wt.exe");
+        let handler = r"*\ShellEx\ContextMenuHandlers\7-Zip";
+        reg.create_key(SceneRoot::HKCR, handler).unwrap();
+        let clsid = RegItemValue::SZ("{23170F69-40C1-278A-1000-000100020000}".to_string());
+        reg.set_value(SceneRoot::HKCR, handler, "", &clsid.to_raw())
+            .unwrap();
+
+        let mut items: Vec<_> = load_all_in(&reg)
+            .unwrap()
+            .into_iter()
+            .map(|i| (i.id, i.kind, i.scene, i.source))
+            .collect();
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            items,
+            [
+                (
+                    handler.to_string(),
+                    Some(MenuItemKind::ContextMenuHandler),
+                    Some(Scene::File),
+                    Some(SceneRoot::HKCR)
+                ),
+                (
+                    r"Directory\Background\Shell\wt".to_string(),
+                    Some(MenuItemKind::Verb),
+                    Some(Scene::Background),
+                    Some(SceneRoot::HKCR)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_add_remove() {
+        let reg = MemoryBackend::new();
+        let mut verb = NewVerb::new(Scene::Background, "Open terminal here", "wt.exe");
+        verb.icon = Some("wt.exe,0".to_string());
+        let item = add_in(&reg, &verb).unwrap();
+        assert_eq!(item.id, r"Directory\Background\Shell\Open terminal here");
+        assert_eq!(item.name, "Open terminal here");
+        assert_eq!(item.kind, Some(MenuItemKind::Verb));
+        assert!(add_in(&reg, &verb).is_err());
+
+        let mut backup = Backup::new(0);
+        assert!(remove_in(&reg, &backup, &item.id).is_err());
+        let mut elsewhere = item.clone();
+        let reg_item = elsewhere
+            .info
+            .as_mut()
+            .and_then(|i| i.reg.as_mut())
+            .unwrap();
+        reg_item.root = SceneRoot::HKCU;
+        backup.add(&elsewhere, 0);
+        assert!(remove_in(&reg, &backup, &item.id).is_err());
+        backup.merge(std::slice::from_ref(&item), 0);
+        assert!(remove_in(&reg, &backup, &item.id).is_err());
+        backup.add(&item, 0);
+        remove_in(&reg, &backup, &item.id).unwrap();
+        assert!(!reg.key_exists(SceneRoot::HKCR, &item.id));
+        assert!(reg.key_exists(SceneRoot::HKCR, r"Directory\Background\Shell"));
+        remove_in(&reg, &backup, &item.id).unwrap();
+
+        let dir = TempDir::new("verbs");
+        let store = BackupStore::new(dir.join("backup.json"));
+        let item = Type::add_in(&reg, &store, &verb).unwrap();
+        assert!(
+            store
+                .load()
+                .unwrap()
+                .find(&item.id)
+                .is_some_and(|e| e.added)
+        );
+        Type::remove_in(&reg, &store, &item.id).unwrap();
+        assert!(store.load().unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn test_edit_revert() {
+        let reg = MemoryBackend::new();
+        let id = r"Drive\Shell\format";
+        reg.add_verb(id, "Format", "format.exe");
+        let edit = VerbEdit {
+            name: Some("Format drive".to_string()),
+            position: Some(Some(Position::Bottom)),
+            extended: Some(true),
+            ..Default::default()
+        };
+        let (before, after) = edit_in(&reg, id, &edit).unwrap();
+        assert_eq!(after.name, "Format drive");
+        let verb = after.info.as_ref().and_then(|i| i.verb.clone()).unwrap();
+        assert_eq!(verb.position, Some(Position::Bottom));
+        assert!(verb.extended && !verb.never_default);
+        assert_eq!(verb.command.as_deref(), Some("format.exe"));
+        assert!(edit_in(&reg, r"Drive\Shell\missing", &edit).is_err());
+
+        let mut backup = Backup::new(0);
+        assert!(revert_in(&reg, &backup, id).is_err());
+        // list refreshes back up revisions too, they aren't edits to revert
+        backup.merge(&[before.clone(), after.clone()], 0);
+        assert!(revert_in(&reg, &backup, id).is_err());
+        backup.edit(&before, &after, 0);
+        let edit = VerbEdit {
+            icon: Some("shell32.dll,8".to_string()),
+            ..Default::default()
+        };
+        let (again, after) = edit_in(&reg, id, &edit).unwrap();
+        backup.edit(&again, &after, 0);
+        let reverted = revert_in(&reg, &backup, id).unwrap();
+        assert_eq!(reverted.info, before.info);
+        backup.revert(&reverted, 0);
+        assert!(revert_in(&reg, &backup, id).is_err());
+
+        let dir = TempDir::new("edits");
+        let store = BackupStore::new(dir.join("backup.json"));
+        Type::edit_in(&reg, &store, id, &edit).unwrap();
+        Type::revert_in(&reg, &store, id).unwrap();
+        assert!(Type::revert_in(&reg, &store, id).is_err());
+        assert_eq!(
+            load_all_in(&reg)
+                .unwrap()
+                .iter()
+                .find(|i| i.id == id)
+                .unwrap()
+                .info,
+            before.info
+        );
+    }
 }
@@ -1,72 +1,47 @@
-use crate::{MenuItem, MenuItemInfo};
-use crate::{Scope, TypeItem};
+use crate::Scope;
+use crate::Transaction;
+use crate::Type;
+use crate::{MenuItem, RegistryBackend};
+#[cfg(all(windows, feature = "live"))]
+use crate::{MenuItemInfo, MenuItemKind, Scene, SceneRoot, TypeItem, WinRegBackend};
+#[cfg(all(windows, feature = "live"))]
 use serde_xml_rs::from_str;
 use std::collections::HashSet;
+#[cfg(all(windows, feature = "live"))]
 use std::path::PathBuf;
+#[cfg(all(windows, feature = "live"))]
 use windows::ApplicationModel::Package;
+#[cfg(all(windows, feature = "live"))]
 use windows::Management::Deployment::PackageManager;
+#[cfg(all(windows, feature = "live"))]
 use windows::core::HSTRING;
-use winreg::enums::*;
-use winreg::{RegKey, enums::HKEY_CLASSES_ROOT};
 
-const REG_KEY: &str = r"Software\Microsoft\Windows\CurrentVersion\Shell Extensions\Blocked";
+pub(crate) const REG_KEY: &str =
+    r"Software\Microsoft\Windows\CurrentVersion\Shell Extensions\Blocked";
 
 pub struct Blocks {
-    pub scope: Scope,
     pub items: HashSet<String>,
 }
 
 impl Blocks {
-    pub fn new(scope: Scope) -> Self {
-        let hive = scope.to_hive();
-        let base_key = RegKey::predef(hive);
-
-        let items = if let Ok(sub_key) = base_key.open_subkey(REG_KEY) {
-            sub_key
-                .enum_values()
-                .filter_map(|result| result.ok().map(|(name, _)| Self::from_reg_name(&name)))
+    pub fn new(backend: &dyn RegistryBackend, scope: Scope) -> Self {
+        let items = if let Ok(values) = backend.enum_values(scope.to_root(), REG_KEY) {
+            values
+                .iter()
+                .map(|(name, _)| Self::from_reg_name(name))
                 .collect()
         } else {
             HashSet::new()
         };
 
-        Self { scope, items }
-    }
-
-    pub fn add(&mut self, id: &str) -> anyhow::Result<()> {
-        let hive = self.scope.to_hive();
-        let base_key = RegKey::predef(hive);
-
-        let sub_key = base_key.create_subkey(REG_KEY)?.0;
-        sub_key.set_value(Self::to_reg_name(id), &"")?;
-        self.items.insert(id.to_string());
-
-        Ok(())
-    }
-
-    pub fn remove(&mut self, id: &str) -> anyhow::Result<()> {
-        if !self.items.contains(id) {
-            return Ok(());
-        }
-
-        let hive = self.scope.to_hive();
-        let base_key = RegKey::predef(hive);
-
-        if let Ok(sub_key) = base_key.open_subkey_with_flags(REG_KEY, KEY_WRITE) {
-            let _ = sub_key.delete_value(Self::to_reg_name(id));
-            self.items.remove(id);
-        } else {
-            self.items.clear();
-        }
-
-        Ok(())
+        Self { items }
     }
 
     pub fn contains(&self, id: &str) -> bool {
         self.items.contains(id)
     }
 
-    fn to_reg_name(val: &str) -> String {
+    pub(crate) fn to_reg_name(val: &str) -> String {
         format!("{{{val}}}")
     }
 
@@ -75,6 +50,7 @@ impl Blocks {
     }
 }
 
+#[cfg(all(windows, feature = "live"))]
 struct Ext {
     publisher_display_name: String,
     description: String,
@@ -82,6 +58,7 @@ struct Ext {
     logo_path: String,
 }
 
+#[cfg(all(windows, feature = "live"))]
 fn get_info(manifest_path: &PathBuf) -> Option<Ext> {
     let xml = std::fs::read_to_string(manifest_path).ok()?;
     let package = from_str::<serde_appxmanifest::Package>(&xml).ok()?;
@@ -140,6 +117,7 @@ fn get_info(manifest_path: &PathBuf) -> Option<Ext> {
     None
 }
 
+#[cfg(all(windows, feature = "live"))]
 fn get_logo(pkg: Package) -> Option<Vec<u8>> {
     if let Some(icon) = pkg
         .Logo()
@@ -153,6 +131,7 @@ fn get_logo(pkg: Package) -> Option<Vec<u8>> {
     None
 }
 
+#[cfg(all(windows, feature = "live"))]
 const BAD_APP: [(&str, &str); 6] = [
     ("0002DEAD-9BF7-4CFA-8A5C-DE8679340001", "/../BandiView.exe"),
     ("0002DEAD-9BF7-4CFA-8A5C-DE8679340002", "/../BandiView.exe"),
@@ -165,14 +144,23 @@ const BAD_APP: [(&str, &str); 6] = [
     ),
 ];
 
+#[cfg(all(windows, feature = "live"))]
 pub fn list(scope: Scope) -> Vec<MenuItem> {
-    let hkcr = RegKey::predef(HKEY_CLASSES_ROOT);
-    let subkey = hkcr.open_subkey("PackagedCom\\Package").unwrap();
-    let names: Vec<_> = subkey.enum_keys().flat_map(|x| x.ok()).collect();
-    let package_manager = PackageManager::new().unwrap();
+    list_in(&WinRegBackend, scope)
+}
+
+#[cfg(all(windows, feature = "live"))]
+pub(crate) fn list_in(backend: &dyn RegistryBackend, scope: Scope) -> Vec<MenuItem> {
+    // offline hives and scratch registries may have no packages at all
+    let Ok(names) = backend.enum_keys(SceneRoot::HKCR, "PackagedCom\\Package") else {
+        return vec![];
+    };
+    let Ok(package_manager) = PackageManager::new() else {
+        return vec![];
+    };
 
     let mut v: Vec<MenuItem> = vec![];
-    let blocks = Blocks::new(scope);
+    let blocks = Blocks::new(backend, scope);
 
     for full_name in names {
         if let Ok(pkg) = package_manager.FindPackageByPackageFullName(&HSTRING::from(&full_name)) {
@@ -195,7 +183,10 @@ pub fn list(scope: Scope) -> Vec<MenuItem> {
                 .unwrap_or_default();
             let display_name = pkg.DisplayName().map(|i| i.to_string()).unwrap_or_default();
 
-            let install_path = std::path::PathBuf::from(pkg.InstalledPath().unwrap().to_string());
+            let Ok(install_path) = pkg.InstalledPath() else {
+                continue;
+            };
+            let install_path = std::path::PathBuf::from(install_path.to_string());
             let manifest_path = install_path.join(manifest_name);
 
             if let Some(Ext {
@@ -229,6 +220,7 @@ pub fn list(scope: Scope) -> Vec<MenuItem> {
                     visit.insert(ty.clsid.clone());
                     let info = Some(MenuItemInfo {
                         icon: icon.clone(),
+                        icon_hash: None,
                         publisher_display_name: publisher_display_name.clone(),
                         description: description.clone(),
                         types: types
@@ -241,6 +233,7 @@ pub fn list(scope: Scope) -> Vec<MenuItem> {
                         full_name: full_name.clone(),
                         reg: None,
                         reg_txt: None,
+                        verb: None,
                     });
 
                     v.push(MenuItem {
@@ -248,6 +241,9 @@ pub fn list(scope: Scope) -> Vec<MenuItem> {
                         id: ty.clsid.clone(),
                         name: display_name.clone(),
                         info,
+                        kind: Some(MenuItemKind::PackagedCommand),
+                        scene: Scene::from_key(&ty.ty),
+                        source: Some(SceneRoot::HKCR),
                     });
                 }
             }
@@ -257,12 +253,67 @@ pub fn list(scope: Scope) -> Vec<MenuItem> {
     v
 }
 
+/// Packaged extensions are looked up through `PackageManager`, so none are
+/// found without it.
+#[cfg(not(all(windows, feature = "live")))]
+pub(crate) fn list_in(_: &dyn RegistryBackend, _: Scope) -> Vec<MenuItem> {
+    vec![]
+}
+
+#[cfg(all(windows, feature = "live"))]
 pub fn enable(id: &str, scope: Scope) -> Result<(), anyhow::Error> {
-    let mut blocks = Blocks::new(scope);
-    blocks.remove(id)
+    enable_in(&WinRegBackend, id, scope)
 }
 
+pub(crate) fn enable_in(
+    backend: &dyn RegistryBackend,
+    id: &str,
+    scope: Scope,
+) -> Result<(), anyhow::Error> {
+    Transaction::new()
+        .enable(Type::Win11, id, Some(scope))
+        .commit(backend, &[])
+        .into_result()
+}
+
+#[cfg(all(windows, feature = "live"))]
 pub fn disable(id: &str, scope: Scope) -> Result<(), anyhow::Error> {
-    let mut blocks = Blocks::new(scope);
-    blocks.add(id)
+    disable_in(&WinRegBackend, id, scope)
+}
+
+pub(crate) fn disable_in(
+    backend: &dyn RegistryBackend,
+    id: &str,
+    scope: Scope,
+) -> Result<(), anyhow::Error> {
+    Transaction::new()
+        .disable(Type::Win11, id, Some(scope))
+        .commit(backend, &[])
+        .into_result()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        MemoryBackend, Scope,
+        win11::{Blocks, disable_in, enable_in, list_in},
+    };
+
+    #[test]
+    fn test_list_without_packages() {
+        // no `PackagedCom\Package` key, as in an offline hive
+        assert!(list_in(&MemoryBackend::new(), Scope::User).is_empty());
+    }
+
+    #[test]
+    fn test_enable_disable() {
+        let reg = MemoryBackend::new();
+        disable_in(&reg, "1234", Scope::User).unwrap();
+        disable_in(&reg, "1234", Scope::User).unwrap();
+        assert!(Blocks::new(&reg, Scope::User).contains("1234"));
+        assert!(!Blocks::new(&reg, Scope::Machine).contains("1234"));
+        enable_in(&reg, "1234", Scope::User).unwrap();
+        assert!(Blocks::new(&reg, Scope::User).items.is_empty());
+        enable_in(&reg, "1234", Scope::User).unwrap();
+    }
 }